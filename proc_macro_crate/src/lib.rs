@@ -1,55 +1,187 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote;
-use regex::Regex;
-use walkdir::WalkDir;
-use syn::{self, Ident};
 use proc_macro2::Span;
+use quote::{quote, quote_spanned};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{self, Ident};
+use syn::{parse_macro_input, DeriveInput, ItemImpl, LitStr, Token, Type};
 
+// Registers a tool with the global registry. Put it on the tool's `impl Tool for ...` block; the
+// tool is constructed with `Default::default()` when the registry is first used:
+//
+//     #[auto_register]
+//     #[async_trait]
+//     impl Tool for ShellTool { ... }
+#[proc_macro_attribute]
+pub fn auto_register(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(Span::call_site(), "#[auto_register] takes no arguments")
+            .to_compile_error()
+            .into();
+    }
 
-// Function to convert CamelCase to snake_case and then to an Ident
-fn camel_to_snake(name: &str) -> Ident {
-    let mut snake_case = String::new();
-    for (i, ch) in name.chars().enumerate() {
-        if ch.is_uppercase() && i != 0 {
-            snake_case.push('_');
-        }
-        snake_case.push(ch.to_ascii_lowercase());
+    let item_impl = parse_macro_input!(item as ItemImpl);
+    let implements_tool = item_impl
+        .trait_
+        .as_ref()
+        .and_then(|(_, path, _)| path.segments.last())
+        .is_some_and(|segment| segment.ident == "Tool");
+    if !implements_tool {
+        return syn::Error::new_spanned(
+            &item_impl.self_ty,
+            "#[auto_register] goes on an `impl Tool for YourTool` block",
+        )
+        .to_compile_error()
+        .into();
+    }
+    if !item_impl.generics.params.is_empty() {
+        return syn::Error::new_spanned(
+            &item_impl.generics,
+            "generic tools can't be registered; register a concrete type",
+        )
+        .to_compile_error()
+        .into();
     }
-    Ident::new(&snake_case, Span::call_site())
+
+    let self_ty = &item_impl.self_ty;
+    // Spanned so a missing `Default` impl is reported on the tool type
+    let constructor = quote_spanned! {self_ty.span()=>
+        <#self_ty as ::core::default::Default>::default()
+    };
+    let expanded = quote! {
+        #item_impl
+
+        inventory::submit! {
+            crate::registry::tool_registry::ToolRegistration::new(|| Box::new(#constructor))
+        }
+    };
+
+    expanded.into()
 }
 
-#[proc_macro]
-pub fn auto_register_tools(_item: TokenStream) -> TokenStream {
-    let struct_re = Regex::new(r"pub struct\s+(\w+)").unwrap();
-    let mut registrations = Vec::new();
+// One `key = value` entry of `#[tool(name = "...", input = InputType)]`
+enum ToolArg {
+    Name(LitStr),
+    Input(Type),
+}
 
-    // Iterate over files in the `tools/` directory
-    for entry in WalkDir::new("src/tools").into_iter().filter_map(|e| e.ok()) {
-        if entry.file_type().is_file() {
-            let content = std::fs::read_to_string(entry.path()).expect("Unable to read file");
+impl Parse for ToolArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+        match key.to_string().as_str() {
+            "name" => Ok(ToolArg::Name(input.parse()?)),
+            "input" => Ok(ToolArg::Input(input.parse()?)),
+            _ => Err(syn::Error::new(key.span(), "expected `name` or `input`")),
+        }
+    }
+}
 
-            // Find all struct declarations
-            for cap in struct_re.captures_iter(&content) {
-                let struct_name = &cap[1];
-                let struct_ident = syn::Ident::new(struct_name, proc_macro2::Span::call_site());
-                let module_name = camel_to_snake(&struct_ident.to_string());
+// Join `///` lines into one description, keeping blank lines as paragraph breaks
+fn doc_comment(attrs: &[syn::Attribute]) -> String {
+    let mut paragraphs: Vec<String> = vec![String::new()];
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("doc")) {
+        if let Ok(syn::Meta::NameValue(syn::MetaNameValue {
+            lit: syn::Lit::Str(line),
+            ..
+        })) = attr.parse_meta()
+        {
+            let line = line.value();
+            let line = line.trim();
+            let current = paragraphs.last_mut().unwrap();
+            if line.is_empty() {
+                if !current.is_empty() {
+                    paragraphs.push(String::new());
+                }
+            } else {
+                if !current.is_empty() {
+                    current.push(' ');
+                }
+                current.push_str(line);
+            }
+        }
+    }
+    paragraphs.retain(|paragraph| !paragraph.is_empty());
+    paragraphs.join("\n")
+}
 
-                // Generate imports and registration calls
-                registrations.push(quote! {
-                    use crate::tools::#module_name::#struct_ident;
+// Implements `ToolSpec` from the tool struct's doc comment and its input type, so the schema the
+// model sees is generated from the same type the arguments are deserialized into:
+//
+//     /// Executes a list of Linux shell commands and returns their concatenated output.
+//     #[derive(Tool)]
+//     #[tool(name = "shell_tool", input = ShellToolInput)]
+//     pub struct ShellTool;
+//
+// Also adds `Self::parse_input(args)` to deserialize the arguments into the input type.
+#[proc_macro_derive(Tool, attributes(tool))]
+pub fn derive_tool(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    let ident = &input.ident;
 
-                    registry.register(#struct_ident);
-                });
+    let mut name: Option<LitStr> = None;
+    let mut input_type: Option<Type> = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path.is_ident("tool")) {
+        let args = match attr.parse_args_with(Punctuated::<ToolArg, Token![,]>::parse_terminated) {
+            Ok(args) => args,
+            Err(e) => return e.to_compile_error().into(),
+        };
+        for arg in args {
+            match arg {
+                ToolArg::Name(value) => name = Some(value),
+                ToolArg::Input(value) => input_type = Some(value),
             }
         }
     }
 
-    // Combine everything into a single output
+    let (name, input_type) = match (name, input_type) {
+        (Some(name), Some(input_type)) => (name, input_type),
+        _ => {
+            return syn::Error::new_spanned(
+                ident,
+                "expected #[tool(name = \"...\", input = InputType)]",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+
+    let description = doc_comment(&input.attrs);
+    if description.is_empty() {
+        return syn::Error::new_spanned(
+            ident,
+            "document the tool with a `///` comment; it becomes the description the model sees",
+        )
+        .to_compile_error()
+        .into();
+    }
+
     let expanded = quote! {
-        pub fn register_tools(registry: &mut ToolRegistry) {
-            #( #registrations )*
+        impl crate::models::traits::ToolSpec for #ident {
+            fn name(&self) -> &'static str {
+                #name
+            }
+
+            fn description(&self) -> &'static str {
+                #description
+            }
+
+            fn parameters(&self) -> serde_json::Value {
+                crate::models::traits::parameters_schema::<#input_type>()
+            }
+
+            fn input_schema(&self) -> schemars::schema::RootSchema {
+                schemars::schema_for!(#input_type)
+            }
+        }
+
+        impl #ident {
+            fn parse_input(args: serde_json::Value) -> Result<#input_type, crate::models::types::AppError> {
+                serde_json::from_value(args).map_err(crate::models::types::AppError::from)
+            }
         }
     };
 
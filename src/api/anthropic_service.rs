@@ -0,0 +1,642 @@
+use crate::{
+    api::{
+        provider::{ContentCallback, LlmProvider, ToolOffer},
+        stream::{ChatStreamAccumulator, SseDecoder},
+    },
+    models::types::{
+        AppError, ChatCompletionChunk, Choice, ChunkChoice, Delta, FunctionCall, FunctionCallDelta,
+        Message, OpenAIResponse, ToolCall, ToolCallDelta, Usage,
+    },
+    utils::common::{classify_api_error, next_chunk, post_json, post_stream, RetryPolicy},
+};
+
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+// Talks to Anthropic's Messages API, translating to and from the OpenAI message and tool shapes
+pub struct AnthropicService {
+    url: String,
+    api_key: String,
+    model: String,
+    client: Client,
+    timeout: Duration,
+    retry: RetryPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: JsonValue,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicResponse {
+    id: String,
+    model: String,
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamMessageStart {
+    id: String,
+    model: String,
+    #[serde(default)]
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageDeltaBody {
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StreamError {
+    #[serde(rename = "type")]
+    r#type: String,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StreamMessageStart,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: BlockDelta,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    MessageDelta {
+        delta: MessageDeltaBody,
+        usage: Option<AnthropicUsage>,
+    },
+    MessageStop,
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+impl AnthropicService {
+    pub fn new(
+        base_url: &str,
+        api_key: String,
+        model: String,
+        client: Client,
+        timeout: Duration,
+        retry: RetryPolicy,
+    ) -> Self {
+        Self {
+            url: format!("{}/messages", base_url),
+            api_key,
+            model,
+            client,
+            timeout,
+            retry,
+        }
+    }
+
+    fn request(&self) -> RequestBuilder {
+        self.client
+            .post(&self.url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+    }
+
+    fn build_payload(&self, messages: &[Message], tools: Option<ToolOffer<'_>>) -> JsonValue {
+        let (system, messages) = translate_messages(messages);
+
+        let mut payload = json!({
+            "model": self.model,
+            "max_tokens": DEFAULT_MAX_TOKENS,
+            "messages": messages,
+        });
+        if let Some(system) = system {
+            payload["system"] = json!(system);
+        }
+        if let Some(offer) = tools {
+            payload["tools"] = translate_tools(offer.tools);
+            if !offer.allow_calls {
+                payload["tool_choice"] = json!({ "type": "none" });
+            }
+        }
+
+        payload
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicService {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Option<ToolOffer<'_>>,
+    ) -> Result<OpenAIResponse, AppError> {
+        let payload = self.build_payload(messages, tools);
+        let response: AnthropicResponse =
+            post_json(self.request().timeout(self.timeout), &payload, &self.retry).await?;
+
+        Ok(translate_response(response))
+    }
+
+    async fn complete_stream(
+        &self,
+        messages: &[Message],
+        tools: Option<ToolOffer<'_>>,
+        on_content: ContentCallback<'_>,
+    ) -> Result<OpenAIResponse, AppError> {
+        let mut payload = self.build_payload(messages, tools);
+        payload["stream"] = json!(true);
+
+        let mut response = post_stream(self.request(), &payload, &self.retry).await?;
+
+        let mut decoder = SseDecoder::new();
+        let mut translator = StreamTranslator::default();
+        let mut accumulator = ChatStreamAccumulator::new();
+
+        'stream: while let Some(bytes) = next_chunk(&mut response, self.timeout).await? {
+            for data in decoder.push(&bytes) {
+                let event: StreamEvent = serde_json::from_str(&data)?;
+                if matches!(event, StreamEvent::MessageStop) {
+                    break 'stream;
+                }
+
+                if let Some(chunk) = translator.translate(event)? {
+                    if let Some(content) = accumulator.push(chunk)? {
+                        on_content(&content)?;
+                    }
+                }
+            }
+        }
+
+        Ok(accumulator.finish())
+    }
+}
+
+// Split out the system prompt and convert the rest into Anthropic content blocks.
+// Tool results become `tool_result` blocks on a user turn, and consecutive turns with the
+// same role are merged since the Messages API requires user and assistant to alternate.
+fn translate_messages(messages: &[Message]) -> (Option<String>, Vec<JsonValue>) {
+    let mut system_parts: Vec<&str> = Vec::new();
+    let mut turns: Vec<(&str, Vec<JsonValue>)> = Vec::new();
+
+    for message in messages {
+        let (role, blocks) = match message.role.as_str() {
+            "system" => {
+                if let Some(content) = &message.content {
+                    system_parts.push(content);
+                }
+                continue;
+            }
+            "tool" => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": message.tool_call_id.clone().unwrap_or_default(),
+                    "content": message.content.clone().unwrap_or_default(),
+                })],
+            ),
+            "assistant" => {
+                let mut blocks = Vec::new();
+                if let Some(content) = message.content.as_ref().filter(|c| !c.is_empty()) {
+                    blocks.push(json!({ "type": "text", "text": content }));
+                }
+                for tool_call in message.tool_calls.iter().flatten() {
+                    let input: JsonValue = serde_json::from_str(&tool_call.function.arguments)
+                        .unwrap_or_else(|_| json!({}));
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": tool_call.id,
+                        "name": tool_call.function.name,
+                        "input": input,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            _ => (
+                "user",
+                vec![json!({
+                    "type": "text",
+                    "text": message.content.clone().unwrap_or_default(),
+                })],
+            ),
+        };
+
+        if blocks.is_empty() {
+            continue;
+        }
+
+        match turns.last_mut() {
+            Some((last_role, last_blocks)) if *last_role == role => last_blocks.extend(blocks),
+            _ => turns.push((role, blocks)),
+        }
+    }
+
+    let system = if system_parts.is_empty() {
+        None
+    } else {
+        Some(system_parts.join("\n\n"))
+    };
+    let messages = turns
+        .into_iter()
+        .map(|(role, content)| json!({ "role": role, "content": content }))
+        .collect();
+
+    (system, messages)
+}
+
+// OpenAI wraps each tool in `{"type": "function", "function": {...}}`; Anthropic takes them flat
+fn translate_tools(tools_json: &JsonValue) -> JsonValue {
+    let tools = tools_json
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|tool| tool.get("function"))
+        .map(|function| {
+            json!({
+                "name": function["name"],
+                "description": function["description"],
+                "input_schema": function["parameters"],
+            })
+        })
+        .collect();
+
+    JsonValue::Array(tools)
+}
+
+fn translate_stop_reason(stop_reason: Option<&str>) -> String {
+    match stop_reason {
+        Some("tool_use") => "tool_calls",
+        Some("max_tokens") => "length",
+        _ => "stop",
+    }
+    .to_string()
+}
+
+fn translate_response(response: AnthropicResponse) -> OpenAIResponse {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+
+    for block in response.content {
+        match block {
+            ContentBlock::Text { text: part } => text.push_str(&part),
+            ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                id,
+                r#type: "function".to_string(),
+                function: FunctionCall {
+                    name,
+                    arguments: input.to_string(),
+                },
+            }),
+            ContentBlock::Other => {}
+        }
+    }
+
+    let message = Message {
+        role: "assistant".to_string(),
+        content: if text.is_empty() { None } else { Some(text) },
+        tool_calls: if tool_calls.is_empty() {
+            None
+        } else {
+            Some(tool_calls)
+        },
+        tool_call_id: None,
+        name: None,
+    };
+
+    OpenAIResponse {
+        id: response.id,
+        model: response.model,
+        choices: vec![Choice {
+            index: 0,
+            message,
+            finish_reason: translate_stop_reason(response.stop_reason.as_deref()),
+        }],
+        usage: Usage {
+            prompt_tokens: response.usage.input_tokens,
+            completion_tokens: response.usage.output_tokens,
+            total_tokens: response.usage.input_tokens + response.usage.output_tokens,
+        },
+    }
+}
+
+// Turns Anthropic stream events into OpenAI-style chunks so `ChatStreamAccumulator` can fold them
+#[derive(Default)]
+struct StreamTranslator {
+    id: String,
+    model: String,
+    input_tokens: u32,
+    // Content block index -> (tool call index, whether any input JSON has arrived)
+    tool_blocks: HashMap<usize, (usize, bool)>,
+}
+
+impl StreamTranslator {
+    fn chunk(&self, delta: Delta, finish_reason: Option<String>) -> ChatCompletionChunk {
+        ChatCompletionChunk {
+            id: self.id.clone(),
+            model: self.model.clone(),
+            choices: vec![ChunkChoice {
+                delta,
+                finish_reason,
+            }],
+            usage: None,
+        }
+    }
+
+    fn tool_chunk(
+        &self,
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: String,
+    ) -> ChatCompletionChunk {
+        self.chunk(
+            Delta {
+                tool_calls: Some(vec![ToolCallDelta {
+                    index,
+                    id,
+                    r#type: Some("function".to_string()),
+                    function: Some(FunctionCallDelta {
+                        name,
+                        arguments: Some(arguments),
+                    }),
+                }]),
+                ..Delta::default()
+            },
+            None,
+        )
+    }
+
+    fn translate(&mut self, event: StreamEvent) -> Result<Option<ChatCompletionChunk>, AppError> {
+        let chunk = match event {
+            StreamEvent::MessageStart { message } => {
+                self.id = message.id;
+                self.model = message.model;
+                self.input_tokens = message.usage.input_tokens;
+                self.chunk(
+                    Delta {
+                        role: Some("assistant".to_string()),
+                        ..Delta::default()
+                    },
+                    None,
+                )
+            }
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block: ContentBlock::ToolUse { id, name, .. },
+            } => {
+                let tool_index = self.tool_blocks.len();
+                self.tool_blocks.insert(index, (tool_index, false));
+                self.tool_chunk(tool_index, Some(id), Some(name), String::new())
+            }
+            StreamEvent::ContentBlockDelta {
+                delta: BlockDelta::TextDelta { text },
+                ..
+            } => self.chunk(
+                Delta {
+                    content: Some(text),
+                    ..Delta::default()
+                },
+                None,
+            ),
+            StreamEvent::ContentBlockDelta {
+                index,
+                delta: BlockDelta::InputJsonDelta { partial_json },
+            } => match self.tool_blocks.get_mut(&index) {
+                Some((tool_index, has_input)) => {
+                    *has_input = true;
+                    let tool_index = *tool_index;
+                    self.tool_chunk(tool_index, None, None, partial_json)
+                }
+                None => return Ok(None),
+            },
+            // A tool called without arguments never sends input JSON, but callers expect an object
+            StreamEvent::ContentBlockStop { index } => match self.tool_blocks.get(&index) {
+                Some((tool_index, false)) => {
+                    self.tool_chunk(*tool_index, None, None, "{}".to_string())
+                }
+                _ => return Ok(None),
+            },
+            StreamEvent::MessageDelta { delta, usage } => {
+                let mut chunk = self.chunk(
+                    Delta::default(),
+                    Some(translate_stop_reason(delta.stop_reason.as_deref())),
+                );
+                if let Some(usage) = usage {
+                    chunk.usage = Some(Usage {
+                        prompt_tokens: self.input_tokens,
+                        completion_tokens: usage.output_tokens,
+                        total_tokens: self.input_tokens + usage.output_tokens,
+                    });
+                }
+                chunk
+            }
+            // Errors after the stream has started arrive as events rather than HTTP statuses
+            StreamEvent::Error { error } => {
+                let body = json!({ "error": error }).to_string();
+                return Err(classify_api_error(200, &body, None));
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::provider::{ANTHROPIC_BASE_URL, DEFAULT_TIMEOUT};
+
+    fn tool_call(id: &str, name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_translate_messages_merges_tool_results() {
+        let mut assistant = Message::new("assistant".to_string(), "Let me check".to_string());
+        assistant.tool_calls = Some(vec![
+            tool_call("call_1", "snap_tool", "{}"),
+            tool_call("call_2", "shell_tool", r#"{"commands":[{"command":"ls"}]}"#),
+        ]);
+        let tool_result = |id: &str| Message {
+            role: "tool".to_string(),
+            content: Some(format!("result {}", id)),
+            tool_calls: None,
+            tool_call_id: Some(id.to_string()),
+            name: None,
+        };
+
+        let messages = vec![
+            Message::new("system".to_string(), "Be helpful".to_string()),
+            Message::new("user".to_string(), "Hi".to_string()),
+            assistant,
+            tool_result("call_1"),
+            tool_result("call_2"),
+        ];
+
+        let (system, translated) = translate_messages(&messages);
+        assert_eq!(system.as_deref(), Some("Be helpful"));
+        assert_eq!(translated.len(), 3);
+        assert_eq!(translated[1]["role"], "assistant");
+        assert_eq!(translated[1]["content"][2]["type"], "tool_use");
+        assert_eq!(
+            translated[1]["content"][2]["input"]["commands"][0]["command"],
+            "ls"
+        );
+        assert_eq!(translated[2]["role"], "user");
+        assert_eq!(translated[2]["content"][1]["tool_use_id"], "call_2");
+    }
+
+    #[test]
+    fn test_translate_tools() {
+        let tools = json!([{
+            "type": "function",
+            "function": {"name": "snap_tool", "description": "Snapshot", "parameters": {"type": "object"}}
+        }]);
+        let translated = translate_tools(&tools);
+        assert_eq!(
+            translated,
+            json!([{"name": "snap_tool", "description": "Snapshot", "input_schema": {"type": "object"}}])
+        );
+    }
+
+    #[test]
+    fn test_build_payload_turns_tool_calls_off() {
+        let service = AnthropicService::new(
+            ANTHROPIC_BASE_URL,
+            String::new(),
+            "claude".to_string(),
+            Client::new(),
+            DEFAULT_TIMEOUT,
+            RetryPolicy::default(),
+        );
+        let messages = vec![Message::new("user".to_string(), "Hi".to_string())];
+        let tools = json!([{"type": "function", "function": {"name": "snap_tool"}}]);
+
+        let payload = service.build_payload(&messages, Some(ToolOffer::new(&tools, true)));
+        assert_eq!(payload["tools"][0]["name"], "snap_tool");
+        assert!(payload.get("tool_choice").is_none());
+
+        // The tools are still sent, so earlier tool_use blocks stay valid
+        let payload = service.build_payload(&messages, Some(ToolOffer::new(&tools, false)));
+        assert_eq!(payload["tools"][0]["name"], "snap_tool");
+        assert_eq!(payload["tool_choice"], json!({"type": "none"}));
+    }
+
+    #[test]
+    fn test_translate_response_with_tool_use() {
+        let response: AnthropicResponse = serde_json::from_value(json!({
+            "id": "msg_1",
+            "model": "claude",
+            "content": [
+                {"type": "text", "text": "Running it"},
+                {"type": "tool_use", "id": "toolu_1", "name": "shell_tool", "input": {"commands": []}}
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 10, "output_tokens": 5}
+        }))
+        .unwrap();
+
+        let translated = translate_response(response);
+        let choice = &translated.choices[0];
+        assert_eq!(choice.finish_reason, "tool_calls");
+        assert_eq!(choice.message.content.as_deref(), Some("Running it"));
+        let tool_calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].id, "toolu_1");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"commands":[]}"#);
+        assert_eq!(translated.usage.total_tokens, 15);
+    }
+
+    #[test]
+    fn test_stream_translation_reassembles_tool_input() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_2","model":"claude","usage":{"input_tokens":7,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hi"}}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_2","name":"shell_tool","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"commands\""}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":":[]}"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"content_block_start","index":2,"content_block":{"type":"tool_use","id":"toolu_3","name":"snap_tool","input":{}}}"#,
+            r#"{"type":"content_block_stop","index":2}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":12}}"#,
+        ];
+
+        let mut translator = StreamTranslator::default();
+        let mut accumulator = ChatStreamAccumulator::new();
+        for event in events {
+            let event: StreamEvent = serde_json::from_str(event).unwrap();
+            if let Some(chunk) = translator.translate(event).unwrap() {
+                accumulator.push(chunk).unwrap();
+            }
+        }
+
+        let response = accumulator.finish();
+        let message = &response.choices[0].message;
+        assert_eq!(message.content.as_deref(), Some("Hi"));
+        let tool_calls = message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].function.arguments, r#"{"commands":[]}"#);
+        assert_eq!(tool_calls[1].function.arguments, "{}");
+        assert_eq!(response.choices[0].finish_reason, "tool_calls");
+        assert_eq!(response.usage.total_tokens, 19);
+    }
+}
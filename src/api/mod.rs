@@ -1 +1,4 @@
+pub mod anthropic_service;
 pub mod openai_service;
+pub mod provider;
+pub mod stream;
@@ -1,49 +1,116 @@
 use crate::{
-    assistant::conversation_manager::ConversationManager,
-    models::types::{AppError, OpenAIResponse},
-    utils::common::post_json,
+    api::{
+        provider::{ContentCallback, LlmProvider, ToolOffer},
+        stream::{ChatStreamAccumulator, SseDecoder},
+    },
+    models::types::{AppError, ChatCompletionChunk, Message, OpenAIResponse},
+    utils::common::{next_chunk, post_json, post_stream, RetryPolicy},
 };
 
-use reqwest::Client;
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder};
 use serde_json::{json, Value as JsonValue};
+use std::time::Duration;
 
+// Talks to OpenAI or any server exposing the same `/chat/completions` API (vLLM, llama.cpp, Ollama)
 pub struct OpenAIService {
     url: String,
-    api_key: String,
+    api_key: Option<String>,
     model: String,
     client: Client,
-    tools_json: JsonValue,
+    timeout: Duration,
+    retry: RetryPolicy,
 }
 
 impl OpenAIService {
-    pub fn new(api_key: String, model: String, client: Client, tools_json: JsonValue) -> Self {
+    pub fn new(
+        base_url: &str,
+        api_key: Option<String>,
+        model: String,
+        client: Client,
+        timeout: Duration,
+        retry: RetryPolicy,
+    ) -> Self {
         Self {
-            url: "https://api.openai.com/v1/chat/completions".to_string(),
+            url: format!("{}/chat/completions", base_url),
             api_key,
             model,
             client,
-            tools_json,
+            timeout,
+            retry,
         }
     }
 
-    pub async fn call_openai_api(
-        &mut self,
-        conversation_manager: &ConversationManager,
-        include_tools: bool,
+    fn request(&self) -> RequestBuilder {
+        let request = self.client.post(&self.url);
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+
+    fn build_payload(&self, messages: &[Message], tools: Option<ToolOffer<'_>>) -> JsonValue {
+        let mut payload = json!({
+            "model": self.model,
+            "messages": messages,
+        });
+        if let Some(offer) = tools {
+            payload["tools"] = offer.tools.clone();
+            if !offer.allow_calls {
+                payload["tool_choice"] = json!("none");
+            }
+        }
+
+        payload
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAIService {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Option<ToolOffer<'_>>,
     ) -> Result<OpenAIResponse, AppError> {
-        let payload = if include_tools {
-            json!({
-                "model": self.model,
-                "messages": conversation_manager.messages,
-                "tools": self.tools_json,
-            })
-        } else {
-            json!({
-                "model": self.model,
-                "messages": conversation_manager.messages,
-            })
-        };
-
-        post_json(&self.client, &self.url, &self.api_key, &payload).await
+        let payload = self.build_payload(messages, tools);
+
+        post_json(self.request().timeout(self.timeout), &payload, &self.retry).await
+    }
+
+    // Same as `complete`, but with `stream: true`: content deltas are passed to `on_content`
+    // as they arrive and the fragmented tool calls are reassembled into the returned response
+    async fn complete_stream(
+        &self,
+        messages: &[Message],
+        tools: Option<ToolOffer<'_>>,
+        on_content: ContentCallback<'_>,
+    ) -> Result<OpenAIResponse, AppError> {
+        let mut payload = self.build_payload(messages, tools);
+        payload["stream"] = json!(true);
+        payload["stream_options"] = json!({ "include_usage": true });
+
+        let mut response = post_stream(self.request(), &payload, &self.retry).await?;
+
+        let mut decoder = SseDecoder::new();
+        let mut accumulator = ChatStreamAccumulator::new();
+
+        'stream: while let Some(bytes) = next_chunk(&mut response, self.timeout).await? {
+            for data in decoder.push(&bytes) {
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+
+                let chunk: ChatCompletionChunk = serde_json::from_str(&data)?;
+                if let Some(content) = accumulator.push(chunk)? {
+                    on_content(&content)?;
+                }
+            }
+        }
+
+        Ok(accumulator.finish())
     }
 }
@@ -0,0 +1,169 @@
+use crate::{
+    api::{anthropic_service::AnthropicService, openai_service::OpenAIService},
+    models::types::{AppError, Message, OpenAIResponse},
+    utils::common::RetryPolicy,
+};
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::Value as JsonValue;
+use std::env;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+pub const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Callback receiving content deltas while a streamed completion is in flight
+pub type ContentCallback<'a> = &'a mut (dyn FnMut(&str) -> Result<(), AppError> + Send);
+
+// The tools sent with a request. Once the model may no longer call them they're still sent,
+// with calls turned off, since a history with tool calls but no tools is rejected by some providers.
+#[derive(Debug, Clone, Copy)]
+pub struct ToolOffer<'a> {
+    pub tools: &'a JsonValue,
+    pub allow_calls: bool,
+}
+
+impl<'a> ToolOffer<'a> {
+    pub fn new(tools: &'a JsonValue, allow_calls: bool) -> Self {
+        Self { tools, allow_calls }
+    }
+}
+
+// A chat completion backend. Messages, tools and responses always use the OpenAI shapes;
+// providers with a different wire format translate at the boundary.
+#[async_trait]
+pub trait LlmProvider: Sync + Send {
+    fn model(&self) -> &str;
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Option<ToolOffer<'_>>,
+    ) -> Result<OpenAIResponse, AppError>;
+
+    async fn complete_stream(
+        &self,
+        messages: &[Message],
+        tools: Option<ToolOffer<'_>>,
+        on_content: ContentCallback<'_>,
+    ) -> Result<OpenAIResponse, AppError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProviderKind {
+    OpenAI,
+    OpenAICompatible,
+    Anthropic,
+}
+
+impl FromStr for ProviderKind {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "openai" => Ok(ProviderKind::OpenAI),
+            "openai-compatible" | "compatible" | "local" => Ok(ProviderKind::OpenAICompatible),
+            "anthropic" => Ok(ProviderKind::Anthropic),
+            other => Err(AppError::CommandError(format!(
+                "Unknown provider `{}` (expected openai, openai-compatible or anthropic)",
+                other
+            ))),
+        }
+    }
+}
+
+// Everything needed to construct a provider, except the model which may vary per call
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    pub kind: ProviderKind,
+    pub base_url: String,
+    pub api_key: Option<String>,
+    // Whole-request timeout, or the maximum silence between chunks when streaming
+    pub timeout: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl ProviderConfig {
+    // Resolve the base URL and API key for a provider, reading keys from the environment
+    pub fn new(
+        kind: ProviderKind,
+        base_url: Option<String>,
+        timeout: Duration,
+        retry: RetryPolicy,
+    ) -> Result<Self, AppError> {
+        let (default_base_url, key_var) = match kind {
+            ProviderKind::OpenAI => (Some(OPENAI_BASE_URL), "OPENAI_API_KEY"),
+            ProviderKind::OpenAICompatible => (None, "OPENAI_API_KEY"),
+            ProviderKind::Anthropic => (Some(ANTHROPIC_BASE_URL), "ANTHROPIC_API_KEY"),
+        };
+
+        let base_url = base_url
+            .or_else(|| default_base_url.map(str::to_string))
+            .ok_or_else(|| {
+                AppError::CommandError(
+                    "The openai-compatible provider requires a base URL (--base-url)".to_string(),
+                )
+            })?;
+
+        // Local OpenAI-compatible servers usually run without authentication
+        let api_key = match env::var(key_var) {
+            Ok(key) => Some(key),
+            Err(_) if kind == ProviderKind::OpenAICompatible => None,
+            Err(_) => return Err(AppError::MissingEnvironmentVariable(key_var.to_string())),
+        };
+
+        Ok(Self {
+            kind,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            timeout,
+            retry,
+        })
+    }
+
+    pub fn build(&self, model: String, client: Client) -> Box<dyn LlmProvider> {
+        match self.kind {
+            ProviderKind::OpenAI | ProviderKind::OpenAICompatible => Box::new(OpenAIService::new(
+                &self.base_url,
+                self.api_key.clone(),
+                model,
+                client,
+                self.timeout,
+                self.retry.clone(),
+            )),
+            ProviderKind::Anthropic => Box::new(AnthropicService::new(
+                &self.base_url,
+                self.api_key.clone().unwrap_or_default(),
+                model,
+                client,
+                self.timeout,
+                self.retry.clone(),
+            )),
+        }
+    }
+}
+
+static GLOBAL_PROVIDER_CONFIG: OnceLock<ProviderConfig> = OnceLock::new();
+
+// Record the provider selected at startup so tools like `gpt_tool` talk to the same backend
+pub fn set_global_provider_config(config: ProviderConfig) {
+    if GLOBAL_PROVIDER_CONFIG.set(config).is_err() {
+        log::warn!("Provider configuration was already set, ignoring");
+    }
+}
+
+pub fn global_provider_config() -> Result<ProviderConfig, AppError> {
+    match GLOBAL_PROVIDER_CONFIG.get() {
+        Some(config) => Ok(config.clone()),
+        None => ProviderConfig::new(
+            ProviderKind::OpenAI,
+            None,
+            DEFAULT_TIMEOUT,
+            RetryPolicy::default(),
+        ),
+    }
+}
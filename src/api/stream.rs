@@ -0,0 +1,262 @@
+use crate::models::types::{
+    AppError, ChatCompletionChunk, Choice, FunctionCall, Message, OpenAIResponse, ToolCall, Usage,
+};
+
+// Tool call indices normally count up from 0; one further than this past the last is a broken
+// stream, not a reason to allocate for it
+const MAX_TOOL_CALL_INDEX_GAP: usize = 16;
+
+// Splits a raw server-sent-events byte stream into the payloads of its `data:` lines
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Feed a chunk of bytes and return every complete `data:` payload it finished
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut payloads = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if let Some(data) = line.strip_prefix("data:") {
+                payloads.push(data.trim_start().to_string());
+            }
+        }
+
+        payloads
+    }
+}
+
+// A tool call being put back together from its `tool_calls` deltas
+#[derive(Default)]
+struct PartialToolCall {
+    id: String,
+    r#type: String,
+    name: String,
+    arguments: String,
+}
+
+// Folds streamed chunks into the same `OpenAIResponse` a non-streaming call would return
+#[derive(Default)]
+pub struct ChatStreamAccumulator {
+    id: String,
+    model: String,
+    role: Option<String>,
+    content: String,
+    tool_calls: Vec<PartialToolCall>,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+}
+
+impl ChatStreamAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Absorb one chunk, returning the content delta (if any) so the caller can render it
+    pub fn push(&mut self, chunk: ChatCompletionChunk) -> Result<Option<String>, AppError> {
+        if self.id.is_empty() {
+            self.id = chunk.id;
+            self.model = chunk.model;
+        }
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage;
+        }
+
+        let mut content_delta: Option<String> = None;
+        for choice in chunk.choices {
+            if let Some(role) = choice.delta.role {
+                self.role = Some(role);
+            }
+            if let Some(content) = choice.delta.content {
+                self.content.push_str(&content);
+                content_delta
+                    .get_or_insert_with(String::new)
+                    .push_str(&content);
+            }
+            for delta in choice.delta.tool_calls.unwrap_or_default() {
+                // Tool call deltas are keyed by index; the first one for an index carries id and name
+                if delta.index >= self.tool_calls.len() + MAX_TOOL_CALL_INDEX_GAP {
+                    return Err(AppError::CommandError(format!(
+                        "The stream sent tool call index {} with only {} tool calls so far",
+                        delta.index,
+                        self.tool_calls.len()
+                    )));
+                }
+                if self.tool_calls.len() <= delta.index {
+                    self.tool_calls
+                        .resize_with(delta.index + 1, PartialToolCall::default);
+                }
+                let partial = &mut self.tool_calls[delta.index];
+                if let Some(id) = delta.id {
+                    partial.id = id;
+                }
+                if let Some(r#type) = delta.r#type {
+                    partial.r#type = r#type;
+                }
+                if let Some(function) = delta.function {
+                    if let Some(name) = function.name {
+                        partial.name.push_str(&name);
+                    }
+                    if let Some(arguments) = function.arguments {
+                        partial.arguments.push_str(&arguments);
+                    }
+                }
+            }
+            if choice.finish_reason.is_some() {
+                self.finish_reason = choice.finish_reason;
+            }
+        }
+
+        Ok(content_delta)
+    }
+
+    pub fn finish(self) -> OpenAIResponse {
+        let tool_calls: Vec<ToolCall> = self
+            .tool_calls
+            .into_iter()
+            .map(|partial| ToolCall {
+                id: partial.id,
+                r#type: if partial.r#type.is_empty() {
+                    "function".to_string()
+                } else {
+                    partial.r#type
+                },
+                function: FunctionCall {
+                    name: partial.name,
+                    arguments: partial.arguments,
+                },
+            })
+            .collect();
+
+        let usage = self.usage.unwrap_or_else(|| {
+            log::warn!("Stream ended without a usage report");
+            Usage::default()
+        });
+
+        let message = Message {
+            role: self.role.unwrap_or_else(|| "assistant".to_string()),
+            content: if self.content.is_empty() {
+                None
+            } else {
+                Some(self.content)
+            },
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(tool_calls)
+            },
+            tool_call_id: None,
+            name: None,
+        };
+
+        OpenAIResponse {
+            id: self.id,
+            model: self.model,
+            choices: vec![Choice {
+                index: 0,
+                message,
+                finish_reason: self.finish_reason.unwrap_or_else(|| "stop".to_string()),
+            }],
+            usage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(json: &str) -> ChatCompletionChunk {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_sse_decoder_handles_split_lines() {
+        let mut decoder = SseDecoder::new();
+        assert!(decoder.push(b"data: {\"a\"").is_empty());
+        let payloads = decoder.push(b":1}\r\n\r\n: keep-alive\ndata: [DONE]\n");
+        assert_eq!(payloads, vec!["{\"a\":1}", "[DONE]"]);
+    }
+
+    #[test]
+    fn test_accumulates_content_and_usage() {
+        let mut acc = ChatStreamAccumulator::new();
+        let first = acc.push(chunk(
+            r#"{"id":"c1","model":"m","choices":[{"delta":{"role":"assistant","content":"Hel"},"finish_reason":null}]}"#,
+        ))
+        .unwrap();
+        let second = acc.push(chunk(
+            r#"{"id":"c1","model":"m","choices":[{"delta":{"content":"lo"},"finish_reason":"stop"}]}"#,
+        ))
+        .unwrap();
+        acc.push(chunk(
+            r#"{"id":"c1","model":"m","choices":[],"usage":{"prompt_tokens":3,"completion_tokens":2,"total_tokens":5}}"#,
+        ))
+        .unwrap();
+
+        assert_eq!(first.as_deref(), Some("Hel"));
+        assert_eq!(second.as_deref(), Some("lo"));
+
+        let response = acc.finish();
+        assert_eq!(
+            response.choices[0].message.content.as_deref(),
+            Some("Hello")
+        );
+        assert_eq!(response.choices[0].finish_reason, "stop");
+        assert_eq!(response.usage.total_tokens, 5);
+    }
+
+    #[test]
+    fn test_reassembles_fragmented_tool_calls() {
+        let mut acc = ChatStreamAccumulator::new();
+        acc.push(chunk(
+            r#"{"id":"c2","model":"m","choices":[{"delta":{"role":"assistant","tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"shell_tool","arguments":""}}]},"finish_reason":null}]}"#,
+        ))
+        .unwrap();
+        acc.push(chunk(
+            r#"{"id":"c2","model":"m","choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"commands\":"}}]},"finish_reason":null}]}"#,
+        ))
+        .unwrap();
+        acc.push(chunk(
+            r#"{"id":"c2","model":"m","choices":[{"delta":{"tool_calls":[{"index":1,"id":"call_b","type":"function","function":{"name":"snap_tool","arguments":"{}"}}]},"finish_reason":null}]}"#,
+        ))
+        .unwrap();
+        acc.push(chunk(
+            r#"{"id":"c2","model":"m","choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"[]}"}}]},"finish_reason":"tool_calls"}]}"#,
+        ))
+        .unwrap();
+
+        let response = acc.finish();
+        let message = &response.choices[0].message;
+        assert_eq!(message.content, None);
+
+        let tool_calls = message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "call_a");
+        assert_eq!(tool_calls[0].function.name, "shell_tool");
+        assert_eq!(tool_calls[0].function.arguments, "{\"commands\":[]}");
+        assert_eq!(tool_calls[1].function.name, "snap_tool");
+        assert_eq!(response.choices[0].finish_reason, "tool_calls");
+    }
+
+    #[test]
+    fn test_rejects_tool_call_index_far_past_the_end() {
+        let mut acc = ChatStreamAccumulator::new();
+        let error = acc
+            .push(chunk(
+                r#"{"id":"c3","model":"m","choices":[{"delta":{"tool_calls":[{"index":4000000000,"id":"call_a"}]},"finish_reason":null}]}"#,
+            ))
+            .unwrap_err();
+        assert!(error.to_string().contains("tool call index 4000000000"));
+    }
+}
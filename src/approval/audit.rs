@@ -0,0 +1,36 @@
+use crate::models::types::AppError;
+
+use serde_derive::Serialize;
+use serde_json::Value as JsonValue;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+// Kept in the logs directory
+pub const AUDIT_LOG: &str = "audit.jsonl";
+
+// One line of the audit log: what was asked to run and who or what decided
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    pub timestamp: String,
+    pub conversation_id: &'a str,
+    pub tool: &'a str,
+    pub arguments: &'a JsonValue,
+    // `allow` or `deny`
+    pub decision: &'a str,
+    // The rule, `default policy`, `user`, `session` or `yolo`
+    pub decided_by: &'a str,
+    // What the model was told about a rejected call, including any reason the user gave
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<&'a str>,
+}
+
+// Append an entry, creating the log if needed
+pub fn record(path: &Path, entry: &AuditEntry) -> Result<(), AppError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
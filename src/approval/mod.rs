@@ -0,0 +1,496 @@
+pub mod audit;
+pub mod policy;
+
+use crate::registry::validation::child_pointer;
+use crate::{
+    assistant::GLOBAL_TOOL_REGISTRY,
+    config::settings,
+    models::types::{AppError, ToolCall},
+    utils::common::{edit_in_editor, print_colorful, request_tool_call_approval},
+};
+use audit::{record, AuditEntry, AUDIT_LOG};
+use policy::{Action, ApprovalPolicy};
+
+use async_trait::async_trait;
+use crossterm::style::Color;
+use serde_json::Value as JsonValue;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// What the user typed at the approval prompt
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApprovalAnswer {
+    Yes,
+    // No, optionally saying why (`n: use cargo check instead`)
+    No(Option<String>),
+    // Yes, and don't ask again about this tool for the rest of the session
+    Always,
+    // Edit the arguments, then ask again
+    Edit,
+}
+
+impl ApprovalAnswer {
+    // Anything that isn't a clear yes is a no
+    pub fn parse(input: &str) -> Self {
+        let input = input.trim();
+        if let Some((answer, reason)) = input.split_once(':') {
+            if matches!(answer.trim().to_lowercase().as_str(), "n" | "no") {
+                let reason = reason.trim();
+                return ApprovalAnswer::No((!reason.is_empty()).then(|| reason.to_string()));
+            }
+        }
+        match input.to_lowercase().as_str() {
+            "y" | "yes" => ApprovalAnswer::Yes,
+            "a" | "always" => ApprovalAnswer::Always,
+            "e" | "edit" => ApprovalAnswer::Edit,
+            _ => ApprovalAnswer::No(None),
+        }
+    }
+}
+
+// The user's final answer about a call the policy left to them
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserDecision {
+    // Run the call with these arguments, which the user may have edited. `always` stops asking
+    // about the tool for the rest of the session.
+    Approve { arguments: JsonValue, always: bool },
+    // Don't run it, optionally saying why
+    Reject(Option<String>),
+}
+
+// Asks the user about tool calls: at the terminal, or through the HTTP API. `preview` is the
+// tool's diff of what the call would change, if it has one.
+#[async_trait]
+pub trait ApprovalHandler: Send + Sync {
+    async fn ask(
+        &self,
+        tool_call: &ToolCall,
+        args: &JsonValue,
+        preview: Option<&str>,
+    ) -> Result<UserDecision, AppError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Approve,
+    // Run the call with the arguments the user edited it to
+    ApproveEdited(JsonValue),
+    // Don't run the call; the message is sent back to the model as its result
+    Reject(String),
+}
+
+// Decides whether tool calls run: the policy first, then session-wide answers, then the user
+pub struct Approver {
+    policy: ApprovalPolicy,
+    // Run every call the policy would ask about
+    yolo: bool,
+    // Who to ask; without anyone, calls the policy would ask about are rejected
+    handler: Option<Arc<dyn ApprovalHandler>>,
+    // Tools the user answered "always" for
+    always_allowed: HashSet<String>,
+    audit_log: PathBuf,
+}
+
+impl Approver {
+    pub fn new(
+        policy: ApprovalPolicy,
+        yolo: bool,
+        handler: Option<Arc<dyn ApprovalHandler>>,
+    ) -> Self {
+        Self {
+            policy,
+            yolo,
+            handler,
+            always_allowed: HashSet::new(),
+            audit_log: settings().logs_dir.join(AUDIT_LOG),
+        }
+    }
+
+    pub async fn review(
+        &mut self,
+        conversation_id: &str,
+        tool_call: &ToolCall,
+        args: &JsonValue,
+    ) -> Result<Verdict, AppError> {
+        let tool = tool_call.function.name.as_str();
+        let cwd = std::env::current_dir()?;
+        let (action, rule) = self.policy.evaluate(tool, args, &cwd, &|tool, args| {
+            GLOBAL_TOOL_REGISTRY.is_read_only(tool, args)
+        });
+
+        let (verdict, decided_by) = match action {
+            Action::Allow => (Verdict::Approve, rule),
+            Action::Deny => (
+                Verdict::Reject(format!("Tool call denied by the approval policy: {}", rule)),
+                rule,
+            ),
+            Action::Ask if self.yolo => (Verdict::Approve, "yolo".to_string()),
+            Action::Ask if self.always_allowed.contains(tool) => {
+                (Verdict::Approve, "session".to_string())
+            }
+            Action::Ask => match self.handler.clone() {
+                Some(handler) => {
+                    let preview = GLOBAL_TOOL_REGISTRY.preview(tool, args).await;
+                    let decision = handler.ask(tool_call, args, preview.as_deref()).await?;
+                    (self.apply(decision, tool, args), "user".to_string())
+                }
+                None => (
+                    Verdict::Reject(format!(
+                        "Tool call not run: the approval policy asks about it ({}) and there is no user to ask",
+                        rule
+                    )),
+                    rule,
+                ),
+            },
+        };
+
+        let audited_args = match &verdict {
+            Verdict::ApproveEdited(edited) => edited,
+            _ => args,
+        };
+        self.audit(conversation_id, tool, audited_args, &verdict, &decided_by);
+        Ok(verdict)
+    }
+
+    fn apply(&mut self, decision: UserDecision, tool: &str, args: &JsonValue) -> Verdict {
+        match decision {
+            UserDecision::Approve { arguments, always } => {
+                if always {
+                    self.always_allowed.insert(tool.to_string());
+                }
+                if arguments == *args {
+                    Verdict::Approve
+                } else {
+                    Verdict::ApproveEdited(arguments)
+                }
+            }
+            UserDecision::Reject(reason) => Verdict::Reject(rejection_message(reason)),
+        }
+    }
+
+    // A failure to write the audit log is reported but doesn't stop the session
+    fn audit(
+        &self,
+        conversation_id: &str,
+        tool: &str,
+        args: &JsonValue,
+        verdict: &Verdict,
+        decided_by: &str,
+    ) {
+        let entry = AuditEntry {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            conversation_id,
+            tool,
+            arguments: args,
+            decision: match verdict {
+                Verdict::Approve | Verdict::ApproveEdited(_) => "allow",
+                Verdict::Reject(_) => "deny",
+            },
+            decided_by,
+            message: match verdict {
+                Verdict::Reject(message) => Some(message),
+                _ => None,
+            },
+        };
+        log::info!(
+            "Tool call {} decided by {}: {}",
+            entry.decision,
+            decided_by,
+            tool
+        );
+        if let Err(e) = record(&self.audit_log, &entry) {
+            log::warn!("Failed to write the audit log: {}", e);
+        }
+    }
+}
+
+// Asks at the terminal
+pub struct TerminalApprovals;
+
+#[async_trait]
+impl ApprovalHandler for TerminalApprovals {
+    // Prompt until the user approves or rejects the call, editing its arguments as often as they like
+    async fn ask(
+        &self,
+        tool_call: &ToolCall,
+        args: &JsonValue,
+        preview: Option<&str>,
+    ) -> Result<UserDecision, AppError> {
+        let tool = tool_call.function.name.as_str();
+        let mut arguments = args.clone();
+        let mut preview = preview.map(str::to_string);
+
+        loop {
+            match request_tool_call_approval(tool_call, &arguments, preview.as_deref()).await? {
+                ApprovalAnswer::Yes => {
+                    return Ok(UserDecision::Approve {
+                        arguments,
+                        always: false,
+                    })
+                }
+                ApprovalAnswer::Always => {
+                    return Ok(UserDecision::Approve {
+                        arguments,
+                        always: true,
+                    })
+                }
+                ApprovalAnswer::No(reason) => return Ok(UserDecision::Reject(reason)),
+                // A failed edit leaves the arguments as they were
+                // The preview is made again, so it matches the arguments as they stand
+                ApprovalAnswer::Edit => match edit_arguments(tool, &arguments) {
+                    Ok(edited) => {
+                        arguments = edited;
+                        preview = GLOBAL_TOOL_REGISTRY.preview(tool, &arguments).await;
+                    }
+                    Err(e) => print_colorful(&format!("{}\n", e), Color::Red)?,
+                },
+            }
+        }
+    }
+}
+
+// What the model is told when the user declines a call. A reason lets it change course without
+// waiting for the next user turn.
+fn rejection_message(reason: Option<String>) -> String {
+    match reason {
+        Some(reason) => format!(
+            "The user declined to run this tool call and said: {}",
+            reason
+        ),
+        None => "The user declined to run this tool call.".to_string(),
+    }
+}
+
+// Open the arguments in the user's editor and check the result against the tool's schema
+fn edit_arguments(tool: &str, args: &JsonValue) -> Result<JsonValue, AppError> {
+    let edited = edit_in_editor(&serde_json::to_string_pretty(args)?, "json")?;
+    let edited: JsonValue = serde_json::from_str(&edited).map_err(|e| {
+        AppError::CommandError(format!("The edited arguments aren't valid JSON: {}", e))
+    })?;
+    GLOBAL_TOOL_REGISTRY.validate_arguments(tool, edited)
+}
+
+// Tell the model how the user changed its arguments, one JSON pointer per line
+pub fn describe_edit(original: &JsonValue, edited: &JsonValue) -> String {
+    let mut changes = Vec::new();
+    collect_changes(original, edited, String::new(), &mut changes);
+    format!(
+        "The user edited the arguments before running this call:\n{}",
+        changes.join("\n")
+    )
+}
+
+fn collect_changes(
+    original: &JsonValue,
+    edited: &JsonValue,
+    pointer: String,
+    changes: &mut Vec<String>,
+) {
+    match (original, edited) {
+        _ if original == edited => {}
+        (JsonValue::Object(original), JsonValue::Object(edited)) => {
+            for (key, value) in original {
+                let child = child_pointer(&pointer, key);
+                match edited.get(key) {
+                    Some(edited_value) => collect_changes(value, edited_value, child, changes),
+                    None => changes.push(format!("- {}: removed (was {})", child, value)),
+                }
+            }
+            for (key, value) in edited {
+                if !original.contains_key(key) {
+                    changes.push(format!(
+                        "- {}: added {}",
+                        child_pointer(&pointer, key),
+                        value
+                    ));
+                }
+            }
+        }
+        (JsonValue::Array(original_items), JsonValue::Array(edited_items))
+            if original_items.len() == edited_items.len() =>
+        {
+            for (i, (original, edited)) in original_items.iter().zip(edited_items).enumerate() {
+                collect_changes(original, edited, format!("{}/{}", pointer, i), changes);
+            }
+        }
+        _ => {
+            let pointer = if pointer.is_empty() {
+                "(root)"
+            } else {
+                &pointer
+            };
+            changes.push(format!("- {}: {} -> {}", pointer, original, edited));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::FunctionCall;
+    use serde_json::json;
+
+    fn tool_call(name: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: "{}".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(ApprovalAnswer::parse("Y\n"), ApprovalAnswer::Yes);
+        assert_eq!(ApprovalAnswer::parse("always"), ApprovalAnswer::Always);
+        assert_eq!(ApprovalAnswer::parse("e"), ApprovalAnswer::Edit);
+        assert_eq!(ApprovalAnswer::parse(""), ApprovalAnswer::No(None));
+        assert_eq!(ApprovalAnswer::parse("sure"), ApprovalAnswer::No(None));
+        assert_eq!(
+            ApprovalAnswer::parse("N: use cargo check instead: it's faster\n"),
+            ApprovalAnswer::No(Some("use cargo check instead: it's faster".to_string()))
+        );
+        assert_eq!(ApprovalAnswer::parse("no:  "), ApprovalAnswer::No(None));
+        // Only a `n:` prefix carries a reason
+        assert_eq!(ApprovalAnswer::parse("y: sure"), ApprovalAnswer::No(None));
+    }
+
+    #[tokio::test]
+    async fn test_policy_and_yolo_decisions_are_audited() {
+        let audit_log =
+            std::env::temp_dir().join(format!("rtool-audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let policy = ApprovalPolicy::parse(
+            "[[rules]]\ntool = \"shell_tool\"\naction = \"deny\"\n\n[[rules]]\ntool = \"snap_tool\"\naction = \"allow\"",
+        )
+        .unwrap();
+        let mut approver = Approver {
+            audit_log: audit_log.clone(),
+            ..Approver::new(policy, true, None)
+        };
+
+        let args = json!({});
+        let verdicts = [
+            approver
+                .review("c1", &tool_call("snap_tool"), &args)
+                .await
+                .unwrap(),
+            approver
+                .review("c1", &tool_call("shell_tool"), &args)
+                .await
+                .unwrap(),
+            // Asked about by default, but yolo mode approves it
+            approver
+                .review("c1", &tool_call("file_tool"), &args)
+                .await
+                .unwrap(),
+        ];
+        assert_eq!(verdicts[0], Verdict::Approve);
+        assert!(matches!(&verdicts[1], Verdict::Reject(message) if message.contains("rule 1")));
+        assert_eq!(verdicts[2], Verdict::Approve);
+
+        let entries: Vec<JsonValue> = std::fs::read_to_string(&audit_log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let decisions: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry["decision"].as_str().unwrap(),
+                    entry["decided_by"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            decisions,
+            [
+                ("allow", "rule 2 (snap_tool allow)"),
+                ("deny", "rule 1 (shell_tool deny)"),
+                ("allow", "yolo")
+            ]
+        );
+        std::fs::remove_file(audit_log).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_is_rejected_without_a_user() {
+        let audit_log =
+            std::env::temp_dir().join(format!("rtool-audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut approver = Approver {
+            audit_log: audit_log.clone(),
+            ..Approver::new(ApprovalPolicy::default(), false, None)
+        };
+
+        let verdict = approver
+            .review("c1", &tool_call("shell_tool"), &json!({}))
+            .await
+            .unwrap();
+        assert!(matches!(&verdict, Verdict::Reject(message) if message.contains("no user to ask")));
+        std::fs::remove_file(audit_log).unwrap();
+    }
+
+    // Gives the same answer every time
+    struct FixedAnswer(UserDecision);
+
+    #[async_trait]
+    impl ApprovalHandler for FixedAnswer {
+        async fn ask(
+            &self,
+            _: &ToolCall,
+            _: &JsonValue,
+            _: Option<&str>,
+        ) -> Result<UserDecision, AppError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_decisions() {
+        let audit_log =
+            std::env::temp_dir().join(format!("rtool-audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let edited = json!({ "commands": [] });
+        let mut approver = Approver {
+            audit_log: audit_log.clone(),
+            ..Approver::new(
+                ApprovalPolicy::default(),
+                false,
+                Some(Arc::new(FixedAnswer(UserDecision::Approve {
+                    arguments: edited.clone(),
+                    always: true,
+                }))),
+            )
+        };
+
+        let args = json!({});
+        let first = approver
+            .review("c1", &tool_call("shell_tool"), &args)
+            .await
+            .unwrap();
+        assert_eq!(first, Verdict::ApproveEdited(edited));
+        // "Always" covers later calls without asking
+        let second = approver
+            .review("c1", &tool_call("shell_tool"), &args)
+            .await
+            .unwrap();
+        assert_eq!(second, Verdict::Approve);
+        std::fs::remove_file(audit_log).unwrap();
+    }
+
+    #[test]
+    fn test_describe_edit() {
+        let original = json!({ "commands": [{ "command": "ls", "args": ["-la"] }], "dry": true });
+        let edited =
+            json!({ "commands": [{ "command": "ls", "args": ["-l", "src"] }], "cwd": "/tmp" });
+        assert_eq!(
+            describe_edit(&original, &edited),
+            "The user edited the arguments before running this call:\n\
+             - /commands/0/args: [\"-la\"] -> [\"-l\",\"src\"]\n\
+             - /dry: removed (was true)\n\
+             - /cwd: added \"/tmp\""
+        );
+    }
+}
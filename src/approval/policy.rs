@@ -0,0 +1,493 @@
+use crate::models::types::AppError;
+
+use serde_derive::Deserialize;
+use serde_json::Value as JsonValue;
+use std::path::{Component, Path, PathBuf};
+
+// Read from the working directory when --approval-policy isn't given
+pub const DEFAULT_POLICY_FILE: &str = "approval.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+    Ask,
+}
+
+impl Action {
+    // Deny over ask over allow
+    fn strictness(&self) -> u8 {
+        match self {
+            Action::Allow => 0,
+            Action::Ask => 1,
+            Action::Deny => 2,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+            Action::Ask => "ask",
+        }
+    }
+}
+
+// One `[[rules]]` entry. A rule applies when the tool matches and every matcher it sets matches
+// the arguments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    // Tool name, or `*` for every tool
+    pub tool: String,
+    pub action: Action,
+    // Command-line prefixes, e.g. `cargo test` (shell_tool)
+    pub commands: Option<Vec<String>>,
+    // Directories the paths in the arguments must be under, e.g. `./src` (file_tool)
+    pub paths: Option<Vec<String>>,
+    // Whether the call only reads, e.g. file_tool's read and grep operations
+    pub read_only: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalPolicy {
+    // What happens to calls no rule matches
+    #[serde(default = "default_action")]
+    pub default: Action,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+fn default_action() -> Action {
+    Action::Ask
+}
+
+impl Default for ApprovalPolicy {
+    // Ask about every call
+    fn default() -> Self {
+        Self {
+            default: default_action(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl ApprovalPolicy {
+    pub fn parse(toml: &str) -> Result<Self, AppError> {
+        toml::from_str(toml)
+            .map_err(|e| AppError::CommandError(format!("Invalid approval policy: {}", e)))
+    }
+
+    // An explicitly given file must exist; the default file is optional
+    pub fn load(path: Option<&str>) -> Result<Self, AppError> {
+        match path {
+            Some(path) => Self::parse(&std::fs::read_to_string(path)?),
+            None if Path::new(DEFAULT_POLICY_FILE).exists() => {
+                Self::parse(&std::fs::read_to_string(DEFAULT_POLICY_FILE)?)
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    // The action for a call and what decided it. The first matching rule wins. Calls that run
+    // other tools (pipeline_tool steps) get the strictest action of the call and its steps.
+    // `is_read_only` says whether a tool call only reads.
+    pub fn evaluate(
+        &self,
+        tool: &str,
+        args: &JsonValue,
+        cwd: &Path,
+        is_read_only: &dyn Fn(&str, &JsonValue) -> bool,
+    ) -> (Action, String) {
+        self.evaluate_nested(tool, args, cwd, is_read_only, false)
+    }
+
+    // `in_step` is set for pipeline steps, whose arguments may hold `${stepId}` placeholders
+    fn evaluate_nested(
+        &self,
+        tool: &str,
+        args: &JsonValue,
+        cwd: &Path,
+        is_read_only: &dyn Fn(&str, &JsonValue) -> bool,
+        in_step: bool,
+    ) -> (Action, String) {
+        let mut decision = self.evaluate_call(tool, args, cwd, is_read_only(tool, args), in_step);
+        for (step_tool, step_args) in nested_calls(args) {
+            let step_decision =
+                self.evaluate_nested(&step_tool, &step_args, cwd, is_read_only, true);
+            if step_decision.0.strictness() > decision.0.strictness() {
+                decision = step_decision;
+            }
+        }
+        decision
+    }
+
+    fn evaluate_call(
+        &self,
+        tool: &str,
+        args: &JsonValue,
+        cwd: &Path,
+        read_only: bool,
+        in_step: bool,
+    ) -> (Action, String) {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.matches(tool, args, cwd, read_only, in_step) {
+                return (
+                    rule.action,
+                    format!("rule {} ({} {})", i + 1, rule.tool, rule.action.as_str()),
+                );
+            }
+        }
+        (self.default, "default policy".to_string())
+    }
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        tool: &str,
+        args: &JsonValue,
+        cwd: &Path,
+        read_only: bool,
+        in_step: bool,
+    ) -> bool {
+        if self.tool != "*" && self.tool != tool {
+            return false;
+        }
+        if self.read_only.is_some_and(|wanted| wanted != read_only) {
+            return false;
+        }
+        // A deny rule catches a call if any part of it matches; allow and ask rules have to cover
+        // all of it. A pipeline step's unresolved placeholders could stand for anything, so they
+        // match every deny matcher and no other.
+        let any = self.action == Action::Deny;
+        let is_placeholder = |value: &str| in_step && is_placeholder(value);
+        let unresolved = in_step && has_placeholder(args);
+
+        if let Some(prefixes) = &self.commands {
+            let command_lines = command_lines(args);
+            if !matches_values(&command_lines, any, unresolved, |line| {
+                if is_placeholder(line) {
+                    return any;
+                }
+                prefixes
+                    .iter()
+                    .any(|prefix| command_has_prefix(line, prefix))
+            }) {
+                return false;
+            }
+        }
+
+        if let Some(dirs) = &self.paths {
+            let dirs: Vec<PathBuf> = dirs.iter().map(|dir| normalize(cwd, dir)).collect();
+            let paths = argument_paths(args);
+            if !matches_values(&paths, any, unresolved, |path| {
+                if is_placeholder(path) {
+                    return any;
+                }
+                let path = normalize(cwd, path);
+                dirs.iter().any(|dir| path.starts_with(dir))
+            }) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+// A matcher never applies to a call it finds nothing to check in, unless it's a deny matcher and
+// a placeholder may hide what it would check, e.g. `"commands": "${listing}"`
+fn matches_values<T>(
+    values: &[T],
+    any: bool,
+    unresolved: bool,
+    predicate: impl Fn(&T) -> bool,
+) -> bool {
+    if values.is_empty() {
+        return any && unresolved;
+    }
+    if any {
+        values.iter().any(predicate)
+    } else {
+        values.iter().all(predicate)
+    }
+}
+
+// The `{tool, parameters}` steps of a pipeline_tool call. Step parameters aren't checked against
+// a schema until the step runs, so parse them here if the model sent them as a string.
+fn nested_calls(args: &JsonValue) -> Vec<(String, JsonValue)> {
+    args.get("steps")
+        .and_then(JsonValue::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|step| {
+            let tool = step.get("tool")?.as_str()?.to_string();
+            let parameters = match step.get("parameters")? {
+                JsonValue::String(s) => {
+                    serde_json::from_str(s).unwrap_or(JsonValue::String(s.clone()))
+                }
+                parameters => parameters.clone(),
+            };
+            Some((tool, parameters))
+        })
+        .collect()
+}
+
+// pipeline_tool fills in `${stepId}` placeholders only when the step runs
+fn is_placeholder(value: &str) -> bool {
+    value.contains("${")
+}
+
+fn has_placeholder(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::String(s) => is_placeholder(s),
+        JsonValue::Array(values) => values.iter().any(has_placeholder),
+        JsonValue::Object(map) => map.values().any(has_placeholder),
+        _ => false,
+    }
+}
+
+// Each `{command, args}` entry of a shell_tool call as a single command line
+fn command_lines(args: &JsonValue) -> Vec<String> {
+    args.get("commands")
+        .and_then(JsonValue::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|command| {
+            let mut line = command.get("command")?.as_str()?.to_string();
+            for arg in command
+                .get("args")
+                .and_then(JsonValue::as_array)
+                .into_iter()
+                .flatten()
+            {
+                line.push(' ');
+                line.push_str(arg.as_str().unwrap_or_default());
+            }
+            Some(line)
+        })
+        .collect()
+}
+
+// `cargo test` covers `cargo test --all` but not `cargo testing`
+fn command_has_prefix(line: &str, prefix: &str) -> bool {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let prefix: Vec<&str> = prefix.split_whitespace().collect();
+    !prefix.is_empty() && words.starts_with(&prefix)
+}
+
+// Every string under a `*path` key, at any depth
+fn argument_paths(args: &JsonValue) -> Vec<String> {
+    let mut paths = Vec::new();
+    collect_paths(args, &mut paths);
+    paths
+}
+
+fn collect_paths(value: &JsonValue, paths: &mut Vec<String>) {
+    match value {
+        JsonValue::Object(map) => {
+            for (key, value) in map {
+                match value {
+                    JsonValue::String(path) if key.ends_with("path") => paths.push(path.clone()),
+                    _ => collect_paths(value, paths),
+                }
+            }
+        }
+        JsonValue::Array(values) => values.iter().for_each(|value| collect_paths(value, paths)),
+        _ => {}
+    }
+}
+
+// Resolve `.` and `..` without touching the filesystem, so paths that don't exist yet compare too
+fn normalize(cwd: &Path, path: &str) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const POLICY: &str = r#"
+        default = "ask"
+
+        [[rules]]
+        tool = "shell_tool"
+        action = "deny"
+        commands = ["rm"]
+
+        [[rules]]
+        tool = "shell_tool"
+        action = "allow"
+        commands = ["ls", "cat", "cargo test"]
+
+        [[rules]]
+        tool = "file_tool"
+        action = "allow"
+        paths = ["./src"]
+
+        [[rules]]
+        tool = "snap_tool"
+        action = "allow"
+    "#;
+
+    fn shell(commands: &[(&str, &[&str])]) -> JsonValue {
+        let commands: Vec<JsonValue> = commands
+            .iter()
+            .map(|(command, args)| json!({ "command": command, "args": args }))
+            .collect();
+        json!({ "commands": commands })
+    }
+
+    fn action(tool: &str, args: JsonValue) -> Action {
+        let policy = ApprovalPolicy::parse(POLICY).unwrap();
+        policy
+            .evaluate(tool, &args, Path::new("/work"), &|_, _| false)
+            .0
+    }
+
+    #[test]
+    fn test_command_prefixes() {
+        assert_eq!(
+            action("shell_tool", shell(&[("ls", &["-la"])])),
+            Action::Allow
+        );
+        assert_eq!(
+            action("shell_tool", shell(&[("cargo", &["test", "--all"])])),
+            Action::Allow
+        );
+        assert_eq!(
+            action("shell_tool", shell(&[("cargo", &["testing"])])),
+            Action::Ask
+        );
+        // Every command has to be allowed, but one denied command denies the call
+        assert_eq!(
+            action("shell_tool", shell(&[("ls", &[]), ("git", &["push"])])),
+            Action::Ask
+        );
+        assert_eq!(
+            action("shell_tool", shell(&[("ls", &[]), ("rm", &["-rf", "x"])])),
+            Action::Deny
+        );
+    }
+
+    #[test]
+    fn test_paths_stay_under_directory() {
+        let file_call =
+            |path: &str| json!({ "operations": [{ "op": "create", "file_path": path }] });
+        assert_eq!(action("file_tool", file_call("src/main.rs")), Action::Allow);
+        assert_eq!(
+            action("file_tool", file_call("/work/src/a/b.rs")),
+            Action::Allow
+        );
+        assert_eq!(
+            action("file_tool", file_call("src/../Cargo.toml")),
+            Action::Ask
+        );
+        assert_eq!(action("file_tool", file_call("srcs/x.rs")), Action::Ask);
+        assert_eq!(action("snap_tool", json!({})), Action::Allow);
+        assert_eq!(action("gpt_tool", json!({})), Action::Ask);
+    }
+
+    #[test]
+    fn test_pipeline_steps_are_checked() {
+        let pipeline = |tool: &str, parameters: JsonValue| {
+            json!({ "steps": [
+                { "id": "a", "tool": "snap_tool", "parameters": {} },
+                { "id": "b", "tool": tool, "parameters": parameters }
+            ] })
+        };
+        let policy = ApprovalPolicy::parse(&format!(
+            "{}\n[[rules]]\ntool = \"pipeline_tool\"\naction = \"allow\"",
+            POLICY
+        ))
+        .unwrap();
+        let action = |args: JsonValue| {
+            policy.evaluate("pipeline_tool", &args, Path::new("/work"), &|_, _| false)
+        };
+
+        assert_eq!(action(pipeline("snap_tool", json!({}))).0, Action::Allow);
+        assert_eq!(action(pipeline("gpt_tool", json!({}))).0, Action::Ask);
+        let stringified = JsonValue::String(shell(&[("rm", &["x"])]).to_string());
+        let (denied, rule) = action(pipeline("shell_tool", stringified));
+        assert_eq!(
+            (denied, rule.as_str()),
+            (Action::Deny, "rule 1 (shell_tool deny)")
+        );
+
+        // Placeholders are only filled in when the step runs, so they could be anything
+        let (denied, rule) = action(pipeline("shell_tool", shell(&[("${a}", &[])])));
+        assert_eq!(
+            (denied, rule.as_str()),
+            (Action::Deny, "rule 1 (shell_tool deny)")
+        );
+        let hidden = json!({ "commands": "${a}" });
+        assert_eq!(action(pipeline("shell_tool", hidden)).0, Action::Deny);
+        let file = |path: &str| json!({ "operations": [{ "op": "delete", "file_path": path }] });
+        let step = |path: &str| {
+            policy
+                .evaluate(
+                    "pipeline_tool",
+                    &pipeline("file_tool", file(path)),
+                    Path::new("/work"),
+                    &|_, _| false,
+                )
+                .0
+        };
+        assert_eq!(step("src/lib.rs"), Action::Allow);
+        assert_eq!(step("src/${a}"), Action::Ask);
+        // Outside a pipeline, `${...}` is just text
+        assert_eq!(
+            self::action("shell_tool", shell(&[("ls", &["${HOME}"])])),
+            Action::Allow
+        );
+    }
+
+    #[test]
+    fn test_read_only_rules() {
+        let policy =
+            ApprovalPolicy::parse("[[rules]]\ntool = \"*\"\naction = \"allow\"\nread_only = true")
+                .unwrap();
+        let reads = |tool: &str, _: &JsonValue| tool == "file_tool";
+        let action = |tool: &str| {
+            policy
+                .evaluate(tool, &json!({}), Path::new("/work"), &reads)
+                .0
+        };
+        assert_eq!(action("file_tool"), Action::Allow);
+        assert_eq!(action("shell_tool"), Action::Ask);
+
+        // A pipeline with a step that writes isn't covered
+        let pipeline = json!({ "steps": [{ "id": "a", "tool": "shell_tool", "parameters": {} }] });
+        let reads = |tool: &str, _: &JsonValue| tool != "shell_tool";
+        assert_eq!(
+            policy
+                .evaluate("pipeline_tool", &pipeline, Path::new("/work"), &reads)
+                .0,
+            Action::Ask
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_keys() {
+        assert!(ApprovalPolicy::parse(
+            "[[rules]]\ntool = \"x\"\naction = \"allow\"\ncommand = [\"ls\"]"
+        )
+        .is_err());
+        assert!(ApprovalPolicy::parse("default = \"maybe\"").is_err());
+        assert_eq!(ApprovalPolicy::parse("").unwrap().default, Action::Ask);
+    }
+}
@@ -2,26 +2,147 @@ use std::io;
 
 use crossterm::style::Color;
 
-use crate::{models::types::AppError, utils::common::print_colorful};
+use crate::{models::types::AppError, storage::search::SearchQuery, utils::common::print_colorful};
 
-use super::{conversation_manager::ConversationManager, GLOBAL_TOOL_REGISTRY};
+use super::{
+    conversation_browser, conversation_exporter::ExportFormat,
+    conversation_manager::ConversationManager, file_changes, GLOBAL_TOOL_REGISTRY,
+};
+
+const HELP: &str = "Commands:
+  /list                 list saved conversations
+  /show <id>            print a saved conversation
+  /search <words> [role:<role>] [tool:<name>] [since:<date>] [until:<date>] [limit:<n>]
+                        search past conversations; dates are YYYY-MM-DD
+  /load <id>            continue a saved conversation
+  /delete <id>          delete a saved conversation
+  /rename <title>       rename the current conversation
+  /fork [index]         continue in a new branch cut after message <index> (default: the last)
+  /retry                answer the last prompt again, keeping the previous reply in its own branch
+  /tree                 show the branches of the current conversation
+  /undo [n] [--force]   revert the file changes of the last tool call, or the last <n>;
+                        --force also reverts files changed since
+  /changes              list the files this conversation's tool calls changed, with diffs
+  /export [format] [path]
+                        export the current conversation as md (default), html or jsonl
+  /tools                list available tools
+  /help                 show this help
+  /exit                 quit
+Conversation ids may be abbreviated to any unique fragment.
+";
 
 pub enum Command {
     Exit,
+    Help,
     ListTools,
     LoadConversation(String),
+    ListConversations,
+    ShowConversation(String),
+    DeleteConversation(String),
+    RenameConversation(String),
+    Fork(Option<usize>),
+    Retry,
+    ShowTree,
+    // How many tool calls, and whether to revert files changed since
+    Undo(usize, bool),
+    ShowChanges,
+    Search(SearchQuery),
+    Export(ExportFormat, Option<String>),
     Prompt(String),
 }
 
 pub enum CommandHandler {}
 
 impl CommandHandler {
-    pub fn read_user_command(tokens: u32) -> Result<Command, AppError> {
-        print_colorful(&format!("[{}] User: ", tokens), Color::Yellow)?;
+    fn required_argument(command: &str, argument: &str) -> Result<String, AppError> {
+        if argument.is_empty() {
+            Err(AppError::CommandError(format!(
+                "`/{}` requires an argument",
+                command
+            )))
+        } else {
+            Ok(argument.to_string())
+        }
+    }
 
-        let mut user_input = String::new();
-        io::stdin().read_line(&mut user_input)?;
-        let user_input = user_input.trim();
+    fn optional_index(argument: &str) -> Result<Option<usize>, AppError> {
+        if argument.is_empty() {
+            return Ok(None);
+        }
+        argument
+            .parse()
+            .map(Some)
+            .map_err(|_| AppError::CommandError(format!("`{}` is not a message index", argument)))
+    }
+
+    fn undo_arguments(argument: &str) -> Result<Command, AppError> {
+        let mut calls = 1;
+        let mut force = false;
+        for word in argument.split_whitespace() {
+            match word {
+                "--force" => force = true,
+                word => match word.parse() {
+                    Ok(count) if count > 0 => calls = count,
+                    _ => {
+                        return Err(AppError::CommandError(format!(
+                            "`{}` is not a number of tool calls",
+                            word
+                        )))
+                    }
+                },
+            }
+        }
+        Ok(Command::Undo(calls, force))
+    }
+
+    // Commands start with `/`; the original bare `exit`, `quit`, `list tools` and `load <id>`
+    // forms are still accepted
+    pub fn parse_command(user_input: &str) -> Result<Command, AppError> {
+        if let Some(slash_command) = user_input.strip_prefix('/') {
+            let (name, argument) = match slash_command.split_once(' ') {
+                Some((name, argument)) => (name, argument.trim()),
+                None => (slash_command, ""),
+            };
+
+            return match name.to_lowercase().as_str() {
+                "exit" | "quit" => Ok(Command::Exit),
+                "help" => Ok(Command::Help),
+                "tools" => Ok(Command::ListTools),
+                "list" => Ok(Command::ListConversations),
+                "load" => Ok(Command::LoadConversation(Self::required_argument(
+                    name, argument,
+                )?)),
+                "show" => Ok(Command::ShowConversation(Self::required_argument(
+                    name, argument,
+                )?)),
+                "delete" => Ok(Command::DeleteConversation(Self::required_argument(
+                    name, argument,
+                )?)),
+                "rename" => Ok(Command::RenameConversation(Self::required_argument(
+                    name, argument,
+                )?)),
+                "fork" => Ok(Command::Fork(Self::optional_index(argument)?)),
+                "retry" => Ok(Command::Retry),
+                "tree" => Ok(Command::ShowTree),
+                "undo" => Self::undo_arguments(argument),
+                "changes" => Ok(Command::ShowChanges),
+                "search" => Ok(Command::Search(SearchQuery::parse(
+                    &Self::required_argument(name, argument)?,
+                )?)),
+                "export" => {
+                    let mut parts = argument.split_whitespace();
+                    let format = match parts.next() {
+                        Some(format) => format.parse()?,
+                        None => ExportFormat::Markdown,
+                    };
+                    Ok(Command::Export(format, parts.next().map(str::to_string)))
+                }
+                _ => Err(AppError::CommandError(format!(
+                    "Unknown command `/{}` (try /help)",
+                    name
+                ))),
+            };
+        }
 
         if user_input.eq_ignore_ascii_case("exit") || user_input.eq_ignore_ascii_case("quit") {
             Ok(Command::Exit)
@@ -39,30 +160,120 @@ impl CommandHandler {
         }
     }
 
+    pub fn read_user_command(tokens: u32) -> Result<Command, AppError> {
+        print_colorful(&format!("[{}] User: ", tokens), Color::Yellow)?;
+
+        let mut user_input = String::new();
+        io::stdin().read_line(&mut user_input)?;
+
+        Self::parse_command(user_input.trim())
+    }
+
+    fn execute_command(
+        command: Command,
+        conversation_manager: &mut ConversationManager,
+    ) -> Result<bool, AppError> {
+        match command {
+            Command::Exit => {
+                std::process::exit(0);
+            }
+            Command::Help => print_colorful(HELP, Color::DarkGreen)?,
+            Command::ListTools => {
+                print_colorful(&GLOBAL_TOOL_REGISTRY.list_tools(), Color::DarkGreen)?
+            }
+            Command::LoadConversation(conversation_id) => {
+                conversation_manager.load_conversation(conversation_id)?;
+            }
+            Command::ListConversations => conversation_browser::print_conversation_list()?,
+            Command::ShowConversation(query) => conversation_browser::print_conversation(&query)?,
+            Command::DeleteConversation(query) => {
+                if conversation_manager
+                    .conversation_id()
+                    .contains(query.trim())
+                {
+                    return Err(AppError::CommandError(
+                        "Refusing to delete the conversation that is currently open".to_string(),
+                    ));
+                }
+                conversation_browser::delete(&query)?;
+            }
+            Command::RenameConversation(title) => {
+                conversation_manager.rename(title.clone())?;
+                print_colorful(
+                    &format!("Renamed this conversation to `{}`\n", title),
+                    Color::DarkMagenta,
+                )?;
+            }
+            Command::Fork(at) => {
+                let parent_id = conversation_manager.fork(at)?;
+                print_colorful(
+                    &format!(
+                        "Forked {} into {}; now continuing in the new branch\n",
+                        parent_id,
+                        conversation_manager.conversation_id()
+                    ),
+                    Color::DarkMagenta,
+                )?;
+            }
+            Command::Retry => {
+                let last_user =
+                    conversation_manager
+                        .last_user_message_index()
+                        .ok_or_else(|| {
+                            AppError::CommandError("There is no prompt to retry".to_string())
+                        })?;
+                // Branch only if there is a reply to keep; otherwise just ask again
+                if last_user + 1 < conversation_manager.messages.len() {
+                    let parent_id = conversation_manager.fork(Some(last_user))?;
+                    print_colorful(
+                        &format!("Previous reply kept in conversation {}\n", parent_id),
+                        Color::DarkMagenta,
+                    )?;
+                }
+                return Ok(true);
+            }
+            Command::ShowTree => {
+                let conversation_id = conversation_manager.conversation_id();
+                conversation_browser::print_conversation_tree(
+                    conversation_id,
+                    Some(conversation_id),
+                )?;
+            }
+            Command::Undo(calls, force) => {
+                file_changes::undo(conversation_manager.conversation_id(), calls, force)?
+            }
+            Command::ShowChanges => {
+                file_changes::print_changes(conversation_manager.conversation_id())?
+            }
+            Command::Search(query) => conversation_browser::print_search_results(&query)?,
+            Command::Export(format, output) => conversation_browser::export(
+                conversation_manager.conversation_id(),
+                format,
+                output.as_deref(),
+            )?,
+            Command::Prompt(prompt) => {
+                conversation_manager.add_user_prompt(prompt)?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
     pub fn get_user_prompt(
         conversation_manager: &mut ConversationManager,
         tokens: u32,
     ) -> Result<(), AppError> {
         let mut got_prompt = false;
         while !got_prompt {
-            match CommandHandler::read_user_command(tokens) {
-                Ok(command) => match command {
-                    Command::Exit => {
-                        std::process::exit(0);
-                    }
-                    Command::ListTools => {
-                        print_colorful(&GLOBAL_TOOL_REGISTRY.list_tools(), Color::DarkGreen)?
-                    }
-                    Command::LoadConversation(conversation_id) => {
-                        conversation_manager.load_conversation(conversation_id)?;
-                    }
-                    Command::Prompt(prompt) => {
-                        got_prompt = true;
-                        conversation_manager.add_user_prompt(prompt)?;
-                    }
-                },
+            let result = CommandHandler::read_user_command(tokens)
+                .and_then(|command| Self::execute_command(command, conversation_manager));
+
+            match result {
+                Ok(is_prompt) => got_prompt = is_prompt,
                 Err(e) => {
-                    log::warn!("Failed to parse user command: {}", e);
+                    log::warn!("Failed to handle user command: {}", e);
+                    print_colorful(&format!("{}\n", e), Color::Red)?;
                 }
             }
         }
@@ -70,3 +281,69 @@ impl CommandHandler {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_slash_commands() {
+        assert!(matches!(
+            CommandHandler::parse_command("/list"),
+            Ok(Command::ListConversations)
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("/show 2024abc"),
+            Ok(Command::ShowConversation(id)) if id == "2024abc"
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("/rename Fix the parser"),
+            Ok(Command::RenameConversation(title)) if title == "Fix the parser"
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("/fork 4"),
+            Ok(Command::Fork(Some(4)))
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("/fork"),
+            Ok(Command::Fork(None))
+        ));
+        assert!(CommandHandler::parse_command("/fork last").is_err());
+        assert!(matches!(
+            CommandHandler::parse_command("/undo"),
+            Ok(Command::Undo(1, false))
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("/undo 3 --force"),
+            Ok(Command::Undo(3, true))
+        ));
+        assert!(CommandHandler::parse_command("/undo 0").is_err());
+        assert!(matches!(
+            CommandHandler::parse_command("/changes"),
+            Ok(Command::ShowChanges)
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("/export html out/chat.html"),
+            Ok(Command::Export(ExportFormat::Html, Some(path))) if path == "out/chat.html"
+        ));
+        assert!(CommandHandler::parse_command("/export pdf").is_err());
+        assert!(CommandHandler::parse_command("/delete").is_err());
+        assert!(CommandHandler::parse_command("/bogus").is_err());
+    }
+
+    #[test]
+    fn test_parse_legacy_commands_and_prompts() {
+        assert!(matches!(
+            CommandHandler::parse_command("quit"),
+            Ok(Command::Exit)
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("list tools"),
+            Ok(Command::ListTools)
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("list the files in src"),
+            Ok(Command::Prompt(_))
+        ));
+    }
+}
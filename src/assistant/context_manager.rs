@@ -0,0 +1,332 @@
+use crate::{
+    api::provider::LlmProvider,
+    models::types::{AppError, Message},
+    utils::common::print_colorful,
+};
+
+use crossterm::style::Color;
+use serde_json::Value as JsonValue;
+use std::ops::Range;
+
+// Rough characters-per-token ratio for English text and JSON; good enough to stay under a limit
+const CHARS_PER_TOKEN: usize = 4;
+// Per-message overhead for the role and framing tokens the API adds
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+// Tool output longer than this is clipped when building the transcript to summarize
+const MAX_TRANSCRIPT_ENTRY_CHARS: usize = 2000;
+
+const SUMMARY_PREFIX: &str = "Summary of the earlier part of this conversation:\n";
+const SUMMARY_INSTRUCTIONS: &str = "You compress conversation history. Summarize the transcript below between a user and an AI assistant that can call tools. Keep the user's goals, decisions made, file paths, commands that were run with their important results, and any unfinished tasks. Be concise and use bullet points.";
+
+pub fn estimate_text_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+// Estimate how many prompt tokens a message will cost, including its tool calls
+pub fn estimate_tokens(message: &Message) -> usize {
+    let mut tokens = MESSAGE_OVERHEAD_TOKENS;
+    if let Some(content) = &message.content {
+        tokens += estimate_text_tokens(content);
+    }
+    for tool_call in message.tool_calls.iter().flatten() {
+        tokens += MESSAGE_OVERHEAD_TOKENS
+            + estimate_text_tokens(&tool_call.function.name)
+            + estimate_text_tokens(&tool_call.function.arguments);
+    }
+    tokens
+}
+
+// Split messages into units that must be kept or dropped together: an assistant message with
+// `tool_calls` and the `tool` replies that follow it form a single unit
+fn group_units(messages: &[Message]) -> Vec<Range<usize>> {
+    let mut units = Vec::new();
+    let mut start = 0;
+
+    while start < messages.len() {
+        let mut end = start + 1;
+        if messages[start].tool_calls.is_some() {
+            while end < messages.len() && messages[end].role == "tool" {
+                end += 1;
+            }
+        }
+        units.push(start..end);
+        start = end;
+    }
+
+    units
+}
+
+// A compacted copy of the prompt. Its last `kept` messages are the prompt's last messages, unchanged.
+#[derive(Debug)]
+pub struct Compacted {
+    pub messages: Vec<Message>,
+    pub kept: usize,
+}
+
+// Which messages to fold into a summary; everything from `keep_from` on stays verbatim
+#[derive(Debug, PartialEq)]
+struct CompactionPlan {
+    compact: Range<usize>,
+    keep_from: usize,
+}
+
+// Keep the leading system prompt and as many recent units as fit in `target` tokens
+fn plan_compaction(messages: &[Message], reserved: usize, target: usize) -> Option<CompactionPlan> {
+    let pinned = match messages.first() {
+        Some(first) if first.role == "system" => 1,
+        _ => 0,
+    };
+    let pinned_tokens: usize = messages[..pinned].iter().map(estimate_tokens).sum();
+
+    let units = group_units(&messages[pinned..]);
+    let mut budget_used = reserved + pinned_tokens;
+    let mut keep_from = messages.len();
+
+    for (i, unit) in units.iter().enumerate().rev() {
+        let unit_tokens: usize = messages[pinned + unit.start..pinned + unit.end]
+            .iter()
+            .map(estimate_tokens)
+            .sum();
+        // The latest unit is always kept, whatever it costs
+        if i + 1 != units.len() && budget_used + unit_tokens > target {
+            break;
+        }
+        budget_used += unit_tokens;
+        keep_from = pinned + unit.start;
+    }
+
+    if keep_from <= pinned {
+        return None;
+    }
+
+    Some(CompactionPlan {
+        compact: pinned..keep_from,
+        keep_from,
+    })
+}
+
+fn clip(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        let clipped: String = text.chars().take(max_chars).collect();
+        format!("{}... [truncated]", clipped)
+    }
+}
+
+fn render_transcript(messages: &[Message]) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        if let Some(content) = &message.content {
+            let speaker = match (&message.role[..], &message.name) {
+                ("tool", Some(name)) => format!("tool result ({})", name),
+                (role, _) => role.to_string(),
+            };
+            transcript.push_str(&format!(
+                "{}: {}\n",
+                speaker,
+                clip(content, MAX_TRANSCRIPT_ENTRY_CHARS)
+            ));
+        }
+        for tool_call in message.tool_calls.iter().flatten() {
+            transcript.push_str(&format!(
+                "assistant called {} with {}\n",
+                tool_call.function.name,
+                clip(&tool_call.function.arguments, MAX_TRANSCRIPT_ENTRY_CHARS)
+            ));
+        }
+    }
+    transcript
+}
+
+// Keeps the conversation under the model's context window by summarizing (or, if that fails,
+// dropping) the oldest turns once the estimated prompt size nears the configured limit
+pub struct ContextManager {
+    limit: usize,
+}
+
+impl ContextManager {
+    // Start compacting at 80% of the limit and shrink the history down to half of it
+    const TRIGGER_RATIO: f64 = 0.8;
+    const TARGET_RATIO: f64 = 0.5;
+
+    pub fn new(limit: usize) -> Self {
+        Self { limit }
+    }
+
+    pub fn estimate_prompt(&self, messages: &[Message], tools: Option<&JsonValue>) -> usize {
+        let tools_tokens = tools.map_or(0, |tools| estimate_text_tokens(&tools.to_string()));
+        tools_tokens + messages.iter().map(estimate_tokens).sum::<usize>()
+    }
+
+    // Return a compacted copy of `messages` if they are near the limit (or `force` is set)
+    pub async fn compact(
+        &self,
+        messages: &[Message],
+        tools: Option<&JsonValue>,
+        provider: &dyn LlmProvider,
+        force: bool,
+    ) -> Result<Option<Compacted>, AppError> {
+        let estimate = self.estimate_prompt(messages, tools);
+        if !force && (estimate as f64) < self.limit as f64 * Self::TRIGGER_RATIO {
+            return Ok(None);
+        }
+
+        let reserved = tools.map_or(0, |tools| estimate_text_tokens(&tools.to_string()));
+        let target = (self.limit as f64 * Self::TARGET_RATIO) as usize;
+        let plan = match plan_compaction(messages, reserved, target) {
+            Some(plan) => plan,
+            None => {
+                log::warn!(
+                    "Conversation is ~{} tokens but there are no older turns left to compact",
+                    estimate
+                );
+                return Ok(None);
+            }
+        };
+
+        let older = &messages[plan.compact.clone()];
+        let replacement = match self.summarize(older, provider).await {
+            Ok(summary) => Message::new(
+                "system".to_string(),
+                format!("{}{}", SUMMARY_PREFIX, summary),
+            ),
+            Err(e) => {
+                log::warn!("Summarization failed, truncating instead: {}", e);
+                Message::new(
+                    "system".to_string(),
+                    format!(
+                        "{} earlier messages were removed to fit the context window.",
+                        older.len()
+                    ),
+                )
+            }
+        };
+
+        let mut compacted = messages[..plan.compact.start].to_vec();
+        compacted.push(replacement);
+        compacted.extend_from_slice(&messages[plan.keep_from..]);
+
+        let notice = format!(
+            "[context] ~{} tokens is near the {} token limit; compacted {} older messages (now ~{} tokens)\n",
+            estimate,
+            self.limit,
+            older.len(),
+            self.estimate_prompt(&compacted, tools)
+        );
+        log::info!("{}", notice.trim_end());
+        print_colorful(&notice, Color::DarkGrey)?;
+
+        Ok(Some(Compacted {
+            messages: compacted,
+            kept: messages.len() - plan.keep_from,
+        }))
+    }
+
+    async fn summarize(
+        &self,
+        messages: &[Message],
+        provider: &dyn LlmProvider,
+    ) -> Result<String, AppError> {
+        // Make sure the summarization request itself fits comfortably in the window
+        let max_chars = self.limit * CHARS_PER_TOKEN / 2;
+        let transcript = clip(&render_transcript(messages), max_chars);
+
+        let request = vec![
+            Message::new("system".to_string(), SUMMARY_INSTRUCTIONS.to_string()),
+            Message::new("user".to_string(), transcript),
+        ];
+        let response = provider.complete(&request, None).await?;
+
+        response.choices[0]
+            .message
+            .content
+            .clone()
+            .filter(|summary| !summary.trim().is_empty())
+            .ok_or_else(|| AppError::CommandError("Model returned an empty summary".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::{FunctionCall, ToolCall};
+
+    fn text(role: &str, chars: usize) -> Message {
+        Message::new(role.to_string(), "x".repeat(chars))
+    }
+
+    fn tool_request(id: &str) -> Message {
+        Message {
+            role: "assistant".to_string(),
+            content: None,
+            tool_calls: Some(vec![ToolCall {
+                id: id.to_string(),
+                r#type: "function".to_string(),
+                function: FunctionCall {
+                    name: "shell_tool".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            }]),
+            tool_call_id: None,
+            name: None,
+        }
+    }
+
+    fn tool_reply(id: &str, chars: usize) -> Message {
+        Message {
+            role: "tool".to_string(),
+            content: Some("x".repeat(chars)),
+            tool_calls: None,
+            tool_call_id: Some(id.to_string()),
+            name: Some("shell_tool".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(&text("user", 40)), 14);
+        assert_eq!(estimate_tokens(&tool_request("a")), 4 + 4 + 3 + 1);
+    }
+
+    #[test]
+    fn test_group_units_keeps_tool_replies_with_their_call() {
+        let messages = vec![
+            text("system", 10),
+            text("user", 10),
+            tool_request("a"),
+            tool_reply("a", 10),
+            tool_reply("a", 10),
+            text("assistant", 10),
+        ];
+        assert_eq!(group_units(&messages), vec![0..1, 1..2, 2..5, 5..6]);
+    }
+
+    #[test]
+    fn test_plan_never_splits_tool_call_from_replies() {
+        let messages = vec![
+            text("system", 40),
+            text("user", 400),
+            tool_request("a"),
+            tool_reply("a", 400),
+            tool_reply("a", 40),
+            text("user", 40),
+        ];
+        // Room for the system prompt, the last user message and the small tool reply, but the
+        // tool call unit as a whole does not fit, so it is compacted in one piece
+        let plan = plan_compaction(&messages, 0, 50).unwrap();
+        assert_eq!(
+            plan,
+            CompactionPlan {
+                compact: 1..5,
+                keep_from: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_plan_returns_none_when_nothing_to_compact() {
+        let messages = vec![text("system", 4000), text("user", 4000)];
+        assert_eq!(plan_compaction(&messages, 0, 10), None);
+    }
+}
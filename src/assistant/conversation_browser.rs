@@ -0,0 +1,333 @@
+use crate::{
+    assistant::conversation_manager::{
+        delete_conversation, fork_conversation, list_conversations, read_conversation,
+        rename_conversation, resolve_conversation_id,
+    },
+    assistant::{
+        conversation_exporter::{export_conversation, ExportFormat},
+        GLOBAL_TOOL_REGISTRY,
+    },
+    models::types::{AppError, ConversationMetadata, Message},
+    storage::{search::SearchQuery, store},
+    utils::common::print_colorful,
+};
+
+use crossterm::style::Color;
+use std::collections::HashMap;
+
+// Tool output in `show` is clipped to keep transcripts skimmable
+const SHOW_TOOL_RESULT_CHARS: usize = 500;
+
+fn short_timestamp(rfc3339: &str) -> String {
+    chrono::DateTime::parse_from_rfc3339(rfc3339)
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|_| rfc3339.to_string())
+}
+
+fn format_conversation_row(metadata: &ConversationMetadata) -> String {
+    format!(
+        "{}  {}  {:<20}  {:>8} tokens  {}\n",
+        metadata.id,
+        short_timestamp(&metadata.updated_at),
+        metadata.model,
+        metadata.usage.total_tokens,
+        metadata.title.as_deref().unwrap_or("(untitled)")
+    )
+}
+
+pub fn print_conversation_list() -> Result<(), AppError> {
+    let conversations = list_conversations()?;
+    if conversations.is_empty() {
+        return print_colorful("No saved conversations\n", Color::DarkGrey);
+    }
+
+    for metadata in &conversations {
+        print_colorful(&format_conversation_row(metadata), Color::DarkGreen)?;
+    }
+    Ok(())
+}
+
+// Messages are numbered so `/fork <index>` can refer to them
+fn print_message(index: usize, message: &Message) -> Result<(), AppError> {
+    print_colorful(&format!("[{}] ", index), Color::DarkGrey)?;
+    match message.role.as_str() {
+        "system" => print_colorful(
+            &format!(
+                "System: ({} characters)\n",
+                message.content.as_deref().unwrap_or_default().len()
+            ),
+            Color::DarkGrey,
+        )?,
+        "user" => print_colorful(
+            &format!("User: {}\n", message.content.as_deref().unwrap_or_default()),
+            Color::Yellow,
+        )?,
+        "tool" => {
+            let content = message.content.as_deref().unwrap_or_default();
+            let clipped: String = content.chars().take(SHOW_TOOL_RESULT_CHARS).collect();
+            let ellipsis = if clipped.len() < content.len() {
+                "..."
+            } else {
+                ""
+            };
+            print_colorful(
+                &format!(
+                    "  {} => {}{}\n",
+                    message.name.as_deref().unwrap_or("tool"),
+                    clipped,
+                    ellipsis
+                ),
+                Color::DarkMagenta,
+            )?
+        }
+        _ => {
+            if let Some(content) = &message.content {
+                print_colorful(&format!("Assistant: {}\n", content), Color::Cyan)?;
+            }
+            for tool_call in message.tool_calls.iter().flatten() {
+                print_colorful(
+                    &format!(
+                        "  {}({})\n",
+                        tool_call.function.name, tool_call.function.arguments
+                    ),
+                    Color::Blue,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn print_conversation(query: &str) -> Result<(), AppError> {
+    let conversation_id = resolve_conversation_id(query)?;
+    let (metadata, messages) = read_conversation(&conversation_id)?;
+
+    print_colorful(
+        &format!(
+            "{}\nmodel: {} | started: {} | updated: {} | tokens: {} ({} prompt, {} completion) | {} messages\n\n",
+            metadata.title.as_deref().unwrap_or("(untitled)"),
+            metadata.model,
+            short_timestamp(&metadata.created_at),
+            short_timestamp(&metadata.updated_at),
+            metadata.usage.total_tokens,
+            metadata.usage.prompt_tokens,
+            metadata.usage.completion_tokens,
+            messages.len()
+        ),
+        Color::DarkGreen,
+    )?;
+
+    for (index, message) in messages.iter().enumerate() {
+        print_message(index, message)?;
+    }
+    Ok(())
+}
+
+// Render the family of branches `conversation_id` belongs to, starting from its root
+fn render_tree(
+    conversations: &[ConversationMetadata],
+    conversation_id: &str,
+    current: Option<&str>,
+) -> Vec<String> {
+    let by_id: HashMap<&str, &ConversationMetadata> = conversations
+        .iter()
+        .map(|metadata| (metadata.id.as_str(), metadata))
+        .collect();
+    let mut children: HashMap<&str, Vec<&ConversationMetadata>> = HashMap::new();
+    for metadata in conversations {
+        if let Some(parent) = &metadata.parent {
+            children
+                .entry(parent.conversation_id.as_str())
+                .or_default()
+                .push(metadata);
+        }
+    }
+    for branches in children.values_mut() {
+        branches.sort_by(|a, b| {
+            let index = |metadata: &ConversationMetadata| {
+                metadata.parent.as_ref().map(|parent| parent.message_index)
+            };
+            index(a)
+                .cmp(&index(b))
+                .then_with(|| a.created_at.cmp(&b.created_at))
+        });
+    }
+
+    // Walk up to the oldest ancestor that still exists; a deleted parent ends the walk
+    let mut root = conversation_id;
+    for _ in 0..conversations.len() {
+        match by_id
+            .get(root)
+            .and_then(|metadata| metadata.parent.as_ref())
+        {
+            Some(parent) if by_id.contains_key(parent.conversation_id.as_str()) => {
+                root = parent.conversation_id.as_str()
+            }
+            _ => break,
+        }
+    }
+
+    let label = |metadata: &ConversationMetadata| {
+        format!(
+            "{}  {}{}",
+            metadata.id,
+            metadata.title.as_deref().unwrap_or("(untitled)"),
+            if current == Some(metadata.id.as_str()) {
+                "  (current)"
+            } else {
+                ""
+            }
+        )
+    };
+
+    // Depth-first, so each branch is printed right under the message it forked from
+    fn push_branches<'a>(
+        stack: &mut Vec<(&'a ConversationMetadata, String, bool)>,
+        branches: Option<&Vec<&'a ConversationMetadata>>,
+        indent: &str,
+    ) {
+        let branches = branches.map_or(&[][..], Vec::as_slice);
+        for (i, child) in branches.iter().enumerate().rev() {
+            stack.push((*child, indent.to_string(), i + 1 == branches.len()));
+        }
+    }
+
+    let Some(root_metadata) = by_id.get(root) else {
+        return Vec::new();
+    };
+    let mut lines = vec![label(root_metadata)];
+    let mut stack = Vec::new();
+    push_branches(&mut stack, children.get(root), "");
+
+    while let Some((metadata, indent, is_last)) = stack.pop() {
+        let fork_index = metadata
+            .parent
+            .as_ref()
+            .map_or(0, |parent| parent.message_index);
+        lines.push(format!(
+            "{}{} @{} {}",
+            indent,
+            if is_last { "└─" } else { "├─" },
+            fork_index,
+            label(metadata)
+        ));
+
+        let child_indent = format!("{}{}", indent, if is_last { "   " } else { "│  " });
+        push_branches(
+            &mut stack,
+            children.get(metadata.id.as_str()),
+            &child_indent,
+        );
+    }
+
+    lines
+}
+
+// Print the branch tree around a conversation; `@N` marks the message a branch forked after
+pub fn print_conversation_tree(query: &str, current: Option<&str>) -> Result<(), AppError> {
+    let conversation_id = resolve_conversation_id(query)?;
+    let conversations = list_conversations()?;
+    for line in render_tree(&conversations, &conversation_id, current) {
+        print_colorful(&format!("{}\n", line), Color::DarkGreen)?;
+    }
+    Ok(())
+}
+
+pub fn print_search_results(query: &SearchQuery) -> Result<(), AppError> {
+    let hits = store().search(query)?;
+    if hits.is_empty() {
+        return print_colorful("No matching messages\n", Color::DarkGrey);
+    }
+
+    for hit in &hits {
+        print_colorful(
+            &format!(
+                "{}  #{} {}  {}\n",
+                hit.conversation_id,
+                hit.message_index,
+                hit.role,
+                hit.title.as_deref().unwrap_or("(untitled)")
+            ),
+            Color::DarkGreen,
+        )?;
+        print_colorful(&format!("    {}\n", hit.snippet), Color::Reset)?;
+    }
+    print_colorful(
+        "Open a result with /load <id> or --resume <id>, or view it with show <id>\n",
+        Color::DarkGrey,
+    )
+}
+
+pub fn fork(query: &str, at: Option<usize>) -> Result<(), AppError> {
+    let metadata = fork_conversation(query, at)?;
+    print_colorful(
+        &format!("Forked into conversation {}\n", metadata.id),
+        Color::DarkMagenta,
+    )
+}
+
+pub fn export(query: &str, format: ExportFormat, output: Option<&str>) -> Result<(), AppError> {
+    let tools = GLOBAL_TOOL_REGISTRY.generate_tools_json();
+    let path = export_conversation(query, format, output, &tools)?;
+    print_colorful(&format!("Exported to {}\n", path), Color::DarkMagenta)
+}
+
+pub fn delete(query: &str) -> Result<(), AppError> {
+    let conversation_id = resolve_conversation_id(query)?;
+    delete_conversation(&conversation_id)?;
+    print_colorful(
+        &format!("Deleted conversation {}\n", conversation_id),
+        Color::DarkMagenta,
+    )
+}
+
+pub fn rename(query: &str, title: &str) -> Result<(), AppError> {
+    let conversation_id = resolve_conversation_id(query)?;
+    rename_conversation(&conversation_id, title)?;
+    print_colorful(
+        &format!("Renamed conversation {} to `{}`\n", conversation_id, title),
+        Color::DarkMagenta,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::{ForkOrigin, Usage};
+
+    fn conversation(id: &str, parent: Option<(&str, usize)>) -> ConversationMetadata {
+        ConversationMetadata {
+            id: id.to_string(),
+            title: Some(format!("title {}", id)),
+            model: "m".to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            usage: Usage::default(),
+            parent: parent.map(|(conversation_id, message_index)| ForkOrigin {
+                conversation_id: conversation_id.to_string(),
+                message_index,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_render_tree_from_root() {
+        let conversations = vec![
+            conversation("root", None),
+            conversation("late", Some(("root", 7))),
+            conversation("early", Some(("root", 3))),
+            conversation("nested", Some(("early", 5))),
+            conversation("unrelated", None),
+        ];
+
+        assert_eq!(
+            render_tree(&conversations, "nested", Some("nested")),
+            vec![
+                "root  title root",
+                "├─ @3 early  title early",
+                "│  └─ @5 nested  title nested  (current)",
+                "└─ @7 late  title late",
+            ]
+        );
+    }
+}
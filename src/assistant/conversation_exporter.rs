@@ -0,0 +1,376 @@
+use crate::{
+    assistant::conversation_manager::{read_conversation, resolve_conversation_id},
+    config::settings,
+    models::types::{AppError, ConversationMetadata, Message, ToolCall},
+};
+
+use serde_json::{json, Value as JsonValue};
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const EXPORTS_DIR: &str = "exports";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    // One OpenAI fine-tuning example per line
+    Jsonl,
+}
+
+impl FromStr for ExportFormat {
+    type Err = AppError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            "html" => Ok(ExportFormat::Html),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            _ => Err(AppError::CommandError(format!(
+                "Unknown export format `{}` (expected md, html or jsonl)",
+                format
+            ))),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+// Arguments are stored as the model sent them; show them indented when they are valid JSON
+fn pretty_arguments(tool_call: &ToolCall) -> String {
+    serde_json::from_str::<JsonValue>(&tool_call.function.arguments)
+        .and_then(|arguments| serde_json::to_string_pretty(&arguments))
+        .unwrap_or_else(|_| tool_call.function.arguments.clone())
+}
+
+fn role_heading(role: &str) -> &str {
+    match role {
+        "system" => "System",
+        "user" => "User",
+        "assistant" => "Assistant",
+        "tool" => "Tool",
+        other => other,
+    }
+}
+
+fn header_line(metadata: &ConversationMetadata, messages: &[Message]) -> String {
+    format!(
+        "{} | model {} | started {} | updated {} | {} tokens | {} messages",
+        metadata.id,
+        metadata.model,
+        metadata.created_at,
+        metadata.updated_at,
+        metadata.usage.total_tokens,
+        messages.len()
+    )
+}
+
+// A code fence longer than any run of backticks inside `content`, so it cannot be closed early
+fn fenced(content: &str, language: &str) -> String {
+    let mut longest_run = 0;
+    let mut run = 0;
+    for c in content.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest_run = longest_run.max(run);
+    }
+    let fence = "`".repeat((longest_run + 1).max(3));
+    format!("{}{}\n{}\n{}\n", fence, language, content.trim_end(), fence)
+}
+
+fn render_markdown(metadata: &ConversationMetadata, messages: &[Message]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# {}\n\n_{}_\n",
+        metadata.title.as_deref().unwrap_or("(untitled)"),
+        header_line(metadata, messages)
+    );
+
+    for message in messages {
+        let content = message.content.as_deref().unwrap_or_default();
+        match message.role.as_str() {
+            // System prompts embed the tool schemas and can be very long
+            "system" => {
+                let _ = writeln!(
+                    out,
+                    "<details>\n<summary>System</summary>\n\n{}\n</details>\n",
+                    fenced(content, "text")
+                );
+            }
+            "tool" => {
+                let _ = writeln!(
+                    out,
+                    "<details>\n<summary>Tool result: {}</summary>\n\n{}\n</details>\n",
+                    message.name.as_deref().unwrap_or("tool"),
+                    fenced(content, "text")
+                );
+            }
+            role => {
+                let _ = writeln!(out, "## {}\n", role_heading(role));
+                if !content.is_empty() {
+                    let _ = writeln!(out, "{}\n", content.trim_end());
+                }
+                for tool_call in message.tool_calls.iter().flatten() {
+                    let _ = writeln!(
+                        out,
+                        "**Tool call** `{}` ({})\n\n{}",
+                        tool_call.function.name,
+                        tool_call.id,
+                        fenced(&pretty_arguments(tool_call), "json")
+                    );
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:56rem;margin:2rem auto;padding:0 1rem;color:#1f2328}
+.meta{color:#656d76;font-size:.9rem}
+.message{border-left:4px solid #d0d7de;margin:1rem 0;padding:.25rem 1rem}
+.user{border-color:#bf8700}.assistant{border-color:#0969da}.system,.tool{border-color:#8c959f}
+.role{font-weight:600;margin:.25rem 0}
+.content{white-space:pre-wrap}
+pre{background:#f6f8fa;padding:.75rem;overflow-x:auto;white-space:pre-wrap}
+summary{cursor:pointer;color:#656d76}";
+
+fn render_html(metadata: &ConversationMetadata, messages: &[Message]) -> String {
+    let title = escape_html(metadata.title.as_deref().unwrap_or("(untitled)"));
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>{}</h1>\n<p class=\"meta\">{}</p>",
+        title,
+        HTML_STYLE,
+        title,
+        escape_html(&header_line(metadata, messages))
+    );
+
+    for message in messages {
+        let content = escape_html(message.content.as_deref().unwrap_or_default());
+        let _ = writeln!(
+            out,
+            "<div class=\"message {}\">",
+            escape_html(&message.role)
+        );
+        match message.role.as_str() {
+            "system" => {
+                let _ = writeln!(
+                    out,
+                    "<details><summary>System</summary><pre>{}</pre></details>",
+                    content
+                );
+            }
+            "tool" => {
+                let _ = writeln!(
+                    out,
+                    "<details><summary>Tool result: {}</summary><pre>{}</pre></details>",
+                    escape_html(message.name.as_deref().unwrap_or("tool")),
+                    content
+                );
+            }
+            role => {
+                let _ = writeln!(
+                    out,
+                    "<p class=\"role\">{}</p>",
+                    escape_html(role_heading(role))
+                );
+                if !content.is_empty() {
+                    let _ = writeln!(out, "<div class=\"content\">{}</div>", content);
+                }
+                for tool_call in message.tool_calls.iter().flatten() {
+                    let _ = writeln!(
+                        out,
+                        "<p>Tool call <code>{}</code> <span class=\"meta\">{}</span></p>\n<pre>{}</pre>",
+                        escape_html(&tool_call.function.name),
+                        escape_html(&tool_call.id),
+                        escape_html(&pretty_arguments(tool_call))
+                    );
+                }
+            }
+        }
+        let _ = writeln!(out, "</div>");
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+// The chat fine-tuning format: the whole conversation as one `messages` example, with the tool
+// definitions attached when the conversation used tools
+fn render_jsonl(messages: &[Message], tools: &JsonValue) -> Result<String, AppError> {
+    let messages: Vec<JsonValue> = messages
+        .iter()
+        .map(|message| {
+            let mut value = json!({ "role": message.role, "content": message.content });
+            if let Some(tool_calls) = &message.tool_calls {
+                value["tool_calls"] = json!(tool_calls);
+            }
+            if let Some(tool_call_id) = &message.tool_call_id {
+                value["tool_call_id"] = json!(tool_call_id);
+            }
+            value
+        })
+        .collect();
+
+    let mut example = json!({ "messages": messages });
+    if messages
+        .iter()
+        .any(|message| message.get("tool_calls").is_some())
+    {
+        example["tools"] = tools.clone();
+    }
+
+    Ok(format!("{}\n", serde_json::to_string(&example)?))
+}
+
+pub fn render(
+    format: ExportFormat,
+    metadata: &ConversationMetadata,
+    messages: &[Message],
+    tools: &JsonValue,
+) -> Result<String, AppError> {
+    match format {
+        ExportFormat::Markdown => Ok(render_markdown(metadata, messages)),
+        ExportFormat::Html => Ok(render_html(metadata, messages)),
+        ExportFormat::Jsonl => render_jsonl(messages, tools),
+    }
+}
+
+// Write a conversation to `output` (default `exports/<id>.<ext>`) and return the path written
+pub fn export_conversation(
+    query: &str,
+    format: ExportFormat,
+    output: Option<&str>,
+    tools: &JsonValue,
+) -> Result<String, AppError> {
+    let conversation_id = resolve_conversation_id(query)?;
+    let (metadata, messages) = read_conversation(&conversation_id)?;
+    let rendered = render(format, &metadata, &messages, tools)?;
+
+    let path = match output {
+        Some(path) => path.to_string(),
+        None => {
+            let exports_dir = &settings().exports_dir;
+            std::fs::create_dir_all(exports_dir)?;
+            exports_dir
+                .join(format!("{}.{}", conversation_id, format.extension()))
+                .to_string_lossy()
+                .into_owned()
+        }
+    };
+    if let Some(parent) = Path::new(&path).parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    std::fs::write(&path, rendered)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::{FunctionCall, Usage};
+
+    fn sample() -> (ConversationMetadata, Vec<Message>) {
+        let metadata = ConversationMetadata {
+            id: "20240101-000000-abc".to_string(),
+            title: Some("List <files>".to_string()),
+            model: "gpt-4".to_string(),
+            created_at: "2024-01-01T00:00:00+00:00".to_string(),
+            updated_at: "2024-01-01T00:01:00+00:00".to_string(),
+            usage: Usage::default(),
+            parent: None,
+        };
+        let messages = vec![
+            Message::new("system".to_string(), "You are helpful".to_string()),
+            Message::new("user".to_string(), "List the files".to_string()),
+            Message {
+                role: "assistant".to_string(),
+                content: None,
+                tool_calls: Some(vec![ToolCall {
+                    id: "call_1".to_string(),
+                    r#type: "function".to_string(),
+                    function: FunctionCall {
+                        name: "shell_tool".to_string(),
+                        arguments: r#"{"commands":[{"command":"ls"}]}"#.to_string(),
+                    },
+                }]),
+                tool_call_id: None,
+                name: None,
+            },
+            Message {
+                role: "tool".to_string(),
+                content: Some("main.rs\n```\n".to_string()),
+                tool_calls: None,
+                tool_call_id: Some("call_1".to_string()),
+                name: Some("shell_tool".to_string()),
+            },
+            Message::new("assistant".to_string(), "There is main.rs".to_string()),
+        ];
+        (metadata, messages)
+    }
+
+    #[test]
+    fn test_markdown_collapses_tool_results_and_pretty_prints_arguments() {
+        let (metadata, messages) = sample();
+        let markdown = render_markdown(&metadata, &messages);
+
+        assert!(markdown.starts_with("# List <files>\n"));
+        assert!(markdown.contains("<summary>Tool result: shell_tool</summary>"));
+        assert!(markdown.contains("\"commands\": [\n"));
+        // The tool output contains a fence, so a longer one is used around it
+        assert!(markdown.contains("````text\nmain.rs\n```\n````"));
+    }
+
+    #[test]
+    fn test_html_escapes_content() {
+        let (metadata, messages) = sample();
+        let html = render_html(&metadata, &messages);
+
+        assert!(html.contains("<title>List &lt;files&gt;</title>"));
+        assert!(html.contains("<details><summary>Tool result: shell_tool</summary>"));
+        assert!(!html.contains("<files>"));
+    }
+
+    #[test]
+    fn test_jsonl_is_a_single_fine_tuning_example() {
+        let (_, messages) = sample();
+        let tools = json!([{ "type": "function", "function": { "name": "shell_tool" } }]);
+        let jsonl = render_jsonl(&messages, &tools).unwrap();
+
+        assert_eq!(jsonl.lines().count(), 1);
+        let example: JsonValue = serde_json::from_str(&jsonl).unwrap();
+        assert_eq!(example["messages"].as_array().unwrap().len(), 5);
+        assert_eq!(example["messages"][3]["tool_call_id"], "call_1");
+        assert!(example["messages"][3].get("name").is_none());
+        assert_eq!(example["tools"], tools);
+    }
+}
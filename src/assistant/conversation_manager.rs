@@ -1,67 +1,315 @@
 use crate::{
-    models::types::{AppError, Message},
+    assistant::context_manager::Compacted,
+    config::settings,
+    models::types::{AppError, ConversationMetadata, ForkOrigin, Message, Usage},
+    storage::store,
     utils::common::{print_colorful, read_file},
 };
 
 use crossterm::style::Color;
 use serde_json::Value as JsonValue;
-use std::fs::File;
-use std::io::Write;
+use std::borrow::Cow;
+
+const TITLE_MAX_CHARS: usize = 60;
+
+// A sortable, unique id: creation timestamp followed by a random uuid
+pub fn new_conversation_id() -> String {
+    format!(
+        "{}-{}",
+        chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+// Derive a title from the first line of a prompt
+pub fn title_from_prompt(prompt: &str) -> String {
+    let first_line = prompt.lines().next().unwrap_or_default().trim();
+    if first_line.chars().count() > TITLE_MAX_CHARS {
+        let clipped: String = first_line.chars().take(TITLE_MAX_CHARS).collect();
+        format!("{}...", clipped)
+    } else {
+        first_line.to_string()
+    }
+}
+
+pub fn read_conversation(
+    conversation_id: &str,
+) -> Result<(ConversationMetadata, Vec<Message>), AppError> {
+    store().load(conversation_id)
+}
+
+// Metadata for every stored conversation, most recently updated first
+pub fn list_conversations() -> Result<Vec<ConversationMetadata>, AppError> {
+    store().list()
+}
+
+// Accept a full id or any fragment of one that matches a single conversation
+pub fn resolve_conversation_id(query: &str) -> Result<String, AppError> {
+    let query = query.trim();
+    let ids: Vec<String> = list_conversations()?
+        .into_iter()
+        .map(|metadata| metadata.id)
+        .collect();
+    if ids.iter().any(|id| id == query) {
+        return Ok(query.to_string());
+    }
+
+    let matches: Vec<String> = ids.into_iter().filter(|id| id.contains(query)).collect();
+
+    match matches.len() {
+        1 => Ok(matches[0].clone()),
+        0 => Err(AppError::CommandError(format!(
+            "No conversation matches `{}`",
+            query
+        ))),
+        _ => Err(AppError::CommandError(format!(
+            "`{}` matches several conversations: {}",
+            query,
+            matches.join(", ")
+        ))),
+    }
+}
+
+// The most recently updated conversation, for `--continue-last`
+pub fn latest_conversation_id() -> Result<String, AppError> {
+    list_conversations()?
+        .into_iter()
+        .next()
+        .map(|metadata| metadata.id)
+        .ok_or_else(|| {
+            AppError::CommandError("There are no saved conversations to continue".to_string())
+        })
+}
+
+// Swap the leading system prompt for `system_message`, or insert one if the history has none
+fn replace_system_prompt(messages: &mut Vec<Message>, system_message: String) {
+    let system_prompt = Message::new("system".to_string(), system_message);
+    match messages.first_mut() {
+        Some(first) if first.role == "system" => *first = system_prompt,
+        _ => messages.insert(0, system_prompt),
+    }
+}
+
+// Branch a conversation after message `at` (or after its last message). Tool results answering
+// that message are carried along so the fork never ends halfway through a tool call.
+pub fn fork_messages(
+    parent: &ConversationMetadata,
+    messages: &[Message],
+    at: Option<usize>,
+) -> Result<(ConversationMetadata, Vec<Message>), AppError> {
+    let last_index = messages
+        .len()
+        .checked_sub(1)
+        .ok_or_else(|| AppError::CommandError("Cannot fork an empty conversation".to_string()))?;
+    let at = at.unwrap_or(last_index);
+    if at > last_index {
+        return Err(AppError::CommandError(format!(
+            "Message {} does not exist; the conversation has messages 0 to {}",
+            at, last_index
+        )));
+    }
+
+    let mut end = at + 1;
+    while end < messages.len() && messages[end].role == "tool" {
+        end += 1;
+    }
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let metadata = ConversationMetadata {
+        id: new_conversation_id(),
+        title: parent.title.clone(),
+        model: parent.model.clone(),
+        created_at: now.clone(),
+        updated_at: now,
+        usage: Usage::default(),
+        parent: Some(ForkOrigin {
+            conversation_id: parent.id.clone(),
+            message_index: end - 1,
+        }),
+    };
+
+    Ok((metadata, messages[..end].to_vec()))
+}
+
+// Fork a saved conversation into a new one, returning the new conversation's metadata
+pub fn fork_conversation(query: &str, at: Option<usize>) -> Result<ConversationMetadata, AppError> {
+    let conversation_id = resolve_conversation_id(query)?;
+    let (parent, messages) = read_conversation(&conversation_id)?;
+    let (metadata, messages) = fork_messages(&parent, &messages, at)?;
+    store().save(&metadata, &messages)?;
+    Ok(metadata)
+}
+
+pub fn delete_conversation(conversation_id: &str) -> Result<(), AppError> {
+    store().delete(conversation_id)
+}
+
+pub fn rename_conversation(conversation_id: &str, title: &str) -> Result<(), AppError> {
+    let (mut metadata, messages) = read_conversation(conversation_id)?;
+    metadata.title = Some(title.to_string());
+    store().save(&metadata, &messages)
+}
+
+// What the model is sent in place of the first `covers` messages once they've been summarized
+struct Compaction {
+    head: Vec<Message>,
+    covers: usize,
+}
 
 pub struct ConversationManager {
+    // The whole history, as stored
     pub messages: Vec<Message>,
+    compaction: Option<Compaction>,
+    // How many of the messages are known to be in the store
+    stored: usize,
     tools_json: JsonValue,
     tools_schema: String,
-    conversation_id: String,
+    pub metadata: ConversationMetadata,
 }
 
 impl ConversationManager {
-    pub fn new(tools_json: JsonValue, tools_schema: String) -> Self {
+    pub fn new(tools_json: JsonValue, tools_schema: String, model: String) -> Self {
+        let now = chrono::Utc::now().to_rfc3339();
         Self {
             messages: vec![],
+            compaction: None,
+            stored: 0,
             tools_json,
             tools_schema,
-            conversation_id: "filler".to_string(),
+            metadata: ConversationMetadata {
+                id: new_conversation_id(),
+                title: None,
+                model,
+                created_at: now.clone(),
+                updated_at: now,
+                usage: Usage::default(),
+                parent: None,
+            },
         }
     }
 
+    pub fn conversation_id(&self) -> &str {
+        &self.metadata.id
+    }
+
+    // A prompt that couldn't be saved is taken back, since nothing has answered it yet
     pub fn add_user_prompt(&mut self, prompt: String) -> Result<(), AppError> {
-        self.add_message(Message::new("user".to_string(), prompt))
+        let result = self.add_message(Message::new("user".to_string(), prompt));
+        if result.is_err() {
+            self.messages.pop();
+        }
+        result
     }
 
+    // The message is kept even if it couldn't be saved; the next save retries it
+
     pub fn add_message(&mut self, message: Message) -> Result<(), AppError> {
         log::info!("[+] Message: {:?}", message);
+        if self.metadata.title.is_none() && message.role == "user" {
+            self.metadata.title = message.content.as_deref().map(title_from_prompt);
+        }
         self.messages.push(message);
 
-        // Try to serialize the entire conversation to JSON and write to file
-        match serde_json::to_string(&self.messages) {
-            Ok(messages_str) => {
-                let conversation_file_path = format!("conversations/{}.json", self.conversation_id);
-                let mut file = File::create(&conversation_file_path).map_err(AppError::from)?;
+        self.save(self.messages.len() - 1)
+    }
 
-                file.write_all(messages_str.as_bytes())?;
-            }
-            Err(_) => {
-                log::warn!("Failed to write conversation")
+    // The messages to send to the model: the history, with any summarized turns swapped for the summary
+    pub fn prompt(&self) -> Cow<'_, [Message]> {
+        match &self.compaction {
+            Some(compaction) => {
+                let mut prompt = compaction.head.clone();
+                prompt.extend_from_slice(&self.messages[compaction.covers..]);
+                Cow::Owned(prompt)
             }
+            None => Cow::Borrowed(&self.messages),
+        }
+    }
+
+    // Send a compacted prompt from now on. Only the prompt changes; the stored history keeps every
+    // turn, for /search, /fork, /export and --resume.
+    pub fn compact_prompt(&mut self, compacted: Compacted) {
+        log::info!(
+            "Compacted the prompt to {} messages; the history keeps all {}",
+            compacted.messages.len(),
+            self.messages.len()
+        );
+        // The kept messages can reach back into an earlier summary, which isn't in the history
+        let covers = self
+            .compaction
+            .as_ref()
+            .map_or(0, |compaction| compaction.covers);
+        let kept = compacted.kept.min(self.messages.len() - covers);
+        let mut head = compacted.messages;
+        head.truncate(head.len() - kept);
+        self.compaction = Some(Compaction {
+            head,
+            covers: self.messages.len() - kept,
+        });
+    }
+
+    // Add the usage reported for a completion to the conversation's running totals
+    pub fn record_usage(&mut self, usage: &Usage) {
+        self.metadata.usage.add(usage);
+        if let Err(e) = store().record_usage(self.conversation_id(), usage) {
+            log::warn!("Failed to record usage: {}", e)
         }
+    }
+
+    // Continue in a new branch of this conversation cut after message `at`; returns the old id
+    pub fn fork(&mut self, at: Option<usize>) -> Result<String, AppError> {
+        let (metadata, messages) = fork_messages(&self.metadata, &self.messages, at)?;
+        let parent_id = std::mem::replace(&mut self.metadata, metadata).id;
+        self.messages = messages;
+        self.stored = 0;
+        // The summary still stands in for the same turns, unless the cut falls among them
+        if self
+            .compaction
+            .as_ref()
+            .is_some_and(|compaction| compaction.covers > self.messages.len())
+        {
+            self.compaction = None;
+        }
+        self.save(0)?;
+        Ok(parent_id)
+    }
+
+    // Index of the most recent user message, the turn `/retry` answers again
+    pub fn last_user_message_index(&self) -> Option<usize> {
+        self.messages
+            .iter()
+            .rposition(|message| message.role == "user")
+    }
 
+    pub fn rename(&mut self, title: String) -> Result<(), AppError> {
+        self.metadata.title = Some(title);
+        self.save(self.messages.len())
+    }
+
+    // Persist the metadata and the messages from index `from` on, along with any earlier ones a
+    // failed save left out
+    fn save(&mut self, from: usize) -> Result<(), AppError> {
+        self.metadata.updated_at = chrono::Utc::now().to_rfc3339();
+
+        let from = from.min(self.stored);
+        store().append(&self.metadata, &self.messages, from)?;
+        self.stored = self.messages.len();
         Ok(())
     }
 
-    pub fn initialize_conversation(
-        &mut self,
-        initial_prompt: String,
-        include_state: bool,
-    ) -> Result<(), AppError> {
-        let mut system_message = read_file("system.txt")?;
+    // The system prompt file, optionally the project state, and the tool descriptions the model is given
+    fn build_system_message(&self, include_state: bool) -> Result<String, AppError> {
+        let settings = settings();
+        let mut system_message = read_file(&settings.system_prompt)?;
 
         if include_state {
-            if let Ok(state_content) = read_file("state.txt") {
+            if let Ok(state_content) = read_file(&settings.state_file) {
                 system_message.push_str("\nHere is the current project source code:\n");
                 system_message.push_str(&state_content);
             } else {
-                log::warn!("state.txt not found, continuing without state (run `cargo test` to generate it)");
+                log::warn!(
+                    "{} not found, continuing without state (run `cargo test` to generate it)",
+                    settings.state_file.display()
+                );
             }
         }
 
@@ -70,24 +318,166 @@ impl ConversationManager {
         system_message.push_str("\ntools JSON schemas:\n");
         system_message.push_str(&self.tools_schema);
 
+        Ok(system_message)
+    }
+
+    pub fn initialize_conversation(
+        &mut self,
+        initial_prompt: String,
+        include_state: bool,
+    ) -> Result<(), AppError> {
+        let system_message = self.build_system_message(include_state)?;
+
         self.add_message(Message::new("system".to_string(), system_message))?;
         self.add_message(Message::new("user".to_string(), initial_prompt))?;
 
         Ok(())
     }
 
+    // Pick a saved conversation back up; with `refresh_system` its system prompt is rebuilt so the
+    // model sees the tools as they are now rather than when the conversation started
+    pub fn resume_conversation(
+        &mut self,
+        conversation_id: &str,
+        refresh_system: bool,
+        include_state: bool,
+    ) -> Result<(), AppError> {
+        let conversation_id = resolve_conversation_id(conversation_id)?;
+        let (metadata, messages) = read_conversation(&conversation_id)?;
+        self.metadata = metadata;
+        self.messages = messages;
+        self.compaction = None;
+        self.stored = self.messages.len();
+
+        if refresh_system {
+            let system_message = self.build_system_message(include_state)?;
+            replace_system_prompt(&mut self.messages, system_message);
+            self.save(0)?;
+        }
+
+        Ok(())
+    }
+
     pub fn load_conversation(&mut self, conversation_id: String) -> Result<(), AppError> {
-        let file_path = format!("conversations/{}.json", conversation_id);
-        let content = std::fs::read_to_string(file_path)?;
-        let new_messages: Vec<Message> = serde_json::from_str(&content)?;
+        let conversation_id = resolve_conversation_id(&conversation_id)?;
+        let (metadata, new_messages) = read_conversation(&conversation_id)?;
         print_colorful(
             &format!("Successfully loaded conversation {}\n", conversation_id),
             Color::DarkMagenta,
         )?;
 
         self.messages = new_messages;
-        self.conversation_id = conversation_id.to_string();
+        self.metadata = metadata;
+        self.compaction = None;
+        self.stored = self.messages.len();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_conversation_ids_are_unique() {
+        let first = new_conversation_id();
+        let second = new_conversation_id();
+        assert_ne!(first, second);
+        // yyyymmdd-hhmmss-<32 hex digits>
+        assert_eq!(first.len(), 8 + 1 + 6 + 1 + 32);
+    }
+
+    #[test]
+    fn test_title_from_prompt() {
+        assert_eq!(title_from_prompt("Fix the bug\nin main.rs"), "Fix the bug");
+        let long = "a".repeat(100);
+        assert_eq!(title_from_prompt(&long), format!("{}...", "a".repeat(60)));
+    }
+
+    #[test]
+    fn test_replace_system_prompt() {
+        let mut messages = vec![
+            Message::new("system".to_string(), "old tools".to_string()),
+            Message::new("user".to_string(), "hello".to_string()),
+        ];
+        replace_system_prompt(&mut messages, "new tools".to_string());
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content.as_deref(), Some("new tools"));
+
+        let mut messages = vec![Message::new("user".to_string(), "hello".to_string())];
+        replace_system_prompt(&mut messages, "new tools".to_string());
+        assert_eq!(messages[0].role, "system");
+        assert_eq!(messages[1].role, "user");
+    }
+
+    #[test]
+    fn test_fork_keeps_tool_results_with_their_call() {
+        let parent = ConversationManager::new(JsonValue::Null, String::new(), "m".to_string());
+        let mut tool_call = Message::new("assistant".to_string(), String::new());
+        tool_call.tool_calls = Some(vec![]);
+        let messages = vec![
+            Message::new("system".to_string(), "s".to_string()),
+            Message::new("user".to_string(), "u".to_string()),
+            tool_call,
+            Message::new("tool".to_string(), "result".to_string()),
+            Message::new("assistant".to_string(), "done".to_string()),
+        ];
+
+        let (metadata, forked) = fork_messages(&parent.metadata, &messages, Some(2)).unwrap();
+        assert_eq!(forked.len(), 4);
+        assert_ne!(metadata.id, parent.metadata.id);
+        assert_eq!(
+            metadata.parent,
+            Some(ForkOrigin {
+                conversation_id: parent.metadata.id.clone(),
+                message_index: 3
+            })
+        );
+
+        let (_, whole) = fork_messages(&parent.metadata, &messages, None).unwrap();
+        assert_eq!(whole.len(), 5);
+        assert!(fork_messages(&parent.metadata, &messages, Some(5)).is_err());
+    }
+
+    #[test]
+    fn test_compaction_only_changes_the_prompt() {
+        let message =
+            |role: &str, content: &str| Message::new(role.to_string(), content.to_string());
+        let mut manager = ConversationManager::new(JsonValue::Null, String::new(), "m".to_string());
+        manager.messages = vec![
+            message("system", "s"),
+            message("user", "u1"),
+            message("assistant", "a1"),
+            message("user", "u2"),
+        ];
+
+        manager.compact_prompt(Compacted {
+            messages: vec![
+                message("system", "s"),
+                message("system", "summary 1"),
+                message("user", "u2"),
+            ],
+            kept: 1,
+        });
+        manager.messages.push(message("assistant", "a2"));
+        assert_eq!(manager.messages.len(), 5);
+        let prompt = manager.prompt();
+        assert_eq!(prompt.len(), 4);
+        assert_eq!(prompt[1].content.as_deref(), Some("summary 1"));
+        assert_eq!(prompt[3].content.as_deref(), Some("a2"));
+
+        // Compacting again, keeping more than the first summary left verbatim
+        let mut compacted = vec![message("system", "summary 2")];
+        compacted.extend_from_slice(&prompt[1..]);
+        manager.compact_prompt(Compacted {
+            messages: compacted,
+            kept: 3,
+        });
+        let prompt = manager.prompt();
+        assert_eq!(prompt.len(), 4);
+        assert_eq!(prompt[0].content.as_deref(), Some("summary 2"));
+        assert_eq!(prompt[1].content.as_deref(), Some("summary 1"));
+        assert_eq!(manager.messages.len(), 5);
+    }
+}
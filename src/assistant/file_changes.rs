@@ -0,0 +1,102 @@
+use crate::{
+    models::types::AppError,
+    storage::backups::{Backups, SessionChange},
+    utils::{
+        common::{print_colorful, print_diff},
+        diff::unified_diff,
+    },
+};
+
+use crossterm::style::Color;
+use std::path::{Path, PathBuf};
+
+// Relative to the working directory where possible, as the model usually wrote them
+fn display_path(path: &Path) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    path.strip_prefix(&cwd)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+fn path_list(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| display_path(path))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Revert the file changes of the last `calls` tool calls
+pub fn undo(conversation_id: &str, calls: usize, force: bool) -> Result<(), AppError> {
+    let report = Backups::for_conversation(conversation_id).undo(calls, force)?;
+    let undone = match report.calls {
+        1 => "the last tool call".to_string(),
+        calls => format!("the last {} tool calls", calls),
+    };
+    if report.restored.is_empty() {
+        print_colorful(&format!("Undid {}\n", undone), Color::DarkMagenta)?;
+    } else {
+        print_colorful(
+            &format!(
+                "Undid {}: restored {}\n",
+                undone,
+                path_list(&report.restored)
+            ),
+            Color::DarkMagenta,
+        )?;
+    }
+    if !report.skipped.is_empty() {
+        print_colorful(
+            &format!(
+                "Left alone, since they've changed after the tool call: {}\n/undo --force reverts them anyway\n",
+                path_list(&report.skipped)
+            ),
+            Color::DarkYellow,
+        )?;
+    }
+    Ok(())
+}
+
+fn describe_change(change: &SessionChange) -> &'static str {
+    match (&change.original, &change.current) {
+        (None, Some(_)) => "created",
+        (Some(_), None) => "deleted",
+        (None, None) => "created and deleted",
+        (Some(original), Some(current)) if original == current => "unchanged",
+        _ => "modified",
+    }
+}
+
+// Every file the conversation's tool calls touched, with a diff from before the first of them
+pub fn print_changes(conversation_id: &str) -> Result<(), AppError> {
+    let changes = Backups::for_conversation(conversation_id).changes()?;
+    if changes.is_empty() {
+        print_colorful(
+            "No files changed in this conversation\n",
+            Color::DarkMagenta,
+        )?;
+        return Ok(());
+    }
+
+    for change in &changes {
+        let path = display_path(&change.path);
+        print_colorful(
+            &format!(
+                "{} ({}, {} tool call{})\n",
+                path,
+                describe_change(change),
+                change.calls,
+                if change.calls == 1 { "" } else { "s" }
+            ),
+            Color::DarkMagenta,
+        )?;
+        let original = change.original.as_deref().unwrap_or_default();
+        let current = change.current.as_deref().unwrap_or_default();
+        match (std::str::from_utf8(original), std::str::from_utf8(current)) {
+            (Ok(original), Ok(current)) => print_diff(&unified_diff(&path, original, current))?,
+            _ => print_colorful("(binary file)\n", Color::DarkGrey)?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use crate::{
+    assistant::{Assistant, SessionStart},
+    models::types::{AppError, Usage},
+};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+// Exit codes for --print and --batch
+pub const EXIT_OK: i32 = 0;
+// The model couldn't be reached, or a batch prompt failed
+pub const EXIT_FAILED: i32 = 1;
+// No prompt, or an unreadable batch file
+pub const EXIT_USAGE: i32 = 2;
+// Answered, but the approval policy stopped at least one tool call
+pub const EXIT_DENIED: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolOutcome {
+    // Ran, and the result went back to the model
+    Ran,
+    // Ran and failed; the error went back to the model
+    Failed,
+    // The arguments didn't match the tool's schema
+    Invalid,
+    // Denied by the policy or declined by the user
+    Rejected,
+}
+
+// One tool call made while answering a prompt
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolTrace {
+    pub tool: String,
+    pub arguments: JsonValue,
+    pub outcome: ToolOutcome,
+    pub result: String,
+}
+
+// The outcome of a single user turn
+#[derive(Debug, Clone, Serialize)]
+pub struct Answer {
+    pub conversation_id: String,
+    pub response: Option<String>,
+    pub tool_calls: Vec<ToolTrace>,
+    pub usage: Usage,
+}
+
+impl Answer {
+    fn exit_code(&self) -> i32 {
+        if self
+            .tool_calls
+            .iter()
+            .any(|call| call.outcome == ToolOutcome::Rejected)
+        {
+            EXIT_DENIED
+        } else {
+            EXIT_OK
+        }
+    }
+}
+
+// One line of a --batch file. The id is echoed back so results can be matched to prompts; it
+// defaults to the line number.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchPrompt {
+    id: Option<JsonValue>,
+    prompt: String,
+}
+
+// One line of --batch output: an answer, or the error that stopped it
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    id: JsonValue,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    answer: Option<Answer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Answer one prompt and print the reply, and nothing else, to stdout
+pub async fn print_answer(mut assistant: Assistant, start: SessionStart) -> i32 {
+    match assistant.answer(start).await {
+        Ok(answer) => {
+            if let Some(response) = &answer.response {
+                println!("{}", response);
+            }
+            answer.exit_code()
+        }
+        Err(e) => {
+            log::error!("Turn failed: {}", e);
+            eprintln!("Error: {}", e);
+            EXIT_FAILED
+        }
+    }
+}
+
+// Answer every prompt in `input` (`-` for stdin), each in a new conversation, writing one JSON
+// line per prompt to `output` (stdout if not given). A failed prompt doesn't stop the batch.
+pub async fn run_batch(
+    input: &str,
+    output: Option<&str>,
+    mut new_assistant: impl FnMut() -> Assistant,
+) -> i32 {
+    let (reader, mut writer) = match (batch_input(input), batch_output(output)) {
+        (Ok(reader), Ok(writer)) => (reader, writer),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Error: {}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    // A failed prompt outranks a denied tool call
+    let mut exit_code = EXIT_OK;
+    for (i, line) in reader.lines().enumerate() {
+        let result = match line.map_err(AppError::from) {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => answer_line(i + 1, &line, new_assistant()).await,
+            Err(e) => BatchResult::failed(JsonValue::from(i + 1), e),
+        };
+
+        exit_code = match (&result.answer, exit_code) {
+            (None, _) => EXIT_FAILED,
+            (Some(answer), EXIT_OK) => answer.exit_code(),
+            (Some(_), exit_code) => exit_code,
+        };
+        if let Err(e) = write_result(&mut writer, &result) {
+            eprintln!("Error: failed to write the batch output: {}", e);
+            return EXIT_FAILED;
+        }
+    }
+    exit_code
+}
+
+fn batch_input(input: &str) -> Result<Box<dyn BufRead>, AppError> {
+    match input {
+        "-" => Ok(Box::new(BufReader::new(io::stdin()))),
+        path => Ok(Box::new(BufReader::new(File::open(path).map_err(|e| {
+            AppError::CommandError(format!("Can't read batch file {}: {}", path, e))
+        })?))),
+    }
+}
+
+fn batch_output(output: Option<&str>) -> Result<Box<dyn Write>, AppError> {
+    match output {
+        Some(path) => Ok(Box::new(BufWriter::new(File::create(path).map_err(
+            |e| AppError::CommandError(format!("Can't write batch output {}: {}", path, e)),
+        )?))),
+        None => Ok(Box::new(io::stdout())),
+    }
+}
+
+async fn answer_line(line_number: usize, line: &str, mut assistant: Assistant) -> BatchResult {
+    let prompt: BatchPrompt = match serde_json::from_str(line) {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            return BatchResult::failed(
+                JsonValue::from(line_number),
+                AppError::CommandError(format!("Invalid batch line: {}", e)),
+            )
+        }
+    };
+    let id = prompt.id.unwrap_or_else(|| JsonValue::from(line_number));
+
+    let start = SessionStart::New {
+        initial_prompt: prompt.prompt,
+        include_state: false,
+    };
+    match assistant.answer(start).await {
+        Ok(answer) => BatchResult {
+            id,
+            answer: Some(answer),
+            error: None,
+        },
+        Err(e) => BatchResult::failed(id, e),
+    }
+}
+
+impl BatchResult {
+    fn failed(id: JsonValue, error: AppError) -> Self {
+        log::error!("Batch prompt {} failed: {}", id, error);
+        Self {
+            id,
+            answer: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+// Flushed per line, so results can be followed while the batch runs
+fn write_result(writer: &mut dyn Write, result: &BatchResult) -> Result<(), AppError> {
+    writeln!(writer, "{}", serde_json::to_string(result)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// The whole of stdin, for `--print` without a prompt argument
+pub fn read_stdin_prompt() -> Result<String, AppError> {
+    let mut prompt = String::new();
+    io::stdin().read_to_string(&mut prompt)?;
+    if prompt.trim().is_empty() {
+        return Err(AppError::CommandError(
+            "--print needs a prompt, as an argument or on stdin".to_string(),
+        ));
+    }
+    Ok(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(outcomes: &[ToolOutcome]) -> Answer {
+        Answer {
+            conversation_id: "c1".to_string(),
+            response: Some("done".to_string()),
+            tool_calls: outcomes
+                .iter()
+                .map(|&outcome| ToolTrace {
+                    tool: "shell_tool".to_string(),
+                    arguments: JsonValue::Null,
+                    outcome,
+                    result: String::new(),
+                })
+                .collect(),
+            usage: Usage::default(),
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(answer(&[]).exit_code(), EXIT_OK);
+        assert_eq!(
+            answer(&[ToolOutcome::Ran, ToolOutcome::Failed]).exit_code(),
+            EXIT_OK
+        );
+        assert_eq!(
+            answer(&[ToolOutcome::Ran, ToolOutcome::Rejected]).exit_code(),
+            EXIT_DENIED
+        );
+    }
+
+    #[test]
+    fn test_batch_result_lines() {
+        let result = BatchResult {
+            id: JsonValue::from("a"),
+            answer: Some(answer(&[ToolOutcome::Ran])),
+            error: None,
+        };
+        let line: JsonValue = serde_json::to_value(&result).unwrap();
+        assert_eq!(line["id"], "a");
+        assert_eq!(line["response"], "done");
+        assert_eq!(line["tool_calls"][0]["outcome"], "ran");
+        assert_eq!(line["usage"]["total_tokens"], 0);
+        assert!(line.get("error").is_none());
+
+        let failed = BatchResult::failed(
+            JsonValue::from(2),
+            AppError::CommandError("boom".to_string()),
+        );
+        let line: JsonValue = serde_json::to_value(&failed).unwrap();
+        assert_eq!(line["error"], "Error with command: boom");
+        assert!(line.get("response").is_none());
+
+        assert!(serde_json::from_str::<BatchPrompt>(r#"{"prompt": "hi"}"#).is_ok());
+        assert!(serde_json::from_str::<BatchPrompt>(r#"{"promt": "hi"}"#).is_err());
+    }
+}
@@ -1,141 +1,586 @@
-use crossterm::style::Color;
-use proc_macro_crate::auto_register_tools;
-
 pub mod command_handler;
+pub mod context_manager;
+pub mod conversation_browser;
+pub mod conversation_exporter;
 pub mod conversation_manager;
+pub mod file_changes;
+pub mod headless;
+pub mod output;
 
 use crate::{
-    api::openai_service::OpenAIService,
-    models::types::{AppError, Message, OpenAIResponse, ToolCall},
+    api::provider::{LlmProvider, ToolOffer},
+    approval::{describe_edit, policy::ApprovalPolicy, ApprovalHandler, Approver, Verdict},
+    config::settings,
+    models::{
+        traits::ToolContext,
+        types::{AppError, Message, OpenAIResponse, ToolCall, Usage},
+    },
     registry::tool_registry::ToolRegistry,
-    utils::common::{print_assistant_reply, print_colorful, request_tool_call_approval},
 };
+use context_manager::ContextManager;
 use conversation_manager::ConversationManager;
+use headless::{Answer, ToolOutcome, ToolTrace};
+use output::{AssistantEvent, OutputSink};
 
 use lazy_static::lazy_static;
-use reqwest::Client;
 use serde_json::Value as JsonValue;
+use std::sync::Arc;
 
 use self::command_handler::CommandHandler;
 
-auto_register_tools!();
-
 lazy_static! {
-    pub static ref GLOBAL_TOOL_REGISTRY: ToolRegistry = {
-        let mut registry = ToolRegistry::new();
-        register_tools(&mut registry); // Use the generated function
-        registry
-    };
+    // Every tool marked with `#[auto_register]` that the config enables
+    pub static ref GLOBAL_TOOL_REGISTRY: ToolRegistry =
+        ToolRegistry::with_enabled_tools(settings().tools.as_deref());
+}
+
+// Knobs controlling how a user turn is carried out
+pub struct AssistantOptions {
+    // Render replies delta by delta as they are generated
+    pub stream: bool,
+    // Maximum number of tool-call rounds within a single user turn
+    pub max_iterations: usize,
+    // Stop offering tools once a user turn has used this many tokens across all its calls
+    pub token_budget: Option<u32>,
+    // Estimated prompt size at which older turns get summarized
+    pub context_limit: usize,
+    // Which tool calls run without asking
+    pub approval_policy: ApprovalPolicy,
+    pub yolo: bool,
+    // Who is asked about calls the policy leaves to the user; nobody for --print and --batch
+    pub approval_handler: Option<Arc<dyn ApprovalHandler>>,
+    // Where replies, tool results and progress go
+    pub output: Arc<dyn OutputSink>,
+}
+
+// How a session begins: a fresh conversation, or a saved one picked up where it left off
+pub enum SessionStart {
+    New {
+        initial_prompt: String,
+        include_state: bool,
+    },
+    Resume {
+        conversation_id: String,
+        // Sent as the first turn if given; otherwise the session waits for the user
+        prompt: Option<String>,
+        // Rebuild the system prompt from the system prompt file and the current tool schemas
+        refresh_system: bool,
+        include_state: bool,
+    },
 }
 
 pub struct Assistant {
     conversation_manager: ConversationManager,
-    openai_service: OpenAIService,
+    context_manager: ContextManager,
+    provider: Box<dyn LlmProvider>,
+    tools_json: JsonValue,
     tool_registry: &'static ToolRegistry,
+    approver: Approver,
     tokens: u32,
+    // Tool calls and usage of the current turn
+    turn_trace: Vec<ToolTrace>,
+    turn_usage: Usage,
+    options: AssistantOptions,
 }
 
 impl Assistant {
-    pub fn new(client: Client, api_key: String, model: String) -> Self {
+    pub fn new(provider: Box<dyn LlmProvider>, options: AssistantOptions) -> Self {
         let tool_registry = &*GLOBAL_TOOL_REGISTRY;
         let tools_json: JsonValue = tool_registry.generate_tools_json();
         let tools_schema: String = tool_registry.generate_tools_schemas();
 
         Assistant {
-            conversation_manager: ConversationManager::new(tools_json.clone(), tools_schema),
-            openai_service: OpenAIService::new(api_key, model, client, tools_json),
+            conversation_manager: ConversationManager::new(
+                tools_json.clone(),
+                tools_schema,
+                provider.model().to_string(),
+            ),
+            context_manager: ContextManager::new(options.context_limit),
+            provider,
+            tools_json,
             tool_registry,
+            approver: Approver::new(
+                options.approval_policy.clone(),
+                options.yolo,
+                options.approval_handler.clone(),
+            ),
             tokens: 0,
+            turn_trace: Vec::new(),
+            turn_usage: Usage::default(),
+            options,
         }
     }
 
-    pub async fn get_response(&mut self, include_tools: bool) -> Result<OpenAIResponse, AppError> {
-        let response: OpenAIResponse = self
-            .openai_service
-            .call_openai_api(&self.conversation_manager, include_tools)
-            .await?;
-        self.tokens = response.usage.total_tokens;
+    // The tools are always sent, since the history may hold tool calls; `allow_tool_calls` decides
+    // whether the model may make more
+    pub async fn get_response(
+        &mut self,
+        allow_tool_calls: bool,
+    ) -> Result<OpenAIResponse, AppError> {
+        self.compact_context(false).await?;
+
+        let response: OpenAIResponse = match self.request_completion(allow_tool_calls).await {
+            // Our estimate can undershoot; compact harder and try once more
+            Err(AppError::ContextLengthExceeded(message)) => {
+                log::warn!("Context length exceeded, compacting: {}", message);
+                if !self.compact_context(true).await? {
+                    return Err(AppError::ContextLengthExceeded(message));
+                }
+                self.request_completion(allow_tool_calls).await?
+            }
+            result => result?,
+        };
+        // Some OpenAI-compatible servers do not report usage for streams, so keep the last known count
+        if response.usage.total_tokens > 0 {
+            self.tokens = response.usage.total_tokens;
+        }
+        self.turn_usage.add(&response.usage);
+        self.conversation_manager.record_usage(&response.usage);
         self.conversation_manager
             .add_message(response.choices[0].message.clone())?;
 
         Ok(response)
     }
 
-    pub async fn handle_tool_call(&mut self, tool_call: &ToolCall) -> Result<(), AppError> {
-        let function_name = &tool_call.function.name;
-        // Could we actually have the arguments directly be a JsonValue, instead of a String?
-        let arguments: JsonValue = serde_json::from_str(&tool_call.function.arguments)?;
-        let tool_result: String;
-
-        if request_tool_call_approval(&tool_call).await? {
-            match self
-                .tool_registry
-                .execute_tool(&function_name, arguments)
+    async fn request_completion(
+        &mut self,
+        allow_tool_calls: bool,
+    ) -> Result<OpenAIResponse, AppError> {
+        if self.options.stream {
+            self.get_streamed_response(allow_tool_calls).await
+        } else {
+            let tools = ToolOffer::new(&self.tools_json, allow_tool_calls);
+            self.provider
+                .complete(&self.conversation_manager.prompt(), Some(tools))
                 .await
-            {
-                Ok(result) => {
-                    let tool_call_str = format!("{:?}\n=>\n{}\n", tool_call, result);
-                    log::info!("Succesfully executed tool call: {}", tool_call_str);
-                    print_colorful(&tool_call_str, Color::DarkMagenta)?;
-                    tool_result = result;
-                }
-                Err(e) => {
-                    let error_str =
-                        format!("Error executing tool call: {:?}\n=>\n{}", tool_call, e);
-                    log::warn!("{}", error_str);
-                    print_colorful(&error_str, Color::Red)?;
-                    tool_result = e.to_string();
-                }
+        }
+    }
+
+    // Summarize older turns if the prompt is close to the context limit; returns whether it did
+    async fn compact_context(&mut self, force: bool) -> Result<bool, AppError> {
+        let compacted = self
+            .context_manager
+            .compact(
+                &self.conversation_manager.prompt(),
+                Some(&self.tools_json),
+                self.provider.as_ref(),
+                force,
+            )
+            .await?;
+
+        match compacted {
+            Some(compacted) => {
+                self.conversation_manager.compact_prompt(compacted);
+                Ok(true)
             }
-        } else {
-            let error_str = format!("User rejected tool call: {:?}", tool_call);
-            log::warn!("{}", error_str);
-            print_colorful(&error_str, Color::DarkRed)?;
-            tool_result = error_str;
+            None => Ok(false),
         }
+    }
+
+    // Pass content deltas on as they arrive
+    async fn get_streamed_response(
+        &mut self,
+        allow_tool_calls: bool,
+    ) -> Result<OpenAIResponse, AppError> {
+        let output = self.options.output.clone();
+        let mut on_content = |delta: &str| -> Result<(), AppError> {
+            output.emit(AssistantEvent::Delta {
+                content: delta.to_string(),
+            })
+        };
 
-        self.conversation_manager.add_message(Message {
+        let tools = ToolOffer::new(&self.tools_json, allow_tool_calls);
+        self.provider
+            .complete_stream(
+                &self.conversation_manager.prompt(),
+                Some(tools),
+                &mut on_content,
+            )
+            .await
+    }
+
+    fn emit(&self, event: AssistantEvent) -> Result<(), AppError> {
+        self.options.output.emit(event)
+    }
+
+    // Every tool call gets a `tool` reply, whatever goes wrong with it; a call left unanswered
+    // makes the provider reject every later request in the conversation
+    pub async fn handle_tool_call(&mut self, tool_call: &ToolCall) -> Result<(), AppError> {
+        let (outcome, tool_result) = self.run_tool_call(tool_call).await;
+        self.answer_tool_call(tool_call, outcome, tool_result)
+    }
+
+    // Add the `tool` reply to the conversation, then trace and report the call. The reply stays in
+    // the conversation even if saving it fails, and the call is still reported.
+    fn answer_tool_call(
+        &mut self,
+        tool_call: &ToolCall,
+        outcome: ToolOutcome,
+        tool_result: String,
+    ) -> Result<(), AppError> {
+        let function_name = &tool_call.function.name;
+        let saved = self.conversation_manager.add_message(Message {
             role: "tool".to_string(),
-            content: Some(tool_result),
+            content: Some(tool_result.clone()),
             tool_calls: None,
             tool_call_id: Some(tool_call.id.clone()),
             name: Some(function_name.to_string()),
+        });
+
+        // Arguments that aren't valid JSON are reported as the raw string
+        let arguments = serde_json::from_str(&tool_call.function.arguments)
+            .unwrap_or_else(|_| JsonValue::String(tool_call.function.arguments.clone()));
+        self.turn_trace.push(ToolTrace {
+            tool: function_name.to_string(),
+            arguments: arguments.clone(),
+            outcome,
+            result: tool_result.clone(),
+        });
+        self.emit(AssistantEvent::ToolResult {
+            call_id: tool_call.id.clone(),
+            tool: function_name.to_string(),
+            arguments,
+            outcome,
+            result: tool_result,
         })?;
+        saved
+    }
 
-        Ok(())
+    // Parse, validate, review and execute a tool call, returning the result to send back to the
+    // model. Failures at any step become the result.
+    async fn run_tool_call(&mut self, tool_call: &ToolCall) -> (ToolOutcome, String) {
+        let function_name = &tool_call.function.name;
+
+        // Malformed calls go straight back to the model instead of to the user for approval.
+        // Arguments cut off at max_tokens aren't valid JSON.
+        let arguments = match serde_json::from_str(&tool_call.function.arguments) {
+            Ok(arguments) => arguments,
+            Err(e) => {
+                let error = AppError::CommandError(format!(
+                    "The arguments aren't valid JSON ({}). If they were cut short, split the work into smaller calls.",
+                    e
+                ));
+                return (ToolOutcome::Invalid, report_tool_error(tool_call, error));
+            }
+        };
+        let arguments = match self
+            .tool_registry
+            .validate_arguments(function_name, arguments)
+        {
+            Ok(arguments) => arguments,
+            Err(e) => return (ToolOutcome::Invalid, report_tool_error(tool_call, e)),
+        };
+
+        let verdict = match self
+            .approver
+            .review(
+                self.conversation_manager.conversation_id(),
+                tool_call,
+                &arguments,
+            )
+            .await
+        {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                let error = AppError::CommandError(format!(
+                    "The call wasn't run because approving it failed: {}",
+                    e
+                ));
+                return (ToolOutcome::Rejected, report_tool_error(tool_call, error));
+            }
+        };
+        // The model is told how the user changed its call, ahead of the result
+        let (arguments, edit_note) = match verdict {
+            Verdict::Approve => (arguments, None),
+            Verdict::ApproveEdited(edited) => {
+                let note = describe_edit(&arguments, &edited);
+                (edited, Some(note))
+            }
+            Verdict::Reject(message) => {
+                log::warn!("{}", message);
+                return (ToolOutcome::Rejected, message);
+            }
+        };
+
+        let ctx = ToolContext {
+            conversation_id: Some(self.conversation_id().to_string()),
+            call_id: tool_call.id.clone(),
+        };
+        let (outcome, result) = match self
+            .tool_registry
+            .execute_tool(function_name, arguments, &ctx)
+            .await
+        {
+            Ok(result) => {
+                log::info!(
+                    "Succesfully executed tool call: {:?}\n=>\n{}",
+                    tool_call,
+                    result
+                );
+                (ToolOutcome::Ran, result)
+            }
+            Err(e) => (ToolOutcome::Failed, report_tool_error(tool_call, e)),
+        };
+
+        let result = match edit_note {
+            Some(note) => format!("{}\n\n{}", note, result),
+            None => result,
+        };
+        (outcome, result)
     }
 
-    pub async fn run(
-        &mut self,
-        initial_prompt: String,
-        include_state: bool,
-    ) -> Result<(), AppError> {
-        // Initialize conversations with system message (optionally including state) and first user message
-        self.conversation_manager
-            .initialize_conversation(initial_prompt, include_state)?;
+    pub async fn run(&mut self, start: SessionStart) -> Result<(), AppError> {
+        let mut pending_turn = self.start_session(start)?;
 
         // Assistant main loop
         loop {
-            // Get a response including possible tool calls
-            let mut response: OpenAIResponse = self.get_response(true).await?;
+            if pending_turn {
+                // A failed API call should not end the session; report it and let the user carry on
+                if let Err(e) = self.run_turn().await {
+                    if matches!(e, AppError::AuthenticationError(_)) {
+                        return Err(e);
+                    }
+                    self.report_turn_error(&e)?;
+                }
+            }
+
+            CommandHandler::get_user_prompt(&mut self.conversation_manager, self.tokens)?;
+            pending_turn = true;
+        }
+    }
 
-            if let Some(tool_calls) = &response.choices[0].message.tool_calls {
-                for tool_call in tool_calls {
-                    self.handle_tool_call(tool_call).await?;
+    // Carry out a single turn and return the reply, for --print and --batch
+    pub async fn answer(&mut self, start: SessionStart) -> Result<Answer, AppError> {
+        if !self.start_session(start)? {
+            return Err(AppError::CommandError(
+                "There is no prompt to answer".to_string(),
+            ));
+        }
+        self.respond().await
+    }
+
+    // Reply to the user prompt at the end of the conversation
+    pub async fn respond(&mut self) -> Result<Answer, AppError> {
+        let response = self.run_turn().await?;
+        Ok(Answer {
+            conversation_id: self.conversation_manager.conversation_id().to_string(),
+            response,
+            tool_calls: std::mem::take(&mut self.turn_trace),
+            usage: std::mem::take(&mut self.turn_usage),
+        })
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        self.conversation_manager.conversation_id()
+    }
+
+    pub fn add_user_prompt(&mut self, prompt: String) -> Result<(), AppError> {
+        self.conversation_manager.add_user_prompt(prompt)
+    }
+
+    // Set up the conversation; returns whether there is a user prompt waiting for a reply
+    pub fn start_session(&mut self, start: SessionStart) -> Result<bool, AppError> {
+        match start {
+            SessionStart::New {
+                initial_prompt,
+                include_state,
+            } => {
+                // Initialize conversations with system message (optionally including state) and first user message
+                self.conversation_manager
+                    .initialize_conversation(initial_prompt, include_state)?;
+                self.emit(AssistantEvent::ConversationStarted {
+                    conversation_id: self.conversation_id().to_string(),
+                })?;
+                Ok(true)
+            }
+            SessionStart::Resume {
+                conversation_id,
+                prompt,
+                refresh_system,
+                include_state,
+            } => {
+                self.conversation_manager.resume_conversation(
+                    &conversation_id,
+                    refresh_system,
+                    include_state,
+                )?;
+                // No call has reported usage yet, so show an estimate of the restored history
+                self.tokens = self
+                    .context_manager
+                    .estimate_prompt(&self.conversation_manager.messages, Some(&self.tools_json))
+                    as u32;
+
+                // Remind the user where things were left off
+                let last_reply = self
+                    .conversation_manager
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|message| message.role == "assistant")
+                    .and_then(|message| message.content.clone());
+                let metadata = &self.conversation_manager.metadata;
+                self.emit(AssistantEvent::ConversationResumed {
+                    conversation_id: metadata.id.clone(),
+                    title: metadata.title.clone(),
+                    messages: self.conversation_manager.messages.len(),
+                    last_reply,
+                })?;
+
+                match prompt {
+                    Some(prompt) => {
+                        self.conversation_manager.add_user_prompt(prompt)?;
+                        Ok(true)
+                    }
+                    None => Ok(false),
                 }
+            }
+        }
+    }
 
-                // Now let Assistant generate response using the tool call results
-                response = self.get_response(false).await?;
+    // Keep calling the model with tools enabled until it answers with a plain message, and return
+    // that message. Once the iteration limit or token budget is hit, one last call is made with
+    // tool calls turned off to force a reply.
+    async fn run_turn(&mut self) -> Result<Option<String>, AppError> {
+        // Only the last turn's calls and usage are kept, whether or not an Answer takes them
+        self.turn_trace.clear();
+        self.turn_usage = Usage::default();
+        let mut iteration: usize = 0;
+        let mut turn_tokens: u32 = 0;
+
+        loop {
+            let budget_exhausted = self
+                .options
+                .token_budget
+                .is_some_and(|budget| turn_tokens >= budget);
+            let allow_tool_calls = iteration < self.options.max_iterations && !budget_exhausted;
+
+            let response: OpenAIResponse = self.get_response(allow_tool_calls).await?;
+            turn_tokens += response.usage.total_tokens;
+            let message = response.choices[0].message.clone();
+
+            if let Some(content) = &message.content {
+                self.emit(AssistantEvent::Reply {
+                    content: content.clone(),
+                    streamed: self.options.stream,
+                })?;
             }
 
-            match &response.choices[0].message.content {
-                Some(content) => {
-                    print_assistant_reply(&content)?;
+            let tool_calls = match message.tool_calls {
+                Some(tool_calls) if !tool_calls.is_empty() => tool_calls,
+                _ => return Ok(message.content),
+            };
+
+            // Not every provider honours tool_choice. The calls still need answers for the
+            // history to stay valid, but the turn ends here.
+            if !allow_tool_calls {
+                self.emit(AssistantEvent::Warning {
+                    message: format!(
+                        "The model asked for {} tool call(s) after the limit for this turn; they weren't run",
+                        tool_calls.len()
+                    ),
+                })?;
+                // Every call is answered before an error ends the turn
+                let mut error = None;
+                for tool_call in &tool_calls {
+                    if let Err(e) = self.answer_tool_call(
+                        tool_call,
+                        ToolOutcome::Rejected,
+                        "Not run: the tool call limit for this turn was reached".to_string(),
+                    ) {
+                        error.get_or_insert(e);
+                    }
                 }
-                None => {}
+                return match error {
+                    Some(e) => Err(e),
+                    None => Ok(message.content),
+                };
             }
 
-            CommandHandler::get_user_prompt(&mut self.conversation_manager, self.tokens)?;
+            iteration += 1;
+            // Every call is answered before an error, such as a failed save, ends the turn
+            let mut error = None;
+            for tool_call in &tool_calls {
+                if let Err(e) = self.handle_tool_call(tool_call).await {
+                    error.get_or_insert(e);
+                }
+            }
+            if let Some(e) = error {
+                return Err(e);
+            }
+
+            self.print_iteration_summary(
+                iteration,
+                &tool_calls,
+                response.usage.total_tokens,
+                turn_tokens,
+            )?;
         }
     }
+
+    fn report_turn_error(&self, error: &AppError) -> Result<(), AppError> {
+        log::error!("Turn failed: {}", error);
+        let hint = match error {
+            AppError::ContextLengthExceeded(_) => {
+                " (the conversation is too long even after compaction; try a larger --context-limit or `load` another conversation)"
+            }
+            _ if error.is_transient() => " (retries exhausted; send another prompt to try again)",
+            _ => "",
+        };
+        self.emit(AssistantEvent::Error {
+            message: format!("{}{}", error, hint),
+        })
+    }
+
+    fn print_iteration_summary(
+        &self,
+        iteration: usize,
+        tool_calls: &[ToolCall],
+        call_tokens: u32,
+        turn_tokens: u32,
+    ) -> Result<(), AppError> {
+        let tool_names: Vec<&str> = tool_calls
+            .iter()
+            .map(|tool_call| tool_call.function.name.as_str())
+            .collect();
+        let budget = match self.options.token_budget {
+            Some(budget) => format!("{}/{}", turn_tokens, budget),
+            None => turn_tokens.to_string(),
+        };
+        let summary = format!(
+            "[iteration {}/{}] {} tool call(s): {} | {} tokens this call, {} this turn",
+            iteration,
+            self.options.max_iterations,
+            tool_calls.len(),
+            tool_names.join(", "),
+            call_tokens,
+            budget
+        );
+        log::info!("{}", summary);
+        self.emit(AssistantEvent::Notice { message: summary })?;
+
+        let warning = if iteration >= self.options.max_iterations {
+            Some("Reached the maximum number of tool iterations, asking for a final answer")
+        } else if self
+            .options
+            .token_budget
+            .is_some_and(|budget| turn_tokens >= budget)
+        {
+            Some("Token budget for this turn exhausted, asking for a final answer")
+        } else {
+            None
+        };
+        if let Some(message) = warning {
+            self.emit(AssistantEvent::Warning {
+                message: message.to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+// Log a failed tool call and return the error as the tool result, so the model can react to it
+fn report_tool_error(tool_call: &ToolCall, e: AppError) -> String {
+    log::warn!("Error executing tool call: {:?}\n=>\n{}", tool_call, e);
+    e.to_string()
 }
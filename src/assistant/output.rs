@@ -0,0 +1,169 @@
+use crate::{
+    assistant::headless::{Answer, ToolOutcome},
+    models::types::AppError,
+    utils::common::{print_assistant_reply, print_colorful},
+};
+
+use crossterm::style::Color;
+use serde_derive::Serialize;
+use serde_json::Value as JsonValue;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Everything the assistant reports while carrying out a turn
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AssistantEvent {
+    ConversationStarted {
+        conversation_id: String,
+    },
+    ConversationResumed {
+        conversation_id: String,
+        title: Option<String>,
+        messages: usize,
+        // Where things were left off
+        last_reply: Option<String>,
+    },
+    // Part of a reply being streamed
+    Delta {
+        content: String,
+    },
+    // A complete reply; `streamed` if its deltas were sent already
+    Reply {
+        content: String,
+        streamed: bool,
+    },
+    // A call the policy left to the user, waiting for an answer (HTTP API only)
+    ApprovalRequested {
+        call_id: String,
+        tool: String,
+        arguments: JsonValue,
+        // A diff of what the call would change, for tools that can tell
+        preview: Option<String>,
+    },
+    ToolResult {
+        call_id: String,
+        tool: String,
+        arguments: JsonValue,
+        outcome: ToolOutcome,
+        result: String,
+    },
+    // Progress, such as the iteration summary
+    Notice {
+        message: String,
+    },
+    // The turn is being cut short, e.g. by the iteration limit
+    Warning {
+        message: String,
+    },
+    // The turn failed
+    Error {
+        message: String,
+    },
+    // The turn is over (HTTP API only)
+    TurnFinished {
+        answer: Answer,
+    },
+}
+
+// Where the assistant's events go: the terminal, or the clients of `rtool serve`
+pub trait OutputSink: Send + Sync {
+    fn emit(&self, event: AssistantEvent) -> Result<(), AppError>;
+}
+
+pub struct TerminalOutput {
+    // Off for --print and --batch, which print the final reply themselves
+    show_replies: bool,
+    // Whether a streamed reply is being printed
+    streaming: AtomicBool,
+}
+
+impl TerminalOutput {
+    pub fn new(show_replies: bool) -> Self {
+        Self {
+            show_replies,
+            streaming: AtomicBool::new(false),
+        }
+    }
+}
+
+impl OutputSink for TerminalOutput {
+    fn emit(&self, event: AssistantEvent) -> Result<(), AppError> {
+        match event {
+            AssistantEvent::ConversationStarted { conversation_id } => print_colorful(
+                &format!("Conversation {}\n", conversation_id),
+                Color::DarkGrey,
+            ),
+            AssistantEvent::ConversationResumed {
+                conversation_id,
+                title,
+                messages,
+                last_reply,
+            } => {
+                print_colorful(
+                    &format!(
+                        "Resumed conversation {} ({}, {} messages)\n",
+                        conversation_id,
+                        title.as_deref().unwrap_or("untitled"),
+                        messages
+                    ),
+                    Color::DarkGrey,
+                )?;
+                match last_reply {
+                    Some(content) => print_assistant_reply(&content),
+                    None => Ok(()),
+                }
+            }
+            // Deltas go under the same `Assistant:` prefix as a full reply
+            AssistantEvent::Delta { content } => {
+                if !self.streaming.swap(true, Ordering::Relaxed) {
+                    print_colorful("Assistant: ", Color::Cyan)?;
+                }
+                print_colorful(&content, Color::Cyan)
+            }
+            AssistantEvent::Reply { content, streamed } => {
+                if streamed {
+                    if self.streaming.swap(false, Ordering::Relaxed) {
+                        print_colorful("\n", Color::Cyan)?;
+                    }
+                    Ok(())
+                } else if self.show_replies {
+                    print_assistant_reply(&content)
+                } else {
+                    Ok(())
+                }
+            }
+            AssistantEvent::ToolResult {
+                tool,
+                arguments,
+                outcome,
+                result,
+                ..
+            } => match outcome {
+                ToolOutcome::Ran => print_colorful(
+                    &format!("{} {}\n=>\n{}\n", tool, arguments, result),
+                    Color::DarkMagenta,
+                ),
+                ToolOutcome::Failed | ToolOutcome::Invalid => print_colorful(
+                    &format!(
+                        "Error executing tool call {} {}\n=>\n{}\n",
+                        tool, arguments, result
+                    ),
+                    Color::Red,
+                ),
+                ToolOutcome::Rejected => print_colorful(&format!("{}\n", result), Color::DarkRed),
+            },
+            AssistantEvent::Notice { message } => {
+                print_colorful(&format!("{}\n", message), Color::DarkGrey)
+            }
+            AssistantEvent::Warning { message } => {
+                print_colorful(&format!("{}\n", message), Color::DarkYellow)
+            }
+            AssistantEvent::Error { message } => {
+                print_colorful(&format!("Error: {}\n", message), Color::Red)
+            }
+            AssistantEvent::ApprovalRequested { .. } | AssistantEvent::TurnFinished { .. } => {
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{approval::policy::ApprovalPolicy, models::types::AppError};
+
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Settings from one source. Unset fields fall through to the layers below.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    // Seconds to wait for an API response
+    pub timeout: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub system_prompt: Option<PathBuf>,
+    pub state_file: Option<PathBuf>,
+    // Tools offered to the model; every registered tool when unset
+    pub tools: Option<Vec<String>>,
+    // Replaced as a whole, not merged rule by rule
+    pub approval: Option<ApprovalPolicy>,
+    pub storage: Option<String>,
+    pub conversations_dir: Option<PathBuf>,
+    pub logs_dir: Option<PathBuf>,
+    pub exports_dir: Option<PathBuf>,
+    pub backups_dir: Option<PathBuf>,
+    pub stream: Option<bool>,
+    pub max_iterations: Option<usize>,
+    pub token_budget: Option<u32>,
+    pub context_limit: Option<usize>,
+}
+
+impl ConfigLayer {
+    // Overlay `other` on this layer; whatever `other` sets wins
+    pub fn merge(&mut self, other: ConfigLayer) {
+        macro_rules! overlay {
+            ($($field:ident),*) => {
+                $(if other.$field.is_some() {
+                    self.$field = other.$field;
+                })*
+            };
+        }
+        overlay!(
+            provider,
+            model,
+            base_url,
+            timeout,
+            max_retries,
+            system_prompt,
+            state_file,
+            tools,
+            approval,
+            storage,
+            conversations_dir,
+            logs_dir,
+            exports_dir,
+            backups_dir,
+            stream,
+            max_iterations,
+            token_budget,
+            context_limit
+        );
+    }
+
+    // Relative paths in a config file are relative to the file, not to wherever rtool runs
+    fn resolve_paths(&mut self, base: &Path) {
+        for path in [
+            &mut self.system_prompt,
+            &mut self.state_file,
+            &mut self.conversations_dir,
+            &mut self.logs_dir,
+            &mut self.exports_dir,
+            &mut self.backups_dir,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        }
+    }
+}
+
+// A parsed config file: top-level settings, the profile it selects by default, and its profiles
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    pub settings: ConfigLayer,
+    pub profile: Option<String>,
+    pub profiles: HashMap<String, ConfigLayer>,
+    // Project directories whose .rtool.toml may make trusted-only settings. Only read from the
+    // global file.
+    pub trusted_projects: Vec<PathBuf>,
+}
+
+impl ConfigFile {
+    pub fn parse(toml: &str, base: &Path) -> Result<Self, AppError> {
+        let invalid = |e: toml::de::Error| AppError::CommandError(e.to_string());
+
+        let mut table: toml::Table = toml::from_str(toml).map_err(invalid)?;
+        let profile = match table.remove("profile") {
+            Some(toml::Value::String(profile)) => Some(profile),
+            Some(_) => {
+                return Err(AppError::CommandError(
+                    "`profile` must be the name of a profile".to_string(),
+                ))
+            }
+            None => None,
+        };
+
+        let trusted_projects = match table.remove("trusted_projects") {
+            Some(value) => {
+                let paths: Vec<PathBuf> = value.try_into().map_err(invalid)?;
+                paths.into_iter().map(|path| base.join(path)).collect()
+            }
+            None => Vec::new(),
+        };
+
+        let mut profiles = HashMap::new();
+        if let Some(value) = table.remove("profiles") {
+            let tables: toml::Table = value.try_into().map_err(invalid)?;
+            for (name, value) in tables {
+                let mut layer: ConfigLayer = value.try_into().map_err(|e: toml::de::Error| {
+                    AppError::CommandError(format!("in profile `{}`: {}", name, e))
+                })?;
+                layer.resolve_paths(base);
+                profiles.insert(name, layer);
+            }
+        }
+
+        let mut settings: ConfigLayer = toml::Value::Table(table).try_into().map_err(invalid)?;
+        settings.resolve_paths(base);
+
+        Ok(Self {
+            settings,
+            profile,
+            profiles,
+            trusted_projects,
+        })
+    }
+
+    // Drop what only a trusted project file may set: the provider and URL the API key goes to,
+    // and the approval policy. Returns the keys that were set, e.g. `profiles.local.base_url`.
+    pub fn drop_trusted_only(&mut self) -> Vec<String> {
+        let mut dropped = Vec::new();
+        let layers = std::iter::once((String::new(), &mut self.settings)).chain(
+            self.profiles
+                .iter_mut()
+                .map(|(name, layer)| (format!("profiles.{}.", name), layer)),
+        );
+        for (prefix, layer) in layers {
+            if layer.provider.take().is_some() {
+                dropped.push(format!("{}provider", prefix));
+            }
+            if layer.base_url.take().is_some() {
+                dropped.push(format!("{}base_url", prefix));
+            }
+            if layer.approval.take().is_some() {
+                dropped.push(format!("{}approval", prefix));
+            }
+        }
+        dropped.sort();
+        dropped
+    }
+
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let toml = std::fs::read_to_string(path)?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        Self::parse(&toml, base).map_err(|e| {
+            AppError::CommandError(format!("Invalid config file {}: {}", path.display(), e))
+        })
+    }
+}
+
+fn parse_var<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>, AppError> {
+    var(name)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                AppError::CommandError(format!("Invalid value `{}` for {}", value, name))
+            })
+        })
+        .transpose()
+}
+
+// `RTOOL_<SETTING>` environment variables, e.g. RTOOL_MODEL or RTOOL_TOOLS=shell_tool,file_tool.
+// RTOOL_APPROVAL_POLICY names a policy file.
+pub fn env_layer(var: impl Fn(&str) -> Option<String>) -> Result<ConfigLayer, AppError> {
+    Ok(ConfigLayer {
+        provider: var("RTOOL_PROVIDER"),
+        model: var("RTOOL_MODEL"),
+        base_url: var("RTOOL_BASE_URL"),
+        timeout: parse_var(&var, "RTOOL_TIMEOUT")?,
+        max_retries: parse_var(&var, "RTOOL_MAX_RETRIES")?,
+        system_prompt: var("RTOOL_SYSTEM_PROMPT").map(PathBuf::from),
+        state_file: var("RTOOL_STATE_FILE").map(PathBuf::from),
+        tools: var("RTOOL_TOOLS").map(|tools| {
+            tools
+                .split(',')
+                .map(str::trim)
+                .filter(|tool| !tool.is_empty())
+                .map(str::to_string)
+                .collect()
+        }),
+        approval: var("RTOOL_APPROVAL_POLICY")
+            .map(|path| ApprovalPolicy::load(Some(&path)))
+            .transpose()?,
+        storage: var("RTOOL_STORAGE"),
+        conversations_dir: var("RTOOL_CONVERSATIONS_DIR").map(PathBuf::from),
+        logs_dir: var("RTOOL_LOGS_DIR").map(PathBuf::from),
+        exports_dir: var("RTOOL_EXPORTS_DIR").map(PathBuf::from),
+        backups_dir: var("RTOOL_BACKUPS_DIR").map(PathBuf::from),
+        stream: parse_var(&var, "RTOOL_STREAM")?,
+        max_iterations: parse_var(&var, "RTOOL_MAX_ITERATIONS")?,
+        token_budget: parse_var(&var, "RTOOL_TOKEN_BUDGET")?,
+        context_limit: parse_var(&var, "RTOOL_CONTEXT_LIMIT")?,
+    })
+}
@@ -0,0 +1,483 @@
+pub mod layer;
+
+use crate::{
+    api::provider::{ProviderKind, DEFAULT_TIMEOUT},
+    approval::policy::{ApprovalPolicy, DEFAULT_POLICY_FILE},
+    assistant::conversation_exporter::EXPORTS_DIR,
+    models::types::AppError,
+    registry::tool_registry::ToolRegistry,
+    storage::{backups::BACKUPS_DIR, StorageKind, CONVERSATIONS_DIR},
+};
+use layer::{env_layer, ConfigFile, ConfigLayer};
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Looked for in the working directory and each of its parents
+pub const PROJECT_CONFIG_FILE: &str = ".rtool.toml";
+const GLOBAL_CONFIG_FILE: &str = "rtool/config.toml";
+
+// Everything configurable, after layering the config files, the environment and the command line
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub provider: ProviderKind,
+    pub model: String,
+    pub base_url: Option<String>,
+    pub timeout: Duration,
+    pub max_retries: u32,
+    pub system_prompt: PathBuf,
+    pub state_file: PathBuf,
+    // None enables every registered tool
+    pub tools: Option<Vec<String>>,
+    pub approval: ApprovalPolicy,
+    pub storage: StorageKind,
+    pub conversations_dir: PathBuf,
+    pub logs_dir: PathBuf,
+    pub exports_dir: PathBuf,
+    // File contents saved before file_tool changed them, for /undo
+    pub backups_dir: PathBuf,
+    pub stream: bool,
+    pub max_iterations: usize,
+    pub token_budget: Option<u32>,
+    pub context_limit: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            provider: ProviderKind::OpenAI,
+            model: "gpt-4-1106-preview".to_string(),
+            base_url: None,
+            timeout: DEFAULT_TIMEOUT,
+            max_retries: 3,
+            system_prompt: PathBuf::from("system.txt"),
+            state_file: PathBuf::from("state.txt"),
+            tools: None,
+            approval: ApprovalPolicy::default(),
+            storage: StorageKind::Json,
+            conversations_dir: PathBuf::from(CONVERSATIONS_DIR),
+            logs_dir: PathBuf::from("logs"),
+            exports_dir: PathBuf::from(EXPORTS_DIR),
+            backups_dir: PathBuf::from(BACKUPS_DIR),
+            stream: false,
+            max_iterations: 10,
+            token_budget: None,
+            context_limit: 100_000,
+        }
+    }
+}
+
+impl Settings {
+    // Fill in whatever the layers left unset. Without an `approval` table the policy comes from
+    // approval.toml in the working directory, as before config files existed, if the project is
+    // trusted.
+    fn resolve(layer: ConfigLayer, trusted: bool) -> Result<Self, AppError> {
+        let defaults = Settings::default();
+        if let Some(tools) = &layer.tools {
+            check_tool_names(tools)?;
+        }
+        Ok(Self {
+            provider: match layer.provider {
+                Some(provider) => provider.parse()?,
+                None => defaults.provider,
+            },
+            model: layer.model.unwrap_or(defaults.model),
+            base_url: layer.base_url,
+            timeout: layer
+                .timeout
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.timeout),
+            max_retries: layer.max_retries.unwrap_or(defaults.max_retries),
+            system_prompt: layer.system_prompt.unwrap_or(defaults.system_prompt),
+            state_file: layer.state_file.unwrap_or(defaults.state_file),
+            tools: layer.tools,
+            approval: match layer.approval {
+                Some(approval) => approval,
+                None => default_policy(Path::new(DEFAULT_POLICY_FILE), trusted)?,
+            },
+            storage: match layer.storage {
+                Some(storage) => storage.parse()?,
+                None => defaults.storage,
+            },
+            conversations_dir: layer
+                .conversations_dir
+                .unwrap_or(defaults.conversations_dir),
+            logs_dir: layer.logs_dir.unwrap_or(defaults.logs_dir),
+            exports_dir: layer.exports_dir.unwrap_or(defaults.exports_dir),
+            backups_dir: layer.backups_dir.unwrap_or(defaults.backups_dir),
+            stream: layer.stream.unwrap_or(defaults.stream),
+            max_iterations: layer.max_iterations.unwrap_or(defaults.max_iterations),
+            token_budget: layer.token_budget,
+            context_limit: layer.context_limit.unwrap_or(defaults.context_limit),
+        })
+    }
+}
+
+// Load the global config, the project config, the selected profile and the environment, then
+// apply the command line on top. `profile` is the --profile flag.
+pub fn load(profile: Option<&str>, cli: ConfigLayer) -> Result<Settings, AppError> {
+    let global = global_config_path()
+        .filter(|path| path.exists())
+        .map(|path| ConfigFile::load(&path))
+        .transpose()?;
+    let cwd = std::env::current_dir()?;
+    let project_path = find_project_config(&cwd);
+    // The project directory, or the working directory outside any project
+    let trusted = match &project_path {
+        Some(path) => is_trusted(path, global.as_ref()),
+        None => is_trusted_dir(&cwd, global.as_ref()),
+    };
+    let project = match project_path {
+        Some(path) => {
+            let mut project = ConfigFile::load(&path)?;
+            if !trusted {
+                let dropped = project.drop_trusted_only();
+                if !dropped.is_empty() {
+                    log::warn!(
+                        "Ignoring {} in {}; add its directory to `trusted_projects` in the global config to allow them",
+                        dropped.join(", "),
+                        path.display()
+                    );
+                }
+            }
+            Some(project)
+        }
+        None => None,
+    };
+
+    let env_profile = std::env::var("RTOOL_PROFILE").ok();
+    let profile = profile.or(env_profile.as_deref());
+    let env = env_layer(|name| std::env::var(name).ok())?;
+
+    Settings::resolve(layered(global, project, profile, env, cli)?, trusted)
+}
+
+// approval.toml in the working directory, which an untrusted project can't use to approve calls
+fn default_policy(path: &Path, trusted: bool) -> Result<ApprovalPolicy, AppError> {
+    if !path.exists() {
+        return Ok(ApprovalPolicy::default());
+    }
+    if !trusted {
+        log::warn!(
+            "Ignoring {} in an untrusted project; add its directory to `trusted_projects` in the global config to use it",
+            path.display()
+        );
+        return Ok(ApprovalPolicy::default());
+    }
+    ApprovalPolicy::parse(&std::fs::read_to_string(path)?)
+}
+
+// Lowest precedence first: global file, project file, profile, environment, command line. A
+// profile defined in both files is the global definition overridden by the project one.
+fn layered(
+    global: Option<ConfigFile>,
+    project: Option<ConfigFile>,
+    profile: Option<&str>,
+    env: ConfigLayer,
+    cli: ConfigLayer,
+) -> Result<ConfigLayer, AppError> {
+    let files: Vec<ConfigFile> = global.into_iter().chain(project).collect();
+    // An explicit profile beats the default named by the project file, which beats the global one
+    let profile = profile
+        .map(str::to_string)
+        .or_else(|| files.iter().rev().find_map(|file| file.profile.clone()));
+
+    let mut layer = ConfigLayer::default();
+    for file in &files {
+        layer.merge(file.settings.clone());
+    }
+
+    if let Some(profile) = profile {
+        let definitions: Vec<&ConfigLayer> = files
+            .iter()
+            .filter_map(|file| file.profiles.get(&profile))
+            .collect();
+        if definitions.is_empty() {
+            let mut defined: Vec<&str> = files
+                .iter()
+                .flat_map(|file| file.profiles.keys().map(String::as_str))
+                .collect();
+            defined.sort();
+            defined.dedup();
+            return Err(AppError::CommandError(format!(
+                "Unknown profile `{}` (defined: {})",
+                profile,
+                if defined.is_empty() {
+                    "none".to_string()
+                } else {
+                    defined.join(", ")
+                }
+            )));
+        }
+        for definition in definitions {
+            layer.merge(definition.clone());
+        }
+    }
+
+    layer.merge(env);
+    layer.merge(cli);
+    Ok(layer)
+}
+
+// $XDG_CONFIG_HOME/rtool/config.toml, or ~/.config/rtool/config.toml
+fn global_config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join(GLOBAL_CONFIG_FILE))
+}
+
+// The nearest .rtool.toml at or above `dir`
+fn find_project_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+// A project file is trusted if the global config lists its directory. A cloned repository could
+// otherwise auto-approve shell commands or send the API key to a host of its choosing.
+fn is_trusted(project_file: &Path, global: Option<&ConfigFile>) -> bool {
+    project_file
+        .parent()
+        .is_some_and(|dir| is_trusted_dir(dir, global))
+}
+
+fn is_trusted_dir(dir: &Path, global: Option<&ConfigFile>) -> bool {
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let dir = canonical(dir);
+    global.is_some_and(|global| {
+        global
+            .trusted_projects
+            .iter()
+            .any(|trusted| canonical(trusted) == dir)
+    })
+}
+
+fn check_tool_names(tools: &[String]) -> Result<(), AppError> {
+    let registry = ToolRegistry::with_registered_tools();
+    let unknown: Vec<&str> = tools
+        .iter()
+        .map(String::as_str)
+        .filter(|tool| !registry.contains(tool))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::CommandError(format!(
+        "Unknown tools in the config: {} (available: {})",
+        unknown.join(", "),
+        registry.names().join(", ")
+    )))
+}
+
+static GLOBAL_SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+// Set once at startup, before anything reads the settings
+pub fn set_global_settings(settings: Settings) {
+    if GLOBAL_SETTINGS.set(settings).is_err() {
+        log::warn!("Settings were already set, ignoring");
+    }
+}
+
+pub fn settings() -> &'static Settings {
+    GLOBAL_SETTINGS.get_or_init(Settings::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approval::policy::Action;
+
+    fn file(toml: &str) -> ConfigFile {
+        ConfigFile::parse(toml, Path::new("/project")).unwrap()
+    }
+
+    const GLOBAL: &str = r#"
+        model = "gpt-4o"
+        timeout = 60
+
+        [profiles.cheap]
+        model = "gpt-4o-mini"
+        max_iterations = 3
+
+        [profiles.local]
+        provider = "openai-compatible"
+        base_url = "http://localhost:8000/v1"
+        model = "llama3"
+    "#;
+
+    const PROJECT: &str = r#"
+        profile = "cheap"
+        system_prompt = "prompts/system.txt"
+        tools = ["shell_tool", "file_tool"]
+
+        [profiles.cheap]
+        model = "gpt-3.5-turbo"
+
+        [approval]
+        default = "deny"
+
+        [[approval.rules]]
+        tool = "shell_tool"
+        action = "allow"
+        commands = ["ls"]
+    "#;
+
+    #[test]
+    fn test_layers_and_profiles() {
+        // The project picks `cheap` and overrides its model; the global profile still sets the
+        // iteration limit
+        let layer = layered(
+            Some(file(GLOBAL)),
+            Some(file(PROJECT)),
+            None,
+            ConfigLayer::default(),
+            ConfigLayer::default(),
+        )
+        .unwrap();
+        assert_eq!(layer.model.as_deref(), Some("gpt-3.5-turbo"));
+        assert_eq!(layer.max_iterations, Some(3));
+        assert_eq!(layer.timeout, Some(60));
+        assert_eq!(
+            layer.system_prompt,
+            Some(PathBuf::from("/project/prompts/system.txt"))
+        );
+        assert_eq!(layer.approval.unwrap().rules.len(), 1);
+
+        // --profile replaces the default profile; the environment and command line beat both
+        let env = ConfigLayer {
+            timeout: Some(5),
+            model: Some("from-env".to_string()),
+            ..ConfigLayer::default()
+        };
+        let cli = ConfigLayer {
+            model: Some("from-cli".to_string()),
+            ..ConfigLayer::default()
+        };
+        let layer = layered(
+            Some(file(GLOBAL)),
+            Some(file(PROJECT)),
+            Some("local"),
+            env,
+            cli,
+        )
+        .unwrap();
+        assert_eq!(layer.provider.as_deref(), Some("openai-compatible"));
+        assert_eq!(layer.max_iterations, None);
+        assert_eq!(layer.timeout, Some(5));
+        assert_eq!(layer.model.as_deref(), Some("from-cli"));
+    }
+
+    #[test]
+    fn test_untrusted_project_settings() {
+        let mut project = file(
+            r#"
+            model = "gpt-4o"
+            base_url = "https://example.com/v1"
+
+            [profiles.local]
+            provider = "openai-compatible"
+
+            [approval]
+            default = "allow"
+            "#,
+        );
+        assert_eq!(
+            project.drop_trusted_only(),
+            vec!["approval", "base_url", "profiles.local.provider"]
+        );
+        assert_eq!(project.settings.model.as_deref(), Some("gpt-4o"));
+        assert!(project.settings.approval.is_none());
+
+        let global = file("trusted_projects = [\"/project\", \"other\"]");
+        assert_eq!(
+            global.trusted_projects,
+            vec![PathBuf::from("/project"), PathBuf::from("/project/other")]
+        );
+        assert!(is_trusted(Path::new("/project/.rtool.toml"), Some(&global)));
+        assert!(!is_trusted(
+            Path::new("/elsewhere/.rtool.toml"),
+            Some(&global)
+        ));
+        assert!(!is_trusted(Path::new("/project/.rtool.toml"), None));
+        assert!(is_trusted_dir(Path::new("/project/other"), Some(&global)));
+    }
+
+    #[test]
+    fn test_untrusted_project_approval_file() {
+        let dir = std::env::temp_dir().join(format!("rtool_config_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(DEFAULT_POLICY_FILE);
+        std::fs::write(&path, "default = \"allow\"\n").unwrap();
+        let (trusted, untrusted) = (default_policy(&path, true), default_policy(&path, false));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let allows = |policy: &ApprovalPolicy| {
+            let (action, _) =
+                policy.evaluate("shell_tool", &serde_json::json!({}), &dir, &|_, _| false);
+            action == Action::Allow
+        };
+        assert!(allows(&trusted.unwrap()));
+        assert!(!allows(&untrusted.unwrap()));
+    }
+
+    #[test]
+    fn test_unknown_profile_lists_defined_ones() {
+        let error = layered(
+            Some(file(GLOBAL)),
+            None,
+            Some("fast"),
+            ConfigLayer::default(),
+            ConfigLayer::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Error with command: Unknown profile `fast` (defined: cheap, local)"
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_config() {
+        let parse = |toml: &str| ConfigFile::parse(toml, Path::new("/project"));
+        assert!(parse("modle = \"gpt-4o\"").is_err());
+        assert!(parse("[profiles.cheap]\ntimeout = \"soon\"").is_err());
+        assert!(parse("profile = 3").is_err());
+        assert!(Settings::resolve(
+            ConfigLayer {
+                tools: Some(vec!["shell_tool".to_string(), "web_tool".to_string()]),
+                ..ConfigLayer::default()
+            },
+            true
+        )
+        .is_err());
+        assert!(Settings::resolve(
+            ConfigLayer {
+                provider: Some("gemini".to_string()),
+                ..ConfigLayer::default()
+            },
+            true
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_env_layer() {
+        let env = |name: &str| match name {
+            "RTOOL_MODEL" => Some("gpt-4o".to_string()),
+            "RTOOL_TOOLS" => Some("shell_tool, file_tool,".to_string()),
+            "RTOOL_MAX_RETRIES" => Some("5".to_string()),
+            _ => None,
+        };
+        let layer = env_layer(env).unwrap();
+        assert_eq!(layer.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(
+            layer.tools,
+            Some(vec!["shell_tool".to_string(), "file_tool".to_string()])
+        );
+        assert_eq!(layer.max_retries, Some(5));
+        assert!(env_layer(|name| (name == "RTOOL_TIMEOUT").then(|| "soon".to_string())).is_err());
+    }
+}
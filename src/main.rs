@@ -1,41 +1,131 @@
 mod api;
+mod approval;
 mod assistant;
+mod config;
 mod models;
 mod registry;
+mod server;
+mod storage;
 mod tools;
 mod utils;
 
 use crate::models::types::AppError;
 
-use crate::assistant::Assistant;
+use crate::api::provider::{set_global_provider_config, ProviderConfig};
+use crate::approval::policy::ApprovalPolicy;
+use crate::approval::{ApprovalHandler, TerminalApprovals};
+use crate::assistant::conversation_browser;
+use crate::assistant::conversation_manager::latest_conversation_id;
+use crate::assistant::headless::{self, read_stdin_prompt, EXIT_USAGE};
+use crate::assistant::output::{OutputSink, TerminalOutput};
+use crate::assistant::{Assistant, AssistantOptions, SessionStart};
+use crate::config::layer::ConfigLayer;
+use crate::config::{set_global_settings, settings};
+use crate::storage::search::{parse_date, SearchQuery, DEFAULT_SEARCH_LIMIT};
+use crate::storage::{open_store, set_global_store};
+use crate::utils::common::{print_to_stderr, RetryPolicy};
 use clap::{Arg, ArgMatches, Command};
 use reqwest::Client;
 use simplelog::*;
-use std::env;
 use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
     let matches = parse_command_line_arguments()?;
-    setup_logging(matches.value_of("log-level"))?;
+    let interactive = !matches.is_present("print") && !matches.is_present("batch");
+    if !interactive {
+        print_to_stderr();
+    }
+
+    // Config files, the selected profile and the environment, overridden by the command line
+    set_global_settings(config::load(
+        matches.value_of("profile"),
+        command_line_layer(&matches)?,
+    )?);
+    let settings = settings();
+    setup_logging(matches.value_of("log-level"), &settings.logs_dir)?;
 
     log::info!("Logger initialized");
 
-    // Create conversation archive
-    let convs_dir = "conversations";
-    std::fs::create_dir_all(convs_dir).map_err(AppError::IOError)?;
+    // Open the conversation archive
+    set_global_store(open_store(settings.storage, &settings.conversations_dir)?);
+
+    // Conversation management subcommands don't need a provider
+    if let Some(subcommand) = matches.subcommand().filter(|(name, _)| *name != "serve") {
+        return run_subcommand(subcommand);
+    }
+
+    // Resolve the provider (API keys come from env)
+    let retry = RetryPolicy {
+        max_retries: settings.max_retries,
+        ..RetryPolicy::default()
+    };
+    let provider_config = ProviderConfig::new(
+        settings.provider,
+        settings.base_url.clone(),
+        settings.timeout,
+        retry,
+    )?;
+    set_global_provider_config(provider_config.clone());
+
+    let yolo = matches.is_present("yolo");
+    let options = move |output: Arc<dyn OutputSink>, approval_handler| AssistantOptions {
+        // Replies are printed whole once the turn is over when nobody is watching
+        stream: settings.stream && interactive,
+        max_iterations: settings.max_iterations,
+        token_budget: settings.token_budget,
+        context_limit: settings.context_limit,
+        approval_policy: settings.approval.clone(),
+        yolo,
+        approval_handler,
+        output,
+    };
+    let client = Client::new();
 
-    // Retrieve the command-line arguments and API key from env
-    let initial_prompt = matches.value_of("initial_prompt").unwrap();
-    let model = matches.value_of("model").unwrap();
-    let api_key = env::var("OPENAI_API_KEY")
-        .map_err(|_| AppError::MissingEnvironmentVariable("OPENAI_API_KEY".to_string()))?;
+    if let Some(("serve", args)) = matches.subcommand() {
+        let new_assistant: server::NewAssistant = Arc::new(move |output, approvals| {
+            Assistant::new(
+                provider_config.build(settings.model.clone(), client.clone()),
+                options(output, Some(approvals)),
+            )
+        });
+        return server::serve(args.value_of("bind").unwrap(), new_assistant).await;
+    }
 
-    let mut assistant = Assistant::new(Client::new(), api_key, model.to_string());
+    // Without a user at the terminal, calls the policy would ask about are rejected
+    let approval_handler =
+        interactive.then(|| Arc::new(TerminalApprovals) as Arc<dyn ApprovalHandler>);
+    let new_assistant = || {
+        Assistant::new(
+            provider_config.build(settings.model.clone(), client.clone()),
+            options(
+                Arc::new(TerminalOutput::new(interactive)),
+                approval_handler.clone(),
+            ),
+        )
+    };
+
+    // Each batch prompt starts a new conversation
+    if let Some(input) = matches.value_of("batch") {
+        let code = headless::run_batch(input, matches.value_of("output"), new_assistant).await;
+        std::process::exit(code);
+    }
+
+    let start = match session_start(&matches) {
+        Ok(start) => start,
+        Err(e) if !interactive => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+        Err(e) => return Err(e),
+    };
+    if !interactive {
+        std::process::exit(headless::print_answer(new_assistant(), start).await);
+    }
 
-    assistant
-        .run(initial_prompt.to_string(), matches.is_present("state"))
-        .await?;
+    new_assistant().run(start).await?;
 
     Ok(())
 }
@@ -45,25 +135,188 @@ fn parse_command_line_arguments() -> Result<ArgMatches, AppError> {
         .version("1.0.0")
         .author("Conor Mahany <conor@mahany.io>")
         .about("Console interface for AI-powered assistant")
+        .subcommand_negates_reqs(true)
+        .subcommand(Command::new("list").about("Lists saved conversations"))
+        .subcommand(
+            Command::new("serve")
+                .about("Serves an HTTP API for driving conversations, e.g. from a web UI or a bot")
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .takes_value(true)
+                        .default_value(server::DEFAULT_BIND)
+                        .help("Address to listen on"),
+                ),
+        )
+        .subcommand(
+            Command::new("show")
+                .about("Prints a saved conversation")
+                .arg(Arg::new("id").required(true).help("Conversation id or a unique fragment of it")),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Deletes a saved conversation")
+                .arg(Arg::new("id").required(true).help("Conversation id or a unique fragment of it")),
+        )
+        .subcommand(
+            Command::new("rename")
+                .about("Sets the title of a saved conversation")
+                .arg(Arg::new("id").required(true).help("Conversation id or a unique fragment of it"))
+                .arg(Arg::new("title").required(true).help("New title")),
+        )
+        .subcommand(
+            Command::new("fork")
+                .about("Copies a saved conversation up to a message into a new branch")
+                .arg(Arg::new("id").required(true).help("Conversation id or a unique fragment of it"))
+                .arg(Arg::new("index").help("Index of the last message to keep (default: the last message)")),
+        )
+        .subcommand(
+            Command::new("tree")
+                .about("Shows the branches a saved conversation belongs to")
+                .arg(Arg::new("id").required(true).help("Conversation id or a unique fragment of it")),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Exports a saved conversation as Markdown, HTML or fine-tuning JSONL")
+                .arg(Arg::new("id").required(true).help("Conversation id or a unique fragment of it"))
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(["md", "html", "jsonl"])
+                        .default_value("md")
+                        .help("Output format"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .help("File to write (default: exports/<id>.<format>)"),
+                ),
+        )
+        .subcommand(
+            Command::new("search")
+                .about("Searches the messages and tool results of saved conversations")
+                .arg(
+                    Arg::new("query")
+                        .required(true)
+                        .multiple_values(true)
+                        .help("Words that must all appear in a message"),
+                )
+                .arg(
+                    Arg::new("role")
+                        .long("role")
+                        .takes_value(true)
+                        .possible_values(["system", "user", "assistant", "tool"])
+                        .help("Only messages with this role"),
+                )
+                .arg(
+                    Arg::new("tool")
+                        .long("tool")
+                        .takes_value(true)
+                        .help("Only calls to and results from this tool"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .takes_value(true)
+                        .help("Only conversations active on or after this date (YYYY-MM-DD)"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .takes_value(true)
+                        .help("Only conversations started on or before this date (YYYY-MM-DD)"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("Maximum number of results"),
+                ),
+        )
         .arg(
             Arg::new("initial_prompt")
-                .help("Sets the initial prompt for the assistant")
-                .required(true)
+                .help("Sets the initial prompt for the assistant (optional when resuming)")
+                .required_unless_present_any(["resume", "continue-last", "print", "batch"])
                 .index(1),
         )
+        .arg(
+            Arg::new("resume")
+                .short('r')
+                .long("resume")
+                .help("Continues a saved conversation, given its id or a unique fragment of it")
+                .takes_value(true)
+                .conflicts_with("continue-last"),
+        )
+        .arg(
+            Arg::new("continue-last")
+                .short('c')
+                .long("continue-last")
+                .help("Continues the most recently updated conversation")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("refresh-system")
+                .long("refresh-system")
+                .help("When resuming, rebuilds the system prompt from the system prompt file and the current tool schemas")
+                .takes_value(false),
+        )
         .arg(
             Arg::new("model")
                 .short('m')
                 .long("model")
-                .help("Sets the model to use with the OpenAI API")
+                .help("Sets the model to use with the selected provider (default: gpt-4-1106-preview)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("provider")
+                .short('p')
+                .long("provider")
+                .help("Selects the LLM provider (default: openai)")
+                .takes_value(true)
+                .possible_values(["openai", "openai-compatible", "anthropic"])
+        )
+        .arg(
+            Arg::new("base-url")
+                .long("base-url")
+                .help("Overrides the provider's API base URL, e.g. http://localhost:8000/v1 for a local server")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .help("Seconds to wait for an API response (or between streamed chunks) (default: 120)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("max-retries")
+                .long("max-retries")
+                .help("How many times to retry rate-limited, timed-out or failed API requests (default: 3)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("storage")
+                .long("storage")
+                .help("Where conversations are kept: one JSON file each (default), or a SQLite database with full-text search")
+                .takes_value(true)
+                .possible_values(["json", "sqlite"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .help("Applies a named bundle of settings from the config files, e.g. cheap or local")
                 .takes_value(true)
-                .default_value("gpt-4-1106-preview"),
+                .global(true),
         )
         .arg(
             Arg::new("log-level")
                 .short('l')
                 .takes_value(true)
-                .possible_values(&["INFO", "DEBUG", "TRACE", "WARN", "ERROR"])
+                .possible_values(["INFO", "DEBUG", "TRACE", "WARN", "ERROR"])
                 .default_value("INFO")
                 .help("Sets the log level"),
         )
@@ -71,7 +324,64 @@ fn parse_command_line_arguments() -> Result<ArgMatches, AppError> {
             Arg::new("state")
                 .short('s')
                 .long("state")
-                .help("Appends the contents of state.txt to the initial system prompt")
+                .help("Appends the contents of the state file (default: state.txt) to the initial system prompt")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .help("Streams the assistant's replies to the terminal as they are generated")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("max-iterations")
+                .long("max-iterations")
+                .help("Maximum number of tool-call rounds per user turn (default: 10)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("token-budget")
+                .long("token-budget")
+                .help("Maximum tokens a single user turn may spend before tool calls are turned off")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("context-limit")
+                .long("context-limit")
+                .help("Model context window in tokens; older turns are summarized as the conversation nears it (default: 100000)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("approval-policy")
+                .long("approval-policy")
+                .help("TOML file of rules deciding which tool calls run without asking (default: approval.toml if present)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("print")
+                .long("print")
+                .help("Prints the answer to the prompt (read from stdin if not given) and exits instead of waiting for more input")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("batch")
+                .long("batch")
+                .help("Answers each {\"id\", \"prompt\"} line of a JSONL file (- for stdin) in a new conversation and exits")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["initial_prompt", "resume", "continue-last", "print"]),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .help("File to write --batch results to, one JSON line per prompt (default: stdout)")
+                .takes_value(true)
+                .requires("batch"),
+        )
+        .arg(
+            Arg::new("yolo")
+                .long("yolo")
+                .help("Run every tool call the approval policy doesn't deny, without asking")
                 .takes_value(false),
         )
         .get_matches();
@@ -79,8 +389,114 @@ fn parse_command_line_arguments() -> Result<ArgMatches, AppError> {
     Ok(matches)
 }
 
+// The settings given as flags. Flags without defaults tell an unset flag from one the user gave,
+// so the config files and environment apply underneath them.
+fn command_line_layer(matches: &ArgMatches) -> Result<ConfigLayer, AppError> {
+    Ok(ConfigLayer {
+        provider: matches.value_of("provider").map(str::to_string),
+        model: matches.value_of("model").map(str::to_string),
+        base_url: matches.value_of("base-url").map(str::to_string),
+        timeout: parse_number_arg(matches, "timeout")?,
+        max_retries: parse_number_arg(matches, "max-retries")?,
+        approval: matches
+            .value_of("approval-policy")
+            .map(|path| ApprovalPolicy::load(Some(path)))
+            .transpose()?,
+        storage: matches.value_of("storage").map(str::to_string),
+        stream: matches.is_present("stream").then_some(true),
+        max_iterations: parse_number_arg(matches, "max-iterations")?,
+        token_budget: parse_number_arg(matches, "token-budget")?,
+        context_limit: parse_number_arg(matches, "context-limit")?,
+        ..ConfigLayer::default()
+    })
+}
+
+// Start a new conversation from the initial prompt, or resume one (with an optional next prompt)
+fn session_start(matches: &ArgMatches) -> Result<SessionStart, AppError> {
+    let prompt = match matches.value_of("initial_prompt") {
+        Some(prompt) => Some(prompt.to_string()),
+        None if matches.is_present("print") => Some(read_stdin_prompt()?),
+        None => None,
+    };
+    let include_state = matches.is_present("state");
+
+    let conversation_id = if matches.is_present("continue-last") {
+        Some(latest_conversation_id()?)
+    } else {
+        matches.value_of("resume").map(str::to_string)
+    };
+
+    match (conversation_id, prompt) {
+        (Some(conversation_id), prompt) => Ok(SessionStart::Resume {
+            conversation_id,
+            prompt,
+            refresh_system: matches.is_present("refresh-system"),
+            include_state,
+        }),
+        (None, Some(initial_prompt)) => Ok(SessionStart::New {
+            initial_prompt,
+            include_state,
+        }),
+        (None, None) => Err(AppError::CommandError(
+            "An initial prompt is required unless --resume or --continue-last is given".to_string(),
+        )),
+    }
+}
+
+fn run_subcommand((name, args): (&str, &ArgMatches)) -> Result<(), AppError> {
+    match name {
+        "list" => conversation_browser::print_conversation_list(),
+        "show" => conversation_browser::print_conversation(args.value_of("id").unwrap()),
+        "delete" => conversation_browser::delete(args.value_of("id").unwrap()),
+        "rename" => conversation_browser::rename(
+            args.value_of("id").unwrap(),
+            args.value_of("title").unwrap(),
+        ),
+        "fork" => conversation_browser::fork(
+            args.value_of("id").unwrap(),
+            parse_number_arg(args, "index")?,
+        ),
+        "export" => conversation_browser::export(
+            args.value_of("id").unwrap(),
+            args.value_of("format").unwrap().parse()?,
+            args.value_of("output"),
+        ),
+        "search" => conversation_browser::print_search_results(&SearchQuery {
+            text: args
+                .values_of("query")
+                .unwrap()
+                .collect::<Vec<_>>()
+                .join(" "),
+            role: args.value_of("role").map(str::to_string),
+            tool: args.value_of("tool").map(str::to_string),
+            since: args.value_of("since").map(parse_date).transpose()?,
+            until: args.value_of("until").map(parse_date).transpose()?,
+            limit: parse_number_arg(args, "limit")?.unwrap_or(DEFAULT_SEARCH_LIMIT),
+        }),
+        "tree" => conversation_browser::print_conversation_tree(args.value_of("id").unwrap(), None),
+        _ => Err(AppError::CommandError(format!(
+            "Unknown subcommand `{}`",
+            name
+        ))),
+    }
+}
+
+fn parse_number_arg<T: std::str::FromStr>(
+    matches: &ArgMatches,
+    name: &str,
+) -> Result<Option<T>, AppError> {
+    matches
+        .value_of(name)
+        .map(|value| {
+            value.parse::<T>().map_err(|_| {
+                AppError::CommandError(format!("Invalid value `{}` for --{}", value, name))
+            })
+        })
+        .transpose()
+}
+
 // Logging setup function
-fn setup_logging(log_level_arg: Option<&str>) -> Result<(), AppError> {
+fn setup_logging(log_level_arg: Option<&str>, logs_dir: &Path) -> Result<(), AppError> {
     let log_level = match log_level_arg {
         Some("DEBUG") => LevelFilter::Debug,
         Some("ERROR") => LevelFilter::Error,
@@ -88,14 +504,12 @@ fn setup_logging(log_level_arg: Option<&str>) -> Result<(), AppError> {
         _ => LevelFilter::Info,
     };
 
-    let logs_dir = "logs";
     std::fs::create_dir_all(logs_dir).map_err(AppError::IOError)?;
 
     let datetime: String = chrono::Utc::now().format("%Y%m%d_%H%M%S").to_string();
-    let log_file_path = format!("{}/{}.log", logs_dir, datetime);
+    let log_file_path: PathBuf = logs_dir.join(format!("{}.log", datetime));
 
     let log_file = OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open(log_file_path)
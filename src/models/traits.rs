@@ -1,15 +1,106 @@
 use crate::models::types::AppError;
 
 use async_trait::async_trait;
+use schemars::gen::SchemaSettings;
 use schemars::schema::RootSchema;
+use schemars::JsonSchema;
 use serde_json::Value as JsonValue;
 
-#[async_trait]
-pub trait Tool: Sync + Send {
+// What the model is told about a tool. Derive it with `#[derive(Tool)]` and
+// `#[tool(name = "...", input = InputType)]` rather than writing it by hand.
+pub trait ToolSpec {
     fn name(&self) -> &'static str;
     fn description(&self) -> &'static str;
     fn parameters(&self) -> JsonValue; // JSON object representing parameters
-    async fn execute(&self, args: JsonValue) -> Result<String, AppError>;
-
     fn input_schema(&self) -> RootSchema;
 }
+
+// What a tool call is running for
+#[derive(Debug, Clone, Default)]
+pub struct ToolContext {
+    // None when the tool runs outside a conversation, as in tests
+    pub conversation_id: Option<String>,
+    pub call_id: String,
+}
+
+#[async_trait]
+pub trait Tool: ToolSpec + Sync + Send {
+    async fn execute(&self, args: JsonValue, ctx: &ToolContext) -> Result<String, AppError>;
+
+    // Whether the call only looks at things and changes nothing, so `read_only` approval rules
+    // can cover it
+    fn is_read_only(&self, _args: &JsonValue) -> bool {
+        false
+    }
+
+    // What the call would do, shown when asking the user to approve it. None if there's nothing
+    // more to show than the arguments.
+    async fn preview(&self, _args: &JsonValue) -> Option<String> {
+        None
+    }
+}
+
+// The function-calling `parameters` object for an input type: its JSON schema with every
+// definition inlined, since not every provider resolves `$ref`s
+pub fn parameters_schema<T: JsonSchema>() -> JsonValue {
+    let settings = SchemaSettings::draft07().with(|settings| {
+        settings.inline_subschemas = true;
+        // Optional fields are left out of `required` rather than made nullable
+        settings.option_add_null_type = false;
+    });
+    let schema = settings.into_generator().into_root_schema_for::<T>();
+
+    let mut parameters = serde_json::to_value(schema).unwrap_or_default();
+    if let Some(object) = parameters.as_object_mut() {
+        object.remove("$schema");
+        object.remove("title");
+        object.remove("definitions");
+    }
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Deserialize;
+    use serde_json::json;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    struct Item {
+        /// What to call it
+        name: String,
+        count: Option<usize>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    struct Input {
+        items: Vec<Item>,
+    }
+
+    #[test]
+    fn test_parameters_schema_inlines_definitions() {
+        let parameters = parameters_schema::<Input>();
+        assert_eq!(
+            parameters,
+            json!({
+                "type": "object",
+                "required": ["items"],
+                "properties": {
+                    "items": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["name"],
+                            "properties": {
+                                "name": { "description": "What to call it", "type": "string" },
+                                "count": { "type": "integer", "format": "uint", "minimum": 0.0 }
+                            }
+                        }
+                    }
+                }
+            })
+        );
+    }
+}
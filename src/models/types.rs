@@ -1,5 +1,8 @@
+use crate::registry::validation::ArgumentViolation;
+
 use serde_derive::{Deserialize, Serialize};
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct FunctionCall {
@@ -30,26 +33,103 @@ pub struct Message {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Choice {
-    index: u32,
+    pub index: u32,
     pub message: Message,
-    finish_reason: String,
+    pub finish_reason: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct Usage {
-    prompt_tokens: u32,
-    completion_tokens: u32,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
     pub total_tokens: u32,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct OpenAIResponse {
-    id: String,
-    model: String,
+    pub id: String,
+    pub model: String,
     pub choices: Vec<Choice>,
     pub usage: Usage,
 }
 
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+// Descriptive information stored alongside a conversation's messages
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConversationMetadata {
+    pub id: String,
+    pub title: Option<String>,
+    pub model: String,
+    pub created_at: String,
+    pub updated_at: String,
+    // Accumulated over every completion requested in this conversation
+    #[serde(default)]
+    pub usage: Usage,
+    // Set when this conversation was forked from another one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ForkOrigin>,
+}
+
+// The conversation and message a fork branched off from
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ForkOrigin {
+    pub conversation_id: String,
+    // Index of the last message the fork shares with its parent
+    pub message_index: usize,
+}
+
+// On-disk form of a conversation
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConversationRecord {
+    pub metadata: ConversationMetadata,
+    pub messages: Vec<Message>,
+}
+
+// Types for the `stream: true` server-sent-events format, where each event carries a delta
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub model: String,
+    #[serde(default)]
+    pub choices: Vec<ChunkChoice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkChoice {
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Delta {
+    pub role: Option<String>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub r#type: Option<String>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
 impl Message {
     pub fn new(role: String, content: String) -> Self {
         Self {
@@ -68,8 +148,42 @@ pub enum AppError {
     IOError(std::io::Error),
     SerdeJsonError(serde_json::Error),
     TaskJoinError(tokio::task::JoinError),
+    SqliteError(rusqlite::Error),
     MissingEnvironmentVariable(String),
     CommandError(String),
+    // Tool-call arguments that don't match the tool's input schema
+    InvalidArguments {
+        tool: String,
+        violations: Vec<ArgumentViolation>,
+    },
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    AuthenticationError(String),
+    ContextLengthExceeded(String),
+    ServerError {
+        status: u16,
+        message: String,
+    },
+    ApiError {
+        status: u16,
+        message: String,
+    },
+    Timeout(String),
+}
+
+impl AppError {
+    // Whether retrying the same request later has a reasonable chance of succeeding
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::RateLimited { .. } | AppError::ServerError { .. } | AppError::Timeout(_) => {
+                true
+            }
+            AppError::ReqwestError(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for AppError {
@@ -79,10 +193,39 @@ impl fmt::Display for AppError {
             AppError::IOError(e) => write!(f, "IO error: {}", e),
             AppError::SerdeJsonError(e) => write!(f, "Serialization/Deserialization error: {}", e),
             AppError::TaskJoinError(e) => write!(f, "TaskJoinError: {}", e),
+            AppError::SqliteError(e) => write!(f, "Database error: {}", e),
             AppError::MissingEnvironmentVariable(e) => {
                 write!(f, "Missing environment variable: {}", e)
             }
             AppError::CommandError(e) => write!(f, "Error with command: {}", e),
+            AppError::InvalidArguments { tool, violations } => {
+                writeln!(f, "Invalid arguments for `{}`:", tool)?;
+                for violation in violations {
+                    writeln!(f, "- {}", violation)?;
+                }
+                write!(f, "Correct the arguments and call the tool again.")
+            }
+            AppError::RateLimited {
+                message,
+                retry_after,
+            } => match retry_after {
+                Some(delay) => write!(
+                    f,
+                    "Rate limited (retry after {}s): {}",
+                    delay.as_secs_f32(),
+                    message
+                ),
+                None => write!(f, "Rate limited: {}", message),
+            },
+            AppError::AuthenticationError(e) => write!(f, "Authentication failed: {}", e),
+            AppError::ContextLengthExceeded(e) => write!(f, "Context length exceeded: {}", e),
+            AppError::ServerError { status, message } => {
+                write!(f, "Server error ({}): {}", status, message)
+            }
+            AppError::ApiError { status, message } => {
+                write!(f, "API error ({}): {}", status, message)
+            }
+            AppError::Timeout(e) => write!(f, "Timed out: {}", e),
         }
     }
 }
@@ -114,3 +257,9 @@ impl From<tokio::task::JoinError> for AppError {
         AppError::TaskJoinError(err)
     }
 }
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        AppError::SqliteError(err)
+    }
+}
@@ -1 +1,2 @@
 pub mod tool_registry;
+pub mod validation;
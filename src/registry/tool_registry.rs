@@ -1,8 +1,22 @@
-use crate::models::traits::Tool;
+use crate::models::traits::{Tool, ToolContext};
 use crate::models::types::AppError;
+use crate::registry::validation::validate;
 use serde_json::{json, Value as JsonValue};
 use std::collections::HashMap;
 
+// Submitted by `#[auto_register]` for every tool impl, and collected when the registry is built
+pub struct ToolRegistration {
+    constructor: fn() -> Box<dyn Tool + Sync + Send>,
+}
+
+impl ToolRegistration {
+    pub const fn new(constructor: fn() -> Box<dyn Tool + Sync + Send>) -> Self {
+        Self { constructor }
+    }
+}
+
+inventory::collect!(ToolRegistration);
+
 pub struct ToolRegistry {
     tools: HashMap<String, Box<dyn Tool + Sync + Send>>,
 }
@@ -14,8 +28,42 @@ impl ToolRegistry {
         }
     }
 
-    pub fn register<T: Tool + 'static>(&mut self, tool: T) {
-        self.tools.insert(tool.name().to_string(), Box::new(tool));
+    pub fn with_registered_tools() -> Self {
+        let mut registry = Self::new();
+        for registration in inventory::iter::<ToolRegistration> {
+            registry.register_boxed((registration.constructor)());
+        }
+        registry
+    }
+
+    // The registered tools, limited to `enabled` when it's given
+    pub fn with_enabled_tools(enabled: Option<&[String]>) -> Self {
+        let mut registry = Self::with_registered_tools();
+        if let Some(enabled) = enabled {
+            registry.tools.retain(|name, _| enabled.contains(name));
+        }
+        registry
+    }
+
+    pub fn contains(&self, tool_name: &str) -> bool {
+        self.tools.contains_key(tool_name)
+    }
+
+    // Sorted, so listings don't depend on registration order
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.tools.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    fn register_boxed(&mut self, tool: Box<dyn Tool + Sync + Send>) {
+        let name = tool.name();
+        if self.tools.insert(name.to_string(), tool).is_some() {
+            log::warn!(
+                "More than one tool is named `{}`; keeping the last one",
+                name
+            );
+        }
     }
 
     pub fn generate_tools_json(&self) -> JsonValue {
@@ -47,15 +95,46 @@ impl ToolRegistry {
         schemas_str
     }
 
-    pub async fn execute_tool(&self, tool_name: &str, args: JsonValue) -> Result<String, AppError> {
-        if let Some(tool) = self.tools.get(tool_name) {
-            tool.execute(args).await
-        } else {
-            Err(AppError::CommandError(format!(
-                "Tool `{}` not found",
-                tool_name
-            )))
-        }
+    // Check arguments against the tool's schema, returning them with any safe coercions applied.
+    // Errors list every problem in a form the model can act on.
+    pub fn validate_arguments(
+        &self,
+        tool_name: &str,
+        args: JsonValue,
+    ) -> Result<JsonValue, AppError> {
+        let tool = self.get_tool(tool_name)?;
+        validate(&tool.input_schema(), args).map_err(|violations| AppError::InvalidArguments {
+            tool: tool_name.to_string(),
+            violations,
+        })
+    }
+
+    pub async fn execute_tool(
+        &self,
+        tool_name: &str,
+        args: JsonValue,
+        ctx: &ToolContext,
+    ) -> Result<String, AppError> {
+        let args = self.validate_arguments(tool_name, args)?;
+        self.get_tool(tool_name)?.execute(args, ctx).await
+    }
+
+    // Unknown tools aren't read-only
+    pub fn is_read_only(&self, tool_name: &str, args: &JsonValue) -> bool {
+        self.tools
+            .get(tool_name)
+            .is_some_and(|tool| tool.is_read_only(args))
+    }
+
+    pub async fn preview(&self, tool_name: &str, args: &JsonValue) -> Option<String> {
+        self.tools.get(tool_name)?.preview(args).await
+    }
+
+    fn get_tool(&self, tool_name: &str) -> Result<&(dyn Tool + Sync + Send), AppError> {
+        self.tools
+            .get(tool_name)
+            .map(|tool| tool.as_ref())
+            .ok_or_else(|| AppError::CommandError(format!("Tool `{}` not found", tool_name)))
     }
 
     pub fn list_tools(&self) -> String {
@@ -66,3 +145,36 @@ impl ToolRegistry {
         tools_listing
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers_every_tool_once() {
+        let registry = ToolRegistry::with_registered_tools();
+        let names = registry.names();
+        assert_eq!(
+            names,
+            [
+                "file_tool",
+                "gpt_tool",
+                "pipeline_tool",
+                "shell_tool",
+                "snap_tool"
+            ]
+        );
+        assert_eq!(
+            inventory::iter::<ToolRegistration>.into_iter().count(),
+            names.len()
+        );
+    }
+
+    #[test]
+    fn test_enabled_tools() {
+        let enabled = ["shell_tool".to_string(), "snap_tool".to_string()];
+        let registry = ToolRegistry::with_enabled_tools(Some(&enabled));
+        assert_eq!(registry.names(), ["shell_tool", "snap_tool"]);
+        assert!(!registry.contains("file_tool"));
+    }
+}
@@ -0,0 +1,380 @@
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use schemars::Map;
+use serde_json::Value as JsonValue;
+use std::fmt;
+
+// One way the arguments fail the schema, located by the JSON pointer of the offending value
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgumentViolation {
+    pub pointer: String,
+    pub message: String,
+}
+
+impl fmt::Display for ArgumentViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pointer = if self.pointer.is_empty() {
+            "(root)"
+        } else {
+            &self.pointer
+        };
+        write!(f, "{}: {}", pointer, self.message)
+    }
+}
+
+// Check tool-call arguments against a tool's input schema. Values the model commonly sends in the
+// wrong shape but with an unambiguous meaning are coerced, so the (possibly corrected) arguments
+// are returned on success:
+// - a string holding JSON where an object or array is expected
+// - a string holding a number or boolean where one is expected
+// - a float with no fractional part where an integer is expected
+pub fn validate(
+    schema: &RootSchema,
+    mut args: JsonValue,
+) -> Result<JsonValue, Vec<ArgumentViolation>> {
+    let mut validator = Validator {
+        definitions: &schema.definitions,
+        violations: Vec::new(),
+    };
+    validator.check_object(&schema.schema, &mut args, String::new());
+    if validator.violations.is_empty() {
+        Ok(args)
+    } else {
+        Err(validator.violations)
+    }
+}
+
+struct Validator<'a> {
+    definitions: &'a Map<String, Schema>,
+    violations: Vec<ArgumentViolation>,
+}
+
+impl Validator<'_> {
+    fn violation(&mut self, pointer: &str, message: String) {
+        self.violations.push(ArgumentViolation {
+            pointer: pointer.to_string(),
+            message,
+        });
+    }
+
+    fn check(&mut self, schema: &Schema, value: &mut JsonValue, pointer: String) {
+        match schema {
+            Schema::Bool(true) => {}
+            Schema::Bool(false) => self.violation(&pointer, "no value is allowed here".to_string()),
+            Schema::Object(object) => self.check_object(object, value, pointer),
+        }
+    }
+
+    fn check_object(&mut self, schema: &SchemaObject, value: &mut JsonValue, pointer: String) {
+        if let Some(reference) = &schema.reference {
+            let name = reference.trim_start_matches("#/definitions/");
+            match self.definitions.get(name) {
+                Some(definition) => self.check(definition, value, pointer.clone()),
+                None => log::warn!("Tool schema refers to unknown definition `{}`", reference),
+            }
+        }
+
+        if let Some(subschemas) = &schema.subschemas {
+            for subschema in subschemas.all_of.iter().flatten() {
+                self.check(subschema, value, pointer.clone());
+            }
+            let alternatives = subschemas
+                .any_of
+                .iter()
+                .chain(subschemas.one_of.iter())
+                .flatten();
+            let alternatives: Vec<&Schema> = alternatives.collect();
+            if !alternatives.is_empty() {
+                self.check_alternatives(&alternatives, value, &pointer);
+            }
+        }
+
+        if let Some(instance_type) = &schema.instance_type {
+            let expected: Vec<InstanceType> = match instance_type {
+                SingleOrVec::Single(single) => vec![**single],
+                SingleOrVec::Vec(types) => types.clone(),
+            };
+            if !expected
+                .iter()
+                .any(|expected| matches_type(value, expected))
+            {
+                match expected.iter().find_map(|expected| coerce(value, expected)) {
+                    Some(coerced) => {
+                        log::info!(
+                            "Coerced tool argument at `{}` to {}",
+                            pointer,
+                            describe_types(&expected)
+                        );
+                        *value = coerced;
+                    }
+                    None => {
+                        self.violation(
+                            &pointer,
+                            format!(
+                                "expected {}, got {}",
+                                describe_types(&expected),
+                                type_name(value)
+                            ),
+                        );
+                        return;
+                    }
+                }
+            }
+        }
+
+        if let Some(allowed) = &schema.enum_values {
+            if !allowed.contains(value) {
+                let allowed: Vec<String> = allowed.iter().map(JsonValue::to_string).collect();
+                self.violation(
+                    &pointer,
+                    format!("expected one of {}, got {}", allowed.join(", "), value),
+                );
+                return;
+            }
+        }
+
+        if let (Some(number), Some(validation)) = (value.as_f64(), &schema.number) {
+            if let Some(minimum) = validation.minimum.filter(|minimum| number < *minimum) {
+                self.violation(
+                    &pointer,
+                    format!("must be at least {}, got {}", minimum, number),
+                );
+            }
+            if let Some(maximum) = validation.maximum.filter(|maximum| number > *maximum) {
+                self.violation(
+                    &pointer,
+                    format!("must be at most {}, got {}", maximum, number),
+                );
+            }
+        }
+
+        if let (JsonValue::Array(items), Some(validation)) = (&mut *value, &schema.array) {
+            if let Some(SingleOrVec::Single(item_schema)) = &validation.items {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.check(item_schema, item, format!("{}/{}", pointer, i));
+                }
+            }
+        }
+
+        if let (JsonValue::Object(properties), Some(validation)) = (&mut *value, &schema.object) {
+            for required in &validation.required {
+                if !properties.contains_key(required) {
+                    self.violation(
+                        &child_pointer(&pointer, required),
+                        "required property is missing".to_string(),
+                    );
+                }
+            }
+            for (key, property) in properties.iter_mut() {
+                let property_pointer = child_pointer(&pointer, key);
+                match validation.properties.get(key) {
+                    Some(property_schema) => {
+                        self.check(property_schema, property, property_pointer)
+                    }
+                    None => match validation.additional_properties.as_deref() {
+                        Some(Schema::Bool(false)) => {
+                            self.violation(&property_pointer, "unknown property".to_string())
+                        }
+                        Some(additional) => self.check(additional, property, property_pointer),
+                        None => {}
+                    },
+                }
+            }
+        }
+    }
+
+    // Keep the first alternative the value satisfies; otherwise report the closest one
+    fn check_alternatives(
+        &mut self,
+        alternatives: &[&Schema],
+        value: &mut JsonValue,
+        pointer: &str,
+    ) {
+        let mut closest: Option<Vec<ArgumentViolation>> = None;
+        for alternative in alternatives {
+            let mut candidate = value.clone();
+            let mut validator = Validator {
+                definitions: self.definitions,
+                violations: Vec::new(),
+            };
+            validator.check(alternative, &mut candidate, pointer.to_string());
+            if validator.violations.is_empty() {
+                *value = candidate;
+                return;
+            }
+            if closest
+                .as_ref()
+                .is_none_or(|violations| validator.violations.len() < violations.len())
+            {
+                closest = Some(validator.violations);
+            }
+        }
+        if let Some(violations) = closest {
+            self.violations.extend(violations);
+        }
+    }
+}
+
+// Escape a property name into a JSON pointer token (RFC 6901)
+pub fn child_pointer(pointer: &str, key: &str) -> String {
+    format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn matches_type(value: &JsonValue, expected: &InstanceType) -> bool {
+    match expected {
+        InstanceType::Null => value.is_null(),
+        InstanceType::Boolean => value.is_boolean(),
+        InstanceType::Object => value.is_object(),
+        InstanceType::Array => value.is_array(),
+        InstanceType::Number => value.is_number(),
+        InstanceType::String => value.is_string(),
+        InstanceType::Integer => value.is_i64() || value.is_u64(),
+    }
+}
+
+fn coerce(value: &JsonValue, expected: &InstanceType) -> Option<JsonValue> {
+    match (value, expected) {
+        (JsonValue::String(s), InstanceType::Object | InstanceType::Array) => {
+            serde_json::from_str::<JsonValue>(s)
+                .ok()
+                .filter(|parsed| matches_type(parsed, expected))
+        }
+        (
+            JsonValue::String(s),
+            InstanceType::Integer | InstanceType::Number | InstanceType::Boolean,
+        ) => serde_json::from_str::<JsonValue>(s.trim())
+            .ok()
+            .and_then(|parsed| {
+                if matches_type(&parsed, expected) {
+                    Some(parsed)
+                } else {
+                    coerce(&parsed, expected)
+                }
+            }),
+        (JsonValue::Number(number), InstanceType::Integer) => number
+            .as_f64()
+            .filter(|float| float.fract() == 0.0 && float.abs() < i64::MAX as f64)
+            .map(|float| JsonValue::from(float as i64)),
+        _ => None,
+    }
+}
+
+fn type_name(value: &JsonValue) -> &'static str {
+    match value {
+        JsonValue::Null => "null",
+        JsonValue::Bool(_) => "boolean",
+        JsonValue::Number(number) if number.is_f64() => "number",
+        JsonValue::Number(_) => "integer",
+        JsonValue::String(_) => "string",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(_) => "object",
+    }
+}
+
+fn describe_types(types: &[InstanceType]) -> String {
+    let names: Vec<&str> = types
+        .iter()
+        .map(|instance_type| match instance_type {
+            InstanceType::Null => "null",
+            InstanceType::Boolean => "boolean",
+            InstanceType::Object => "object",
+            InstanceType::Array => "array",
+            InstanceType::Number => "number",
+            InstanceType::String => "string",
+            InstanceType::Integer => "integer",
+        })
+        .collect();
+    names.join(" or ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schemars::{schema_for, JsonSchema};
+    use serde_json::json;
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    #[serde(rename_all = "lowercase")]
+    enum Mode {
+        Fast,
+        Slow,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Step {
+        id: String,
+        parameters: JsonValue,
+        retries: Option<u32>,
+        mode: Option<Mode>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Input {
+        steps: Vec<Step>,
+        verbose: bool,
+    }
+
+    #[test]
+    fn test_reports_every_violation_by_pointer() {
+        let schema = schema_for!(Input);
+        let violations = validate(
+            &schema,
+            json!({ "steps": [{ "id": 7, "parameters": {}, "mode": "medium" }, { "parameters": 1 }] }),
+        )
+        .unwrap_err();
+
+        let violations: Vec<String> = violations.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            violations,
+            [
+                "/verbose: required property is missing",
+                "/steps/0/id: expected string, got integer",
+                "/steps/0/mode: expected one of \"fast\", \"slow\", got \"medium\"",
+                "/steps/1/id: required property is missing",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_coerces_stringified_values() {
+        let schema = schema_for!(Input);
+        let args = validate(
+            &schema,
+            json!({
+                "steps": "[{\"id\": \"a\", \"parameters\": {\"x\": 1}, \"retries\": \"3\"}]",
+                "verbose": "true"
+            }),
+        )
+        .unwrap();
+
+        assert_eq!(
+            args,
+            json!({
+                "steps": [{ "id": "a", "parameters": { "x": 1 }, "retries": 3 }],
+                "verbose": true
+            })
+        );
+    }
+
+    #[test]
+    fn test_coercion_is_limited_to_unambiguous_values() {
+        let schema = schema_for!(Input);
+        let violations = validate(
+            &schema,
+            json!({ "steps": [{ "id": "a", "parameters": null, "retries": 1.5 }], "verbose": "yes" }),
+        )
+        .unwrap_err();
+
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].pointer, "/steps/0/retries");
+        assert_eq!(violations[1].message, "expected boolean, got string");
+        // Whole numbers sent as floats are accepted
+        assert!(validate(
+            &schema,
+            json!({ "steps": [{ "id": "a", "parameters": 1, "retries": 2.0 }], "verbose": false })
+        )
+        .is_ok());
+    }
+}
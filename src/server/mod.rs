@@ -0,0 +1,466 @@
+pub mod session;
+
+use crate::{
+    approval::{ApprovalHandler, UserDecision},
+    assistant::{
+        conversation_manager::{list_conversations, read_conversation, resolve_conversation_id},
+        output::{AssistantEvent, OutputSink},
+        Assistant, SessionStart, GLOBAL_TOOL_REGISTRY,
+    },
+    models::types::AppError,
+};
+use session::Session;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use serde_derive::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+pub const DEFAULT_BIND: &str = "127.0.0.1:4000";
+// When set, every request needs `Authorization: Bearer <token>`
+pub const TOKEN_VARIABLE: &str = "RTOOL_SERVE_TOKEN";
+// Sessions unused for this long are dropped from memory; their conversations stay saved
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// Builds an assistant that reports to, and asks, a session instead of the terminal
+pub type NewAssistant =
+    Arc<dyn Fn(Arc<dyn OutputSink>, Arc<dyn ApprovalHandler>) -> Assistant + Send + Sync>;
+
+struct ServerState {
+    // Conversations with an assistant in memory, by conversation id
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    new_assistant: NewAssistant,
+    token: Option<String>,
+    // The port listened on, which a tokenless request's Host and Origin must name
+    port: u16,
+}
+
+type SharedState = Arc<ServerState>;
+
+// Serve the API until the process is stopped
+pub async fn serve(bind: &str, new_assistant: NewAssistant) -> Result<(), AppError> {
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    let state = Arc::new(ServerState {
+        sessions: Mutex::new(HashMap::new()),
+        new_assistant,
+        token: std::env::var(TOKEN_VARIABLE).ok(),
+        port: listener.local_addr()?.port(),
+    });
+    // The tools can run shell commands, so only this machine may use the API without a token
+    if state.token.is_none() {
+        if !listener.local_addr()?.ip().is_loopback() {
+            return Err(AppError::CommandError(format!(
+                "Refusing to listen on {} without a token; set {} or bind to 127.0.0.1",
+                bind, TOKEN_VARIABLE
+            )));
+        }
+        log::warn!(
+            "{} isn't set; the API accepts requests without a token from this machine",
+            TOKEN_VARIABLE
+        );
+    }
+    log::info!("Listening on {}", bind);
+    tokio::spawn(evict_idle_sessions(state.clone()));
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+async fn evict_idle_sessions(state: SharedState) {
+    let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.sessions.lock().unwrap().retain(|id, session| {
+            let idle = session.is_idle(SESSION_IDLE_TIMEOUT);
+            if idle {
+                log::info!("Closing idle conversation {}", id);
+            }
+            !idle
+        });
+    }
+}
+
+fn router(state: SharedState) -> Router {
+    Router::new()
+        .route("/tools", get(list_tools))
+        .route(
+            "/conversations",
+            get(list_saved_conversations).post(create_conversation),
+        )
+        .route("/conversations/:id", get(get_conversation))
+        .route("/conversations/:id/messages", post(post_message))
+        .route("/conversations/:id/events", get(follow_events))
+        .route(
+            "/conversations/:id/approvals",
+            get(list_approvals).delete(reject_all_approvals),
+        )
+        .route(
+            "/conversations/:id/approvals/:call_id",
+            post(decide_approval),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+// An error response: `{"error": "..."}`
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<AppError> for ApiError {
+    fn from(error: AppError) -> Self {
+        log::error!("Request failed: {}", error);
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+// Without a token, only requests addressed to the loopback listener, and not made by another
+// site's page, are let through. A page using DNS rebinding to reach the port sends its own name as
+// the Host, and a cross-origin request carries its site in Origin.
+async fn require_token(State(state): State<SharedState>, request: Request, next: Next) -> Response {
+    let Some(token) = &state.token else {
+        if is_local_request(request.headers(), state.port) {
+            return next.run(request).await;
+        }
+        return ApiError(
+            StatusCode::FORBIDDEN,
+            format!(
+                "Without {} set, only same-origin requests to localhost are accepted",
+                TOKEN_VARIABLE
+            ),
+        )
+        .into_response();
+    };
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token);
+    if authorized {
+        next.run(request).await
+    } else {
+        ApiError(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong token".to_string(),
+        )
+        .into_response()
+    }
+}
+
+// The Host names a loopback address and the listening port, and any Origin is that same host
+fn is_local_request(headers: &HeaderMap, port: u16) -> bool {
+    let local_hosts = ["localhost", "127.0.0.1", "[::1]"].map(|host| format!("{}:{}", host, port));
+    let header = |name| {
+        headers
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default())
+    };
+    let host_ok =
+        header(header::HOST).is_some_and(|host| local_hosts.iter().any(|local| local == host));
+    let origin_ok = header(header::ORIGIN).is_none_or(|origin| {
+        origin
+            .strip_prefix("http://")
+            .is_some_and(|origin| local_hosts.iter().any(|local| local == origin))
+    });
+    host_ok && origin_ok
+}
+
+async fn list_tools() -> Json<JsonValue> {
+    Json(GLOBAL_TOOL_REGISTRY.generate_tools_json())
+}
+
+async fn list_saved_conversations() -> ApiResult<Json<JsonValue>> {
+    Ok(Json(
+        serde_json::to_value(list_conversations()?).map_err(AppError::from)?,
+    ))
+}
+
+// A saved conversation's metadata and messages
+async fn get_conversation(Path(id): Path<String>) -> ApiResult<Json<JsonValue>> {
+    let id = resolve_conversation_id(&id).map_err(not_found)?;
+    let (metadata, messages) = read_conversation(&id)?;
+    Ok(Json(json!({ "metadata": metadata, "messages": messages })))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewConversation {
+    prompt: String,
+    #[serde(default)]
+    include_state: bool,
+}
+
+// Start a conversation and reply to its first prompt in the background
+async fn create_conversation(
+    State(state): State<SharedState>,
+    Json(body): Json<NewConversation>,
+) -> ApiResult<(StatusCode, Json<JsonValue>)> {
+    let new_assistant = state.new_assistant.clone();
+    let session = Session::new(|output, approvals| new_assistant(output, approvals));
+    let mut assistant = session.start_turn().expect("a new session is unlocked");
+    assistant.start_session(SessionStart::New {
+        initial_prompt: body.prompt,
+        include_state: body.include_state,
+    })?;
+
+    let conversation_id = assistant.conversation_id().to_string();
+    session.spawn_turn(assistant);
+    state
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(conversation_id.clone(), Arc::new(session));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "conversation_id": conversation_id })),
+    ))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewMessage {
+    content: String,
+}
+
+// Add a user prompt and reply to it in the background. Saved conversations are resumed first.
+async fn post_message(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+    Json(body): Json<NewMessage>,
+) -> ApiResult<(StatusCode, Json<JsonValue>)> {
+    let session = resume_session(&state, &id)?;
+    let Some(mut assistant) = session.start_turn() else {
+        return Err(ApiError(
+            StatusCode::CONFLICT,
+            "The conversation is still replying to the last message".to_string(),
+        ));
+    };
+    assistant.add_user_prompt(body.content)?;
+
+    let conversation_id = assistant.conversation_id().to_string();
+    session.spawn_turn(assistant);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "conversation_id": conversation_id })),
+    ))
+}
+
+// Server-sent events for the current turn and every later one
+async fn follow_events(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+) -> ApiResult<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    let session = live_session(&state, &id)?;
+    let (history, receiver) = session.events.subscribe();
+
+    // A client that falls too far behind skips what it missed
+    let live = BroadcastStream::new(receiver).filter_map(Result::ok);
+    let events = tokio_stream::iter(history)
+        .chain(live)
+        .map(|event| Ok(sse_event(&event)));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn sse_event(event: &AssistantEvent) -> Event {
+    let data = serde_json::to_value(event).unwrap_or_default();
+    let name = data["type"].as_str().unwrap_or("message").to_string();
+    Event::default().event(name).data(data.to_string())
+}
+
+async fn list_approvals(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<JsonValue>> {
+    let session = live_session(&state, &id)?;
+    Ok(Json(
+        serde_json::to_value(session.approvals.list()).map_err(AppError::from)?,
+    ))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RejectAllBody {
+    reason: Option<String>,
+}
+
+// Reject every call waiting for approval, e.g. when a client gives up on a turn
+async fn reject_all_approvals(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+    body: Option<Json<RejectAllBody>>,
+) -> ApiResult<Json<JsonValue>> {
+    let session = live_session(&state, &id)?;
+    let Json(body) = body.unwrap_or_default();
+    let rejected = session.approvals.reject_all(body.reason);
+    Ok(Json(json!({ "rejected": rejected })))
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Decision {
+    Approve,
+    // Approve, and don't ask about this tool again in this conversation
+    Always,
+    Reject,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApprovalBody {
+    decision: Decision,
+    // Replacement arguments to run the call with
+    arguments: Option<JsonValue>,
+    // Why the call was rejected; passed on to the model
+    reason: Option<String>,
+}
+
+async fn decide_approval(
+    State(state): State<SharedState>,
+    Path((id, call_id)): Path<(String, String)>,
+    Json(body): Json<ApprovalBody>,
+) -> ApiResult<StatusCode> {
+    let session = live_session(&state, &id)?;
+    let Some(call) = session.approvals.get(&call_id) else {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("No tool call `{}` is waiting for approval", call_id),
+        ));
+    };
+
+    let decision = match body.decision {
+        Decision::Reject => UserDecision::Reject(body.reason),
+        decision => {
+            // Edited arguments have to fit the tool's schema, like edits made at the terminal
+            let arguments = match body.arguments {
+                Some(arguments) => GLOBAL_TOOL_REGISTRY
+                    .validate_arguments(&call.tool, arguments)
+                    .map_err(|e| ApiError(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?,
+                None => call.arguments,
+            };
+            UserDecision::Approve {
+                arguments,
+                always: decision == Decision::Always,
+            }
+        }
+    };
+
+    if session.approvals.resolve(&call_id, decision) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError(
+            StatusCode::GONE,
+            format!("Tool call `{}` is no longer waiting", call_id),
+        ))
+    }
+}
+
+fn live_session(state: &ServerState, id: &str) -> ApiResult<Arc<Session>> {
+    let session = state.sessions.lock().unwrap().get(id).cloned();
+    session.inspect(|session| session.touch()).ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            format!("Conversation `{}` isn't open on this server", id),
+        )
+    })
+}
+
+// The open session for a conversation, resuming it from storage if needed
+fn resume_session(state: &ServerState, id: &str) -> ApiResult<Arc<Session>> {
+    let mut sessions = state.sessions.lock().unwrap();
+    if let Some(session) = sessions.get(id) {
+        session.touch();
+        return Ok(session.clone());
+    }
+
+    let id = resolve_conversation_id(id).map_err(not_found)?;
+    if let Some(session) = sessions.get(&id) {
+        session.touch();
+        return Ok(session.clone());
+    }
+    let new_assistant = state.new_assistant.clone();
+    let session = Arc::new(Session::new(|output, approvals| {
+        new_assistant(output, approvals)
+    }));
+    session
+        .start_turn()
+        .expect("a new session is unlocked")
+        .start_session(SessionStart::Resume {
+            conversation_id: id.clone(),
+            prompt: None,
+            refresh_system: false,
+            include_state: false,
+        })?;
+    sessions.insert(id, session.clone());
+    Ok(session)
+}
+
+fn not_found(error: AppError) -> ApiError {
+    ApiError(StatusCode::NOT_FOUND, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenless_requests_must_be_local() {
+        let headers = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            headers
+        };
+        assert!(is_local_request(
+            &headers(&[(header::HOST, "127.0.0.1:4000")]),
+            4000
+        ));
+        assert!(is_local_request(
+            &headers(&[
+                (header::HOST, "localhost:4000"),
+                (header::ORIGIN, "http://localhost:4000")
+            ]),
+            4000
+        ));
+        // DNS rebinding: the page's own name reaches the loopback address
+        assert!(!is_local_request(
+            &headers(&[(header::HOST, "evil.example:4000")]),
+            4000
+        ));
+        assert!(!is_local_request(
+            &headers(&[(header::HOST, "127.0.0.1:4001")]),
+            4000
+        ));
+        assert!(!is_local_request(&headers(&[]), 4000));
+        assert!(!is_local_request(
+            &headers(&[
+                (header::HOST, "127.0.0.1:4000"),
+                (header::ORIGIN, "https://evil.example")
+            ]),
+            4000
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::{
+    approval::{ApprovalHandler, UserDecision},
+    assistant::{
+        output::{AssistantEvent, OutputSink},
+        Assistant,
+    },
+    models::types::{AppError, ToolCall},
+};
+
+use async_trait::async_trait;
+use serde_derive::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot, Mutex as AsyncMutex, OwnedMutexGuard};
+
+// How many events a slow client may fall behind before it misses some
+const EVENT_BUFFER: usize = 256;
+// How long a tool call waits for a client to decide it before it's rejected, so an abandoned
+// turn doesn't hold the conversation forever
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// A conversation being driven over HTTP
+pub struct Session {
+    // Locked for as long as a turn runs
+    assistant: Arc<AsyncMutex<Assistant>>,
+    pub events: Arc<TurnEvents>,
+    pub approvals: Arc<PendingApprovals>,
+    // When a client last used the session, for evicting idle ones
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl Session {
+    // Hands the assistant's output and approval questions to this session
+    pub fn new(
+        new_assistant: impl FnOnce(Arc<dyn OutputSink>, Arc<dyn ApprovalHandler>) -> Assistant,
+    ) -> Self {
+        let events = Arc::new(TurnEvents::new());
+        let approvals = Arc::new(PendingApprovals {
+            pending: Mutex::new(HashMap::new()),
+            events: events.clone(),
+            timeout: APPROVAL_TIMEOUT,
+        });
+        let assistant = new_assistant(events.clone(), approvals.clone());
+        Self {
+            assistant: Arc::new(AsyncMutex::new(assistant)),
+            events,
+            approvals,
+            last_active: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    // Unused for `timeout` and not running a turn, so it can be dropped. The conversation itself
+    // is saved, and is resumed by the next message.
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        self.last_active.lock().unwrap().elapsed() >= timeout && self.assistant.try_lock().is_ok()
+    }
+
+    // Hold the assistant for a new turn, forgetting the events of the last one. None while a turn
+    // is running.
+    pub fn start_turn(&self) -> Option<OwnedMutexGuard<Assistant>> {
+        let assistant = self.assistant.clone().try_lock_owned().ok()?;
+        self.events.history.lock().unwrap().clear();
+        self.touch();
+        Some(assistant)
+    }
+
+    // Reply to the prompt just added, in the background. Clients follow along through the events.
+    pub fn spawn_turn(&self, mut assistant: OwnedMutexGuard<Assistant>) {
+        let events = self.events.clone();
+        let last_active = self.last_active.clone();
+        tokio::spawn(async move {
+            let event = match assistant.respond().await {
+                Ok(answer) => AssistantEvent::TurnFinished { answer },
+                Err(e) => {
+                    log::error!("Turn failed: {}", e);
+                    AssistantEvent::Error {
+                        message: e.to_string(),
+                    }
+                }
+            };
+            let _ = events.emit(event);
+            // Idle time counts from the end of the turn
+            *last_active.lock().unwrap() = Instant::now();
+        });
+    }
+}
+
+// The events of the current turn. Clients that connect part way through get what they missed
+// first, then the rest as it happens.
+pub struct TurnEvents {
+    history: Mutex<Vec<AssistantEvent>>,
+    sender: broadcast::Sender<AssistantEvent>,
+}
+
+impl TurnEvents {
+    fn new() -> Self {
+        Self {
+            history: Mutex::new(Vec::new()),
+            sender: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> (Vec<AssistantEvent>, broadcast::Receiver<AssistantEvent>) {
+        let history = self.history.lock().unwrap();
+        (history.clone(), self.sender.subscribe())
+    }
+}
+
+impl OutputSink for TurnEvents {
+    // Nobody listening is fine; the events are kept for whoever connects later
+    fn emit(&self, event: AssistantEvent) -> Result<(), AppError> {
+        let mut history = self.history.lock().unwrap();
+        history.push(event.clone());
+        let _ = self.sender.send(event);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingApproval {
+    pub call_id: String,
+    pub tool: String,
+    pub arguments: JsonValue,
+    // What the call would change, as a diff, for tools that can tell
+    pub preview: Option<String>,
+}
+
+// Tool calls waiting for a client to approve or reject them
+pub struct PendingApprovals {
+    pending: Mutex<HashMap<String, (PendingApproval, oneshot::Sender<UserDecision>)>>,
+    events: Arc<TurnEvents>,
+    timeout: Duration,
+}
+
+impl PendingApprovals {
+    pub fn list(&self) -> Vec<PendingApproval> {
+        let pending = self.pending.lock().unwrap();
+        pending.values().map(|(call, _)| call.clone()).collect()
+    }
+
+    pub fn get(&self, call_id: &str) -> Option<PendingApproval> {
+        let pending = self.pending.lock().unwrap();
+        pending.get(call_id).map(|(call, _)| call.clone())
+    }
+
+    // False if no call with this id is waiting
+    pub fn resolve(&self, call_id: &str, decision: UserDecision) -> bool {
+        match self.pending.lock().unwrap().remove(call_id) {
+            Some((_, responder)) => responder.send(decision).is_ok(),
+            None => false,
+        }
+    }
+
+    // Reject every waiting call, returning how many there were
+    pub fn reject_all(&self, reason: Option<String>) -> usize {
+        let pending: Vec<_> = self.pending.lock().unwrap().drain().collect();
+        let count = pending.len();
+        for (_, (_, responder)) in pending {
+            let _ = responder.send(UserDecision::Reject(reason.clone()));
+        }
+        count
+    }
+}
+
+#[async_trait]
+impl ApprovalHandler for PendingApprovals {
+    // The turn waits until a client answers, or the timeout rejects the call
+    async fn ask(
+        &self,
+        tool_call: &ToolCall,
+        args: &JsonValue,
+        preview: Option<&str>,
+    ) -> Result<UserDecision, AppError> {
+        let call = PendingApproval {
+            call_id: tool_call.id.clone(),
+            tool: tool_call.function.name.clone(),
+            arguments: args.clone(),
+            preview: preview.map(str::to_string),
+        };
+        let (responder, decision) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(call.call_id.clone(), (call.clone(), responder));
+        self.events.emit(AssistantEvent::ApprovalRequested {
+            call_id: call.call_id,
+            tool: call.tool,
+            arguments: call.arguments,
+            preview: call.preview,
+        })?;
+
+        match tokio::time::timeout(self.timeout, decision).await {
+            Ok(decision) => decision.map_err(|_| {
+                AppError::CommandError("The approval request was dropped".to_string())
+            }),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&tool_call.id);
+                let message = format!(
+                    "Nobody decided on tool call `{}` within {} seconds, so it was rejected",
+                    tool_call.id,
+                    self.timeout.as_secs()
+                );
+                self.events.emit(AssistantEvent::Warning {
+                    message: message.clone(),
+                })?;
+                Ok(UserDecision::Reject(Some(message)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::FunctionCall;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_approval_waits_for_a_decision() {
+        let events = Arc::new(TurnEvents::new());
+        let approvals = Arc::new(PendingApprovals {
+            pending: Mutex::new(HashMap::new()),
+            events: events.clone(),
+            timeout: APPROVAL_TIMEOUT,
+        });
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: "shell_tool".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+
+        let asking = approvals.clone();
+        let decision = tokio::spawn(async move {
+            asking
+                .ask(&tool_call, &json!({ "commands": [] }), Some("+new line"))
+                .await
+        });
+        while approvals.list().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // Late subscribers still see the request
+        let (history, _) = events.subscribe();
+        assert!(matches!(
+            &history[..],
+            [AssistantEvent::ApprovalRequested { call_id, preview, .. }]
+                if call_id == "call_1" && preview.as_deref() == Some("+new line")
+        ));
+        assert_eq!(approvals.list()[0].preview.as_deref(), Some("+new line"));
+        assert!(!approvals.resolve("call_2", UserDecision::Reject(None)));
+        assert!(approvals.resolve("call_1", UserDecision::Reject(Some("no".to_string()))));
+        assert_eq!(
+            decision.await.unwrap().unwrap(),
+            UserDecision::Reject(Some("no".to_string()))
+        );
+        assert!(approvals.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_approval_times_out() {
+        let approvals = PendingApprovals {
+            pending: Mutex::new(HashMap::new()),
+            events: Arc::new(TurnEvents::new()),
+            timeout: Duration::from_millis(10),
+        };
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: "shell_tool".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+
+        let decision = approvals.ask(&tool_call, &json!({}), None).await.unwrap();
+        assert!(
+            matches!(decision, UserDecision::Reject(Some(reason)) if reason.contains("call_1"))
+        );
+        assert!(approvals.list().is_empty());
+        assert_eq!(approvals.reject_all(None), 0);
+    }
+}
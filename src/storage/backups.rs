@@ -0,0 +1,311 @@
+use crate::{config::settings, models::types::AppError};
+
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+pub const BACKUPS_DIR: &str = "backups";
+// One line per tool call that changed files, oldest first
+const JOURNAL_FILE: &str = "journal.jsonl";
+// File contents, by the names the journal gives them
+const BLOBS_DIR: &str = "blobs";
+
+// A file a tool call changed. None if it didn't exist before, or doesn't after.
+pub struct FileChange {
+    pub path: PathBuf,
+    pub before: Option<Vec<u8>>,
+    pub after: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    call_id: String,
+    // RFC 3339
+    time: String,
+    files: Vec<JournalFile>,
+}
+
+// Blob names rather than contents, so binary files can be backed up too
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalFile {
+    path: PathBuf,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct UndoReport {
+    pub calls: usize,
+    pub restored: Vec<PathBuf>,
+    // Changed since the tool call, so left as they are. Their backups are kept, for a forced undo.
+    pub skipped: Vec<PathBuf>,
+}
+
+// A file the conversation's tool calls touched, as it was before the first of them and as it is now
+#[derive(Debug)]
+pub struct SessionChange {
+    pub path: PathBuf,
+    pub original: Option<Vec<u8>>,
+    pub current: Option<Vec<u8>>,
+    pub calls: usize,
+}
+
+// What file_tool calls overwrote or deleted, saved per conversation so the calls can be undone
+pub struct Backups {
+    dir: PathBuf,
+}
+
+impl Backups {
+    pub fn for_conversation(conversation_id: &str) -> Self {
+        Self::in_dir(settings().backups_dir.join(conversation_id))
+    }
+
+    fn in_dir(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    // Save a tool call's changes. Paths should be absolute, so they can be undone from anywhere.
+    pub fn record(&self, call_id: &str, changes: Vec<FileChange>) -> Result<(), AppError> {
+        let changes: Vec<FileChange> = changes
+            .into_iter()
+            .filter(|change| change.before != change.after)
+            .collect();
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(self.dir.join(BLOBS_DIR))?;
+        let mut files = Vec::new();
+        for change in changes {
+            files.push(JournalFile {
+                path: change.path,
+                before: self.save_blob(change.before)?,
+                after: self.save_blob(change.after)?,
+            });
+        }
+        let entry = JournalEntry {
+            call_id: call_id.to_string(),
+            time: chrono::Utc::now().to_rfc3339(),
+            files,
+        };
+        let mut journal = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(JOURNAL_FILE))?;
+        writeln!(journal, "{}", serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+
+    // Put back the files changed by the last `calls` tool calls, newest first. Files changed
+    // since, by hand or otherwise, are left alone unless `force` is set, and stay in the journal.
+    // Restored files are forgotten.
+    pub fn undo(&self, calls: usize, force: bool) -> Result<UndoReport, AppError> {
+        let mut entries = self.entries()?;
+        // A pipeline_tool call can leave several entries with the same call id
+        let mut split = entries.len();
+        let mut undone = 0;
+        while split > 0 && undone < calls {
+            let call_id = entries[split - 1].call_id.clone();
+            while split > 0 && entries[split - 1].call_id == call_id {
+                split -= 1;
+            }
+            undone += 1;
+        }
+        if undone == 0 {
+            return Err(AppError::CommandError(
+                "No file changes to undo in this conversation".to_string(),
+            ));
+        }
+        let undo = entries.split_off(split);
+
+        let mut report = UndoReport {
+            calls: undone,
+            ..UndoReport::default()
+        };
+        let mut restored = Vec::new();
+        let mut skipped_entries = Vec::new();
+        for entry in undo.into_iter().rev() {
+            let mut kept = Vec::new();
+            for file in entry.files.into_iter().rev() {
+                if !force && read_if_exists(&file.path)? != self.load_blob(&file.after)? {
+                    if !report.skipped.contains(&file.path) {
+                        report.skipped.push(file.path.clone());
+                    }
+                    kept.push(file);
+                    continue;
+                }
+                match self.load_blob(&file.before)? {
+                    Some(content) => fs::write(&file.path, content)?,
+                    // Already gone, if deleted by hand and forced
+                    None if file.path.exists() => fs::remove_file(&file.path)?,
+                    None => {}
+                }
+                if !report.restored.contains(&file.path) {
+                    report.restored.push(file.path.clone());
+                }
+                restored.push(file);
+            }
+            if !kept.is_empty() {
+                kept.reverse();
+                skipped_entries.push(JournalEntry {
+                    files: kept,
+                    ..entry
+                });
+            }
+        }
+        skipped_entries.reverse();
+        entries.extend(skipped_entries);
+
+        self.write_entries(&entries)?;
+        for blob in restored
+            .iter()
+            .flat_map(|file| [&file.before, &file.after])
+            .flatten()
+        {
+            let _ = fs::remove_file(self.dir.join(BLOBS_DIR).join(blob));
+        }
+        Ok(report)
+    }
+
+    // Every file touched, in the order first touched
+    pub fn changes(&self) -> Result<Vec<SessionChange>, AppError> {
+        let mut changes: Vec<SessionChange> = Vec::new();
+        for entry in self.entries()? {
+            for file in entry.files {
+                match changes.iter_mut().find(|change| change.path == file.path) {
+                    Some(change) => change.calls += 1,
+                    None => changes.push(SessionChange {
+                        original: self.load_blob(&file.before)?,
+                        current: None,
+                        path: file.path,
+                        calls: 1,
+                    }),
+                }
+            }
+        }
+        for change in &mut changes {
+            change.current = read_if_exists(&change.path)?;
+        }
+        Ok(changes)
+    }
+
+    fn entries(&self) -> Result<Vec<JournalEntry>, AppError> {
+        let path = self.dir.join(JOURNAL_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut entries = Vec::new();
+        for line in BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    // Replaced in one rename, so a failed write leaves the old journal
+    fn write_entries(&self, entries: &[JournalEntry]) -> Result<(), AppError> {
+        let mut journal = String::new();
+        for entry in entries {
+            journal.push_str(&serde_json::to_string(entry)?);
+            journal.push('\n');
+        }
+        let temp = self.dir.join(format!("{}.tmp", JOURNAL_FILE));
+        fs::write(&temp, journal)?;
+        fs::rename(temp, self.dir.join(JOURNAL_FILE))?;
+        Ok(())
+    }
+
+    fn save_blob(&self, content: Option<Vec<u8>>) -> Result<Option<String>, AppError> {
+        let Some(content) = content else {
+            return Ok(None);
+        };
+        let name = uuid::Uuid::new_v4().to_string();
+        fs::write(self.dir.join(BLOBS_DIR).join(&name), content)?;
+        Ok(Some(name))
+    }
+
+    fn load_blob(&self, name: &Option<String>) -> Result<Option<Vec<u8>>, AppError> {
+        match name {
+            Some(name) => Ok(Some(fs::read(self.dir.join(BLOBS_DIR).join(name))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, AppError> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(path: &Path, before: Option<&str>, after: Option<&str>) -> FileChange {
+        FileChange {
+            path: path.to_path_buf(),
+            before: before.map(|content| content.as_bytes().to_vec()),
+            after: after.map(|content| content.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_undo_and_changes() {
+        let dir = std::env::temp_dir().join(format!("rtool_backups_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let backups = Backups::in_dir(dir.join("backups"));
+        let (edited, created) = (dir.join("edited.txt"), dir.join("created.txt"));
+
+        // Call 1 edits a file; call 2, a pipeline, edits it again in one step and creates
+        // another in the next
+        fs::write(&edited, "v2").unwrap();
+        backups
+            .record("call_1", vec![change(&edited, Some("v1"), Some("v2"))])
+            .unwrap();
+        fs::write(&edited, "v3").unwrap();
+        fs::write(&created, "new").unwrap();
+        backups
+            .record("call_2", vec![change(&edited, Some("v2"), Some("v3"))])
+            .unwrap();
+        backups
+            .record("call_2", vec![change(&created, None, Some("new"))])
+            .unwrap();
+
+        let changes = backups.changes().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].original.as_deref(), Some("v1".as_bytes()));
+        assert_eq!(changes[0].current.as_deref(), Some("v3".as_bytes()));
+        assert_eq!(changes[0].calls, 2);
+        assert_eq!(changes[1].original, None);
+
+        let report = backups.undo(1, false).unwrap();
+        assert_eq!(report.calls, 1);
+        assert_eq!(report.restored, vec![created.clone(), edited.clone()]);
+        assert_eq!(fs::read_to_string(&edited).unwrap(), "v2");
+        assert!(!created.exists());
+
+        // Edited by hand since call 1, so undoing it leaves the file alone and keeps its backup
+        fs::write(&edited, "by hand").unwrap();
+        let report = backups.undo(5, false).unwrap();
+        assert_eq!(report.calls, 1);
+        assert_eq!(report.skipped, vec![edited.clone()]);
+        assert_eq!(fs::read_to_string(&edited).unwrap(), "by hand");
+        assert_eq!(backups.changes().unwrap().len(), 1);
+
+        // Until the undo is forced
+        let report = backups.undo(1, true).unwrap();
+        assert_eq!(report.restored, vec![edited.clone()]);
+        assert_eq!(fs::read_to_string(&edited).unwrap(), "v1");
+        assert!(backups.undo(1, false).is_err());
+        assert_eq!(fs::read_dir(dir.join("backups/blobs")).unwrap().count(), 0);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
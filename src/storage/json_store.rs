@@ -0,0 +1,205 @@
+use crate::{
+    assistant::conversation_manager::title_from_prompt,
+    models::types::{AppError, ConversationMetadata, ConversationRecord, Message, Usage},
+};
+
+use super::{
+    search::{rank, SearchHit, SearchQuery},
+    ConversationStore,
+};
+
+use serde_derive::Deserialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Conversations written before metadata existed are a bare array of messages
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredConversation {
+    Record(ConversationRecord),
+    Legacy(Vec<Message>),
+}
+
+// One `<id>.json` file per conversation, rewritten in full on every change
+pub struct JsonFileStore {
+    dir: PathBuf,
+}
+
+impl JsonFileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, conversation_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", conversation_id))
+    }
+}
+
+// Write to a temporary file and rename it over the target, so a crash never leaves a
+// half-written conversation behind. Each write has its own temporary file, so two processes saving
+// the same conversation can't mix their contents.
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), AppError> {
+    let temp_path = path.with_extension(format!("json.{}.tmp", uuid::Uuid::new_v4()));
+    let result = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    Ok(result?)
+}
+
+impl ConversationStore for JsonFileStore {
+    fn load(
+        &self,
+        conversation_id: &str,
+    ) -> Result<(ConversationMetadata, Vec<Message>), AppError> {
+        let path = self.path(conversation_id);
+        let content = std::fs::read_to_string(&path)?;
+
+        match serde_json::from_str(&content)? {
+            StoredConversation::Record(record) => Ok((record.metadata, record.messages)),
+            StoredConversation::Legacy(messages) => {
+                let modified: chrono::DateTime<chrono::Utc> =
+                    std::fs::metadata(&path)?.modified()?.into();
+                let title = messages
+                    .iter()
+                    .find(|message| message.role == "user")
+                    .and_then(|message| message.content.as_deref())
+                    .map(title_from_prompt);
+                let metadata = ConversationMetadata {
+                    id: conversation_id.to_string(),
+                    title,
+                    model: "unknown".to_string(),
+                    created_at: modified.to_rfc3339(),
+                    updated_at: modified.to_rfc3339(),
+                    usage: Usage::default(),
+                    parent: None,
+                };
+                Ok((metadata, messages))
+            }
+        }
+    }
+
+    fn list(&self) -> Result<Vec<ConversationMetadata>, AppError> {
+        let mut conversations = Vec::new();
+        if !self.dir.exists() {
+            return Ok(conversations);
+        }
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            match self.load(id) {
+                Ok((metadata, _)) => conversations.push(metadata),
+                Err(e) => log::warn!("Skipping unreadable conversation {}: {}", id, e),
+            }
+        }
+
+        conversations.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+        Ok(conversations)
+    }
+
+    fn save(&self, metadata: &ConversationMetadata, messages: &[Message]) -> Result<(), AppError> {
+        let record = serde_json::json!({ "metadata": metadata, "messages": messages });
+        write_atomically(
+            &self.path(&metadata.id),
+            serde_json::to_string(&record)?.as_bytes(),
+        )
+    }
+
+    fn append(
+        &self,
+        metadata: &ConversationMetadata,
+        messages: &[Message],
+        _from: usize,
+    ) -> Result<(), AppError> {
+        self.save(metadata, messages)
+    }
+
+    // The running totals in the metadata are all this backend keeps
+    fn record_usage(&self, _conversation_id: &str, _usage: &Usage) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn delete(&self, conversation_id: &str) -> Result<(), AppError> {
+        std::fs::remove_file(self.path(conversation_id)).map_err(AppError::from)
+    }
+
+    // There is no persistent index, so every conversation is read and ranked per query
+    fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, AppError> {
+        let mut conversations = Vec::new();
+        for metadata in self.list()? {
+            if query.matches_conversation(&metadata) {
+                conversations.push(self.load(&metadata.id)?);
+            }
+        }
+        rank(&conversations, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assistant::conversation_manager::new_conversation_id;
+
+    fn temp_store() -> JsonFileStore {
+        let dir = std::env::temp_dir().join(format!("rtool-json-store-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        JsonFileStore::new(dir)
+    }
+
+    fn metadata(id: &str) -> ConversationMetadata {
+        ConversationMetadata {
+            id: id.to_string(),
+            title: None,
+            model: "m".to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            usage: Usage::default(),
+            parent: None,
+        }
+    }
+
+    #[test]
+    fn test_save_load_and_delete() {
+        let store = temp_store();
+        let id = new_conversation_id();
+        let messages = vec![Message::new("user".to_string(), "hi".to_string())];
+
+        store.save(&metadata(&id), &messages).unwrap();
+        let (loaded, loaded_messages) = store.load(&id).unwrap();
+        assert_eq!(loaded.id, id);
+        assert_eq!(loaded_messages.len(), 1);
+        // Nothing is left behind from the atomic write
+        assert_eq!(std::fs::read_dir(&store.dir).unwrap().count(), 1);
+
+        store.delete(&id).unwrap();
+        assert!(store.list().unwrap().is_empty());
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_conversations_still_load() {
+        let store = temp_store();
+        std::fs::write(
+            store.path("old"),
+            r#"[{"role": "user", "content": "hello there"}]"#,
+        )
+        .unwrap();
+
+        let (metadata, messages) = store.load("old").unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("hello there"));
+        assert_eq!(messages.len(), 1);
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+pub mod backups;
+pub mod json_store;
+pub mod search;
+pub mod sqlite_store;
+
+use crate::models::types::{AppError, ConversationMetadata, Message, Usage};
+
+use json_store::JsonFileStore;
+use search::{SearchHit, SearchQuery};
+use sqlite_store::SqliteStore;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+pub const CONVERSATIONS_DIR: &str = "conversations";
+const SQLITE_FILE: &str = "conversations.db";
+
+// Where conversations are persisted. Implementations must leave a conversation either fully
+// updated or untouched if a write fails part way.
+pub trait ConversationStore: Send + Sync {
+    fn load(&self, conversation_id: &str)
+        -> Result<(ConversationMetadata, Vec<Message>), AppError>;
+
+    // Metadata for every stored conversation, most recently updated first
+    fn list(&self) -> Result<Vec<ConversationMetadata>, AppError>;
+
+    // Replace everything stored for the conversation
+    fn save(&self, metadata: &ConversationMetadata, messages: &[Message]) -> Result<(), AppError>;
+
+    // Store the metadata and `messages[from..]`, which follow what is already stored. Backends
+    // that cannot append cheaply may rewrite the whole conversation.
+    fn append(
+        &self,
+        metadata: &ConversationMetadata,
+        messages: &[Message],
+        from: usize,
+    ) -> Result<(), AppError>;
+
+    // Keep a row for the usage of a single completion; totals live in the metadata
+    fn record_usage(&self, conversation_id: &str, usage: &Usage) -> Result<(), AppError>;
+
+    fn delete(&self, conversation_id: &str) -> Result<(), AppError>;
+
+    // Messages containing every word of the query, best BM25 match first
+    fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, AppError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    // One JSON file per conversation
+    Json,
+    // A single embedded SQLite database
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(StorageKind::Json),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            other => Err(AppError::CommandError(format!(
+                "Unknown storage backend `{}` (expected json or sqlite)",
+                other
+            ))),
+        }
+    }
+}
+
+pub fn open_store(kind: StorageKind, dir: &Path) -> Result<Box<dyn ConversationStore>, AppError> {
+    std::fs::create_dir_all(dir)?;
+    match kind {
+        StorageKind::Json => Ok(Box::new(JsonFileStore::new(dir))),
+        StorageKind::Sqlite => Ok(Box::new(SqliteStore::open(
+            &dir.join(SQLITE_FILE).to_string_lossy(),
+        )?)),
+    }
+}
+
+static GLOBAL_STORE: OnceLock<Box<dyn ConversationStore>> = OnceLock::new();
+
+// Select the backend at startup; everything reading or writing conversations goes through it
+pub fn set_global_store(store: Box<dyn ConversationStore>) {
+    if GLOBAL_STORE.set(store).is_err() {
+        log::warn!("Conversation store was already set, ignoring");
+    }
+}
+
+pub fn store() -> &'static dyn ConversationStore {
+    GLOBAL_STORE
+        .get_or_init(|| Box::new(JsonFileStore::new(CONVERSATIONS_DIR)))
+        .as_ref()
+}
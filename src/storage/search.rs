@@ -0,0 +1,393 @@
+use crate::models::types::{AppError, ConversationMetadata, Message};
+
+use chrono::NaiveDate;
+use std::collections::HashMap;
+
+// BM25 tuning, the same defaults SQLite's FTS5 uses
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+// Words of context shown around the first match
+const SNIPPET_WORDS: usize = 16;
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+#[derive(Debug, Default, Clone)]
+pub struct SearchQuery {
+    pub text: String,
+    pub role: Option<String>,
+    // Tool results from, or assistant messages calling, this tool
+    pub tool: Option<String>,
+    // Only conversations active between these dates (inclusive)
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub conversation_id: String,
+    pub title: Option<String>,
+    pub message_index: usize,
+    pub role: String,
+    pub snippet: String,
+    // Higher is more relevant
+    pub score: f64,
+}
+
+impl SearchQuery {
+    // Parse the REPL form: words to look for mixed with `role:`, `tool:`, `since:`, `until:` and
+    // `limit:` filters
+    pub fn parse(input: &str) -> Result<Self, AppError> {
+        let mut query = SearchQuery {
+            limit: DEFAULT_SEARCH_LIMIT,
+            ..SearchQuery::default()
+        };
+        let mut words = Vec::new();
+        for word in input.split_whitespace() {
+            match word.split_once(':') {
+                Some(("role", role)) => query.role = Some(role.to_string()),
+                Some(("tool", tool)) => query.tool = Some(tool.to_string()),
+                Some(("since", date)) => query.since = Some(parse_date(date)?),
+                Some(("until", date)) => query.until = Some(parse_date(date)?),
+                Some(("limit", limit)) => {
+                    query.limit = limit.parse().map_err(|_| {
+                        AppError::CommandError(format!("Invalid search limit `{}`", limit))
+                    })?
+                }
+                _ => words.push(word),
+            }
+        }
+        query.text = words.join(" ");
+        Ok(query)
+    }
+
+    pub fn terms(&self) -> Result<Vec<String>, AppError> {
+        let terms: Vec<String> = tokenize(&self.text)
+            .into_iter()
+            .map(|(_, term)| term)
+            .collect();
+        if terms.is_empty() {
+            return Err(AppError::CommandError(
+                "The search query has no words to look for".to_string(),
+            ));
+        }
+        Ok(terms)
+    }
+
+    // Lower and exclusive upper bounds to compare RFC3339 timestamps against
+    pub fn date_bounds(&self) -> (Option<String>, Option<String>) {
+        let since = self.since.map(|date| date.format("%Y-%m-%d").to_string());
+        let until = self
+            .until
+            .and_then(|date| date.succ_opt())
+            .map(|date| date.format("%Y-%m-%d").to_string());
+        (since, until)
+    }
+
+    pub fn matches_conversation(&self, metadata: &ConversationMetadata) -> bool {
+        let (since, until) = self.date_bounds();
+        since.is_none_or(|since| metadata.updated_at >= since)
+            && until.is_none_or(|until| metadata.created_at < until)
+    }
+
+    pub fn matches_message(&self, message: &Message) -> bool {
+        let role_matches = self.role.as_ref().is_none_or(|role| &message.role == role);
+        let tool_matches = self.tool.as_ref().is_none_or(|tool| {
+            message.name.as_ref() == Some(tool)
+                || message
+                    .tool_calls
+                    .iter()
+                    .flatten()
+                    .any(|tool_call| &tool_call.function.name == tool)
+        });
+        role_matches && tool_matches
+    }
+}
+
+pub fn parse_date(date: &str) -> Result<NaiveDate, AppError> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| {
+        AppError::CommandError(format!("Invalid date `{}` (expected YYYY-MM-DD)", date))
+    })
+}
+
+// What gets indexed for a message: its content plus the name and arguments of any tool calls
+pub fn searchable_text(message: &Message) -> String {
+    let mut text = message.content.clone().unwrap_or_default();
+    for tool_call in message.tool_calls.iter().flatten() {
+        text.push('\n');
+        text.push_str(&tool_call.function.name);
+        text.push(' ');
+        text.push_str(&tool_call.function.arguments);
+    }
+    text
+}
+
+// Lowercased alphanumeric words with their byte ranges, split like FTS5's default tokenizer
+fn tokenize(text: &str) -> Vec<(std::ops::Range<usize>, String)> {
+    let mut tokens = Vec::new();
+    let mut start: Option<usize> = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                tokens.push((s..i, text[s..i].to_lowercase()));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s..text.len(), text[s..].to_lowercase()));
+    }
+    tokens
+}
+
+// A window of words around the first match, with matching words in [brackets]
+pub fn snippet(text: &str, terms: &[String]) -> String {
+    let tokens = tokenize(text);
+    let first_match = tokens
+        .iter()
+        .position(|(_, token)| terms.contains(token))
+        .unwrap_or(0);
+    let start = first_match.saturating_sub(SNIPPET_WORDS / 3);
+    let end = (start + SNIPPET_WORDS).min(tokens.len());
+    if start >= end {
+        return String::new();
+    }
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push_str("...");
+    }
+    let mut cursor = tokens[start].0.start;
+    for (range, token) in &tokens[start..end] {
+        snippet.push_str(&text[cursor..range.start]);
+        if terms.contains(token) {
+            snippet.push_str(&format!("[{}]", &text[range.clone()]));
+        } else {
+            snippet.push_str(&text[range.clone()]);
+        }
+        cursor = range.end;
+    }
+    if end < tokens.len() {
+        snippet.push_str("...");
+    }
+
+    flatten_whitespace(&snippet)
+}
+
+pub fn flatten_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Rank every message of every conversation that contains all query terms, using BM25 over an
+// index built in memory
+pub fn rank(
+    conversations: &[(ConversationMetadata, Vec<Message>)],
+    query: &SearchQuery,
+) -> Result<Vec<SearchHit>, AppError> {
+    let terms = query.terms()?;
+
+    struct Document<'a> {
+        metadata: &'a ConversationMetadata,
+        message_index: usize,
+        message: &'a Message,
+        text: String,
+        term_counts: HashMap<String, usize>,
+        length: usize,
+    }
+
+    let mut documents = Vec::new();
+    for (metadata, messages) in conversations {
+        for (message_index, message) in messages.iter().enumerate() {
+            let text = searchable_text(message);
+            let tokens = tokenize(&text);
+            let mut term_counts = HashMap::new();
+            for (_, token) in &tokens {
+                *term_counts.entry(token.clone()).or_insert(0) += 1;
+            }
+            documents.push(Document {
+                metadata,
+                message_index,
+                message,
+                length: tokens.len(),
+                text,
+                term_counts,
+            });
+        }
+    }
+    if documents.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let document_count = documents.len() as f64;
+    let average_length = documents
+        .iter()
+        .map(|document| document.length)
+        .sum::<usize>() as f64
+        / document_count;
+    let idf: HashMap<&String, f64> = terms
+        .iter()
+        .map(|term| {
+            let containing = documents
+                .iter()
+                .filter(|document| document.term_counts.contains_key(term))
+                .count() as f64;
+            let idf = ((document_count - containing + 0.5) / (containing + 0.5) + 1.0).ln();
+            (term, idf)
+        })
+        .collect();
+
+    let mut hits: Vec<SearchHit> = documents
+        .iter()
+        .filter(|document| {
+            terms
+                .iter()
+                .all(|term| document.term_counts.contains_key(term))
+        })
+        .filter(|document| {
+            query.matches_conversation(document.metadata) && query.matches_message(document.message)
+        })
+        .map(|document| {
+            let length_norm = 1.0 - B + B * document.length as f64 / average_length;
+            let score = terms
+                .iter()
+                .map(|term| {
+                    let frequency = document.term_counts[term] as f64;
+                    idf[term] * frequency * (K1 + 1.0) / (frequency + K1 * length_norm)
+                })
+                .sum();
+            SearchHit {
+                conversation_id: document.metadata.id.clone(),
+                title: document.metadata.title.clone(),
+                message_index: document.message_index,
+                role: document.message.role.clone(),
+                snippet: snippet(&document.text, &terms),
+                score,
+            }
+        })
+        .collect();
+
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(query.limit);
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::Usage;
+
+    fn conversation(
+        id: &str,
+        updated_at: &str,
+        messages: &[(&str, &str)],
+    ) -> (ConversationMetadata, Vec<Message>) {
+        let metadata = ConversationMetadata {
+            id: id.to_string(),
+            title: None,
+            model: "m".to_string(),
+            created_at: updated_at.to_string(),
+            updated_at: updated_at.to_string(),
+            usage: Usage::default(),
+            parent: None,
+        };
+        let messages = messages
+            .iter()
+            .map(|(role, content)| Message::new(role.to_string(), content.to_string()))
+            .collect();
+        (metadata, messages)
+    }
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery {
+            text: text.to_string(),
+            limit: 10,
+            ..SearchQuery::default()
+        }
+    }
+
+    #[test]
+    fn test_rank_prefers_denser_matches_and_requires_all_terms() {
+        let conversations = vec![
+            conversation(
+                "a",
+                "2024-01-05T10:00:00+00:00",
+                &[
+                    ("user", "how do I configure the parser"),
+                    (
+                        "assistant",
+                        "parser parser: set the parser option in config",
+                    ),
+                ],
+            ),
+            conversation(
+                "b",
+                "2024-02-05T10:00:00+00:00",
+                &[(
+                    "user",
+                    "the parser crashed while reading a long configuration file today",
+                )],
+            ),
+        ];
+
+        let hits = rank(&conversations, &query("Parser")).unwrap();
+        assert_eq!(hits.len(), 3);
+        assert_eq!(
+            (hits[0].conversation_id.as_str(), hits[0].message_index),
+            ("a", 1)
+        );
+
+        let hits = rank(&conversations, &query("parser crashed")).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].conversation_id, "b");
+        assert!(hits[0].snippet.contains("[parser] [crashed]"));
+    }
+
+    #[test]
+    fn test_filters() {
+        let conversations = vec![
+            conversation(
+                "a",
+                "2024-01-05T10:00:00+00:00",
+                &[("user", "parser"), ("assistant", "parser")],
+            ),
+            conversation("b", "2024-02-05T10:00:00+00:00", &[("user", "parser")]),
+        ];
+
+        let mut by_role = query("parser");
+        by_role.role = Some("assistant".to_string());
+        assert_eq!(rank(&conversations, &by_role).unwrap().len(), 1);
+
+        let mut by_date = query("parser");
+        by_date.since = NaiveDate::from_ymd_opt(2024, 2, 1);
+        let hits = rank(&conversations, &by_date).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].conversation_id, "b");
+
+        by_date.since = None;
+        by_date.until = NaiveDate::from_ymd_opt(2024, 1, 5);
+        assert_eq!(rank(&conversations, &by_date).unwrap().len(), 2);
+
+        assert!(rank(&conversations, &query("  ?! ")).is_err());
+    }
+
+    #[test]
+    fn test_parse_repl_query() {
+        let query =
+            SearchQuery::parse("retry backoff role:assistant since:2024-03-01 limit:5").unwrap();
+        assert_eq!(query.text, "retry backoff");
+        assert_eq!(query.role.as_deref(), Some("assistant"));
+        assert_eq!(query.since, NaiveDate::from_ymd_opt(2024, 3, 1));
+        assert_eq!(query.limit, 5);
+        assert!(SearchQuery::parse("x since:yesterday").is_err());
+    }
+
+    #[test]
+    fn test_snippet_windows_long_text() {
+        let text = format!("{} needle {}", "word ".repeat(20), "tail ".repeat(20));
+        let snippet = snippet(&text, &["needle".to_string()]);
+        assert!(snippet.starts_with("...word"));
+        assert!(snippet.contains("[needle]"));
+        assert!(snippet.ends_with("..."));
+    }
+}
@@ -0,0 +1,544 @@
+use crate::models::types::{
+    AppError, ConversationMetadata, ForkOrigin, FunctionCall, Message, ToolCall, Usage,
+};
+
+use super::{
+    search::{flatten_whitespace, searchable_text, SearchHit, SearchQuery},
+    ConversationStore,
+};
+
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS conversations (
+    id TEXT PRIMARY KEY,
+    title TEXT,
+    model TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL DEFAULT 0,
+    completion_tokens INTEGER NOT NULL DEFAULT 0,
+    total_tokens INTEGER NOT NULL DEFAULT 0,
+    parent_id TEXT,
+    parent_message_index INTEGER
+);
+CREATE TABLE IF NOT EXISTS messages (
+    conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    message_index INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT,
+    tool_call_id TEXT,
+    name TEXT,
+    PRIMARY KEY (conversation_id, message_index)
+);
+CREATE TABLE IF NOT EXISTS tool_calls (
+    conversation_id TEXT NOT NULL,
+    message_index INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    id TEXT NOT NULL,
+    type TEXT NOT NULL,
+    name TEXT NOT NULL,
+    arguments TEXT NOT NULL,
+    PRIMARY KEY (conversation_id, message_index, position),
+    FOREIGN KEY (conversation_id, message_index)
+        REFERENCES messages(conversation_id, message_index) ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    recorded_at TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    total_tokens INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS usage_conversation ON usage(conversation_id);
+-- Message text, tool names and tool arguments, for full-text search across sessions
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+    text,
+    conversation_id UNINDEXED,
+    message_index UNINDEXED
+);
+";
+
+const METADATA_COLUMNS: &str = "id, title, model, created_at, updated_at, prompt_tokens, completion_tokens, total_tokens, parent_id, parent_message_index";
+
+// Conversations in a single SQLite database; new messages are inserted rather than the whole
+// history being rewritten, and every write happens in a transaction
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, AppError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        // A panic while holding the lock cannot leave a half-applied transaction behind
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn metadata_from_row(row: &Row) -> rusqlite::Result<ConversationMetadata> {
+    let parent_id: Option<String> = row.get(8)?;
+    let parent_message_index: Option<usize> = row.get(9)?;
+
+    Ok(ConversationMetadata {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        model: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+        usage: Usage {
+            prompt_tokens: row.get(5)?,
+            completion_tokens: row.get(6)?,
+            total_tokens: row.get(7)?,
+        },
+        parent: parent_id
+            .zip(parent_message_index)
+            .map(|(conversation_id, message_index)| ForkOrigin {
+                conversation_id,
+                message_index,
+            }),
+    })
+}
+
+fn upsert_conversation(tx: &Transaction, metadata: &ConversationMetadata) -> Result<(), AppError> {
+    // `INSERT OR REPLACE` would delete the row first and cascade to its messages
+    tx.execute(
+        "INSERT INTO conversations (id, title, model, created_at, updated_at, prompt_tokens, completion_tokens, total_tokens, parent_id, parent_message_index)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT(id) DO UPDATE SET
+            title = excluded.title,
+            model = excluded.model,
+            updated_at = excluded.updated_at,
+            prompt_tokens = excluded.prompt_tokens,
+            completion_tokens = excluded.completion_tokens,
+            total_tokens = excluded.total_tokens,
+            parent_id = excluded.parent_id,
+            parent_message_index = excluded.parent_message_index",
+        params![
+            metadata.id,
+            metadata.title,
+            metadata.model,
+            metadata.created_at,
+            metadata.updated_at,
+            metadata.usage.prompt_tokens,
+            metadata.usage.completion_tokens,
+            metadata.usage.total_tokens,
+            metadata.parent.as_ref().map(|parent| &parent.conversation_id),
+            metadata.parent.as_ref().map(|parent| parent.message_index),
+        ],
+    )?;
+    Ok(())
+}
+
+// Drop stored messages from index `from` on, together with their tool calls and search entries
+fn truncate_messages(tx: &Transaction, conversation_id: &str, from: usize) -> Result<(), AppError> {
+    tx.execute(
+        "DELETE FROM messages WHERE conversation_id = ?1 AND message_index >= ?2",
+        params![conversation_id, from],
+    )?;
+    tx.execute(
+        "DELETE FROM messages_fts WHERE conversation_id = ?1 AND message_index >= ?2",
+        params![conversation_id, from],
+    )?;
+    Ok(())
+}
+
+fn insert_messages(
+    tx: &Transaction,
+    conversation_id: &str,
+    messages: &[Message],
+    from: usize,
+) -> Result<(), AppError> {
+    let mut insert_message = tx.prepare_cached(
+        "INSERT INTO messages (conversation_id, message_index, role, content, tool_call_id, name)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    let mut insert_tool_call = tx.prepare_cached(
+        "INSERT INTO tool_calls (conversation_id, message_index, position, id, type, name, arguments)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    let mut insert_search_text = tx.prepare_cached(
+        "INSERT INTO messages_fts (text, conversation_id, message_index) VALUES (?1, ?2, ?3)",
+    )?;
+
+    for (index, message) in messages.iter().enumerate().skip(from) {
+        insert_message.execute(params![
+            conversation_id,
+            index,
+            message.role,
+            message.content,
+            message.tool_call_id,
+            message.name,
+        ])?;
+        for (position, tool_call) in message.tool_calls.iter().flatten().enumerate() {
+            insert_tool_call.execute(params![
+                conversation_id,
+                index,
+                position,
+                tool_call.id,
+                tool_call.r#type,
+                tool_call.function.name,
+                tool_call.function.arguments,
+            ])?;
+        }
+        insert_search_text.execute(params![searchable_text(message), conversation_id, index])?;
+    }
+
+    Ok(())
+}
+
+impl ConversationStore for SqliteStore {
+    fn load(
+        &self,
+        conversation_id: &str,
+    ) -> Result<(ConversationMetadata, Vec<Message>), AppError> {
+        let connection = self.connection();
+        let metadata = connection
+            .query_row(
+                &format!(
+                    "SELECT {} FROM conversations WHERE id = ?1",
+                    METADATA_COLUMNS
+                ),
+                [conversation_id],
+                metadata_from_row,
+            )
+            .optional()?
+            .ok_or_else(|| {
+                AppError::CommandError(format!("Conversation `{}` not found", conversation_id))
+            })?;
+
+        let mut tool_calls: HashMap<usize, Vec<ToolCall>> = HashMap::new();
+        let mut statement = connection.prepare(
+            "SELECT message_index, id, type, name, arguments FROM tool_calls
+             WHERE conversation_id = ?1 ORDER BY message_index, position",
+        )?;
+        let rows = statement.query_map([conversation_id], |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                ToolCall {
+                    id: row.get(1)?,
+                    r#type: row.get(2)?,
+                    function: FunctionCall {
+                        name: row.get(3)?,
+                        arguments: row.get(4)?,
+                    },
+                },
+            ))
+        })?;
+        for row in rows {
+            let (message_index, tool_call) = row?;
+            tool_calls.entry(message_index).or_default().push(tool_call);
+        }
+
+        let mut statement = connection.prepare(
+            "SELECT message_index, role, content, tool_call_id, name FROM messages
+             WHERE conversation_id = ?1 ORDER BY message_index",
+        )?;
+        let messages = statement
+            .query_map([conversation_id], |row| {
+                let message_index: usize = row.get(0)?;
+                Ok(Message {
+                    role: row.get(1)?,
+                    content: row.get(2)?,
+                    tool_calls: tool_calls.remove(&message_index),
+                    tool_call_id: row.get(3)?,
+                    name: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((metadata, messages))
+    }
+
+    fn list(&self) -> Result<Vec<ConversationMetadata>, AppError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {} FROM conversations ORDER BY updated_at DESC",
+            METADATA_COLUMNS
+        ))?;
+        let conversations = statement
+            .query_map([], metadata_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(conversations)
+    }
+
+    fn save(&self, metadata: &ConversationMetadata, messages: &[Message]) -> Result<(), AppError> {
+        let mut connection = self.connection();
+        let tx = connection.transaction()?;
+        upsert_conversation(&tx, metadata)?;
+        truncate_messages(&tx, &metadata.id, 0)?;
+        insert_messages(&tx, &metadata.id, messages, 0)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn append(
+        &self,
+        metadata: &ConversationMetadata,
+        messages: &[Message],
+        from: usize,
+    ) -> Result<(), AppError> {
+        let mut connection = self.connection();
+        let tx = connection.transaction()?;
+        upsert_conversation(&tx, metadata)?;
+        // Anything already stored at these indices is stale
+        truncate_messages(&tx, &metadata.id, from)?;
+        insert_messages(&tx, &metadata.id, messages, from)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn record_usage(&self, conversation_id: &str, usage: &Usage) -> Result<(), AppError> {
+        self.connection().execute(
+            "INSERT INTO usage (conversation_id, recorded_at, prompt_tokens, completion_tokens, total_tokens)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                conversation_id,
+                chrono::Utc::now().to_rfc3339(),
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, conversation_id: &str) -> Result<(), AppError> {
+        let mut connection = self.connection();
+        let tx = connection.transaction()?;
+        let deleted = tx.execute("DELETE FROM conversations WHERE id = ?1", [conversation_id])?;
+        if deleted == 0 {
+            return Err(AppError::CommandError(format!(
+                "Conversation `{}` not found",
+                conversation_id
+            )));
+        }
+        tx.execute(
+            "DELETE FROM messages_fts WHERE conversation_id = ?1",
+            [conversation_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, AppError> {
+        // Quote every word so punctuation in the query is never read as FTS5 syntax
+        let match_expression = query
+            .terms()?
+            .iter()
+            .map(|term| format!("\"{}\"", term))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let (since, until) = query.date_bounds();
+
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT messages_fts.conversation_id, messages_fts.message_index, c.title, m.role,
+                    snippet(messages_fts, 0, '[', ']', '...', 16), bm25(messages_fts)
+             FROM messages_fts
+             JOIN conversations c ON c.id = messages_fts.conversation_id
+             JOIN messages m ON m.conversation_id = messages_fts.conversation_id
+                AND m.message_index = messages_fts.message_index
+             WHERE messages_fts MATCH ?1
+                AND (?2 IS NULL OR m.role = ?2)
+                AND (?3 IS NULL OR m.name = ?3 OR EXISTS (
+                    SELECT 1 FROM tool_calls t
+                    WHERE t.conversation_id = m.conversation_id
+                        AND t.message_index = m.message_index AND t.name = ?3))
+                AND (?4 IS NULL OR c.updated_at >= ?4)
+                AND (?5 IS NULL OR c.created_at < ?5)
+             ORDER BY bm25(messages_fts)
+             LIMIT ?6",
+        )?;
+        let hits = statement
+            .query_map(
+                params![
+                    match_expression,
+                    query.role,
+                    query.tool,
+                    since,
+                    until,
+                    query.limit
+                ],
+                |row| {
+                    Ok(SearchHit {
+                        conversation_id: row.get(0)?,
+                        message_index: row.get(1)?,
+                        title: row.get(2)?,
+                        role: row.get(3)?,
+                        snippet: flatten_whitespace(&row.get::<_, String>(4)?),
+                        // FTS5 scores are negative, lower being better
+                        score: -row.get::<_, f64>(5)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(id: &str) -> ConversationMetadata {
+        ConversationMetadata {
+            id: id.to_string(),
+            title: Some("title".to_string()),
+            model: "m".to_string(),
+            created_at: "2024-01-01T00:00:00+00:00".to_string(),
+            updated_at: "2024-01-01T00:00:00+00:00".to_string(),
+            usage: Usage::default(),
+            parent: Some(ForkOrigin {
+                conversation_id: "parent".to_string(),
+                message_index: 2,
+            }),
+        }
+    }
+
+    fn tool_request() -> Message {
+        Message {
+            role: "assistant".to_string(),
+            content: None,
+            tool_calls: Some(vec![ToolCall {
+                id: "call_1".to_string(),
+                r#type: "function".to_string(),
+                function: FunctionCall {
+                    name: "shell_tool".to_string(),
+                    arguments: r#"{"commands":[{"command":"cargo","args":["build"]}]}"#.to_string(),
+                },
+            }]),
+            tool_call_id: None,
+            name: None,
+        }
+    }
+
+    fn count(store: &SqliteStore, table: &str) -> usize {
+        store
+            .connection()
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_with_appends() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut metadata = metadata("c1");
+        let mut messages = vec![Message::new("user".to_string(), "build it".to_string())];
+        store.save(&metadata, &messages).unwrap();
+
+        messages.push(tool_request());
+        metadata.usage.total_tokens = 42;
+        store.append(&metadata, &messages, 1).unwrap();
+        store.record_usage("c1", &metadata.usage).unwrap();
+
+        let (loaded, loaded_messages) = store.load("c1").unwrap();
+        assert_eq!(loaded.usage.total_tokens, 42);
+        assert_eq!(loaded.parent, metadata.parent);
+        assert_eq!(loaded_messages.len(), 2);
+        let tool_calls = loaded_messages[1].tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].function.name, "shell_tool");
+        assert!(loaded_messages[0].tool_calls.is_none());
+        assert_eq!(count(&store, "usage"), 1);
+        assert_eq!(count(&store, "messages_fts"), 2);
+    }
+
+    #[test]
+    fn test_metadata_update_keeps_messages() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut metadata = metadata("c1");
+        let messages = vec![Message::new("user".to_string(), "hello".to_string())];
+        store.save(&metadata, &messages).unwrap();
+
+        metadata.title = Some("renamed".to_string());
+        store.append(&metadata, &messages, messages.len()).unwrap();
+
+        let (loaded, loaded_messages) = store.load("c1").unwrap();
+        assert_eq!(loaded.title.as_deref(), Some("renamed"));
+        assert_eq!(loaded_messages.len(), 1);
+    }
+
+    #[test]
+    fn test_search_with_filters() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let messages = vec![
+            Message::new("user".to_string(), "please build the project".to_string()),
+            tool_request(),
+            Message {
+                role: "tool".to_string(),
+                content: Some("error: could not build `rtool`".to_string()),
+                tool_calls: None,
+                tool_call_id: Some("call_1".to_string()),
+                name: Some("shell_tool".to_string()),
+            },
+        ];
+        store.save(&metadata("c1"), &messages).unwrap();
+
+        let query = |text: &str, role: Option<&str>, tool: Option<&str>| SearchQuery {
+            text: text.to_string(),
+            role: role.map(str::to_string),
+            tool: tool.map(str::to_string),
+            limit: 10,
+            ..SearchQuery::default()
+        };
+
+        assert_eq!(store.search(&query("build", None, None)).unwrap().len(), 3);
+        let hits = store.search(&query("build", Some("tool"), None)).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].message_index, 2);
+        assert!(hits[0].snippet.contains("[build]"));
+        // The assistant's call and the tool's result both belong to shell_tool
+        assert_eq!(
+            store
+                .search(&query("build", None, Some("shell_tool")))
+                .unwrap()
+                .len(),
+            2
+        );
+        // Quotes and operators in the query are treated as plain words
+        assert_eq!(
+            store
+                .search(&query("\"build\" AND (", None, None))
+                .unwrap()
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_delete_cascades() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store
+            .save(&metadata("c1"), &[tool_request(), tool_request()])
+            .unwrap();
+        store.record_usage("c1", &Usage::default()).unwrap();
+
+        store.delete("c1").unwrap();
+        for table in [
+            "conversations",
+            "messages",
+            "tool_calls",
+            "usage",
+            "messages_fts",
+        ] {
+            assert_eq!(count(&store, table), 0, "{} not emptied", table);
+        }
+        assert!(store.delete("c1").is_err());
+        assert!(store.load("c1").is_err());
+    }
+}
@@ -1,19 +1,65 @@
-use crate::models::{traits::Tool, types::AppError};
+use crate::models::{
+    traits::{Tool, ToolContext},
+    types::AppError,
+};
+use crate::storage::backups::{Backups, FileChange};
+use crate::utils::diff::{apply_patch, file_diff, line_ending, unified_diff, AppliedHunk};
 
 use async_trait::async_trait;
-use schemars::schema::RootSchema;
-use schemars::{schema_for, JsonSchema};
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobMatcher};
+use proc_macro_crate::{auto_register, Tool};
+use regex::Regex;
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
-use serde_json::{json, Value as JsonValue};
-use tokio::fs::{self, File};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use serde_json::Value as JsonValue;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+
+// Output of a single read, stat, listdir or grep operation is cut off after this many bytes
+const MAX_OUTPUT_BYTES: usize = 32 * 1024;
+// Longer lines are cut short, so one minified file can't use up the whole output
+const MAX_LINE_CHARS: usize = 500;
+// Files with a NUL byte in this many leading bytes are taken to be binary and not searched
+const BINARY_CHECK_BYTES: usize = 8 * 1024;
+// Larger files aren't searched
+const MAX_GREP_FILE_BYTES: u64 = 4 * 1024 * 1024;
+// How many of the files a directory grep couldn't search are named in its output
+const MAX_SKIP_NOTES: usize = 5;
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 struct FileOperation {
+    /// Type of file operation
     op: FileOpType,
+    /// Path of file to operate upon, or the directory to list or search
     file_path: String,
-    content: Option<String>, // For create, insert, and update operations
-    line: Option<usize>,     // For line-specific operations
+    /// New file contents, the line for insertline and updateline, the text to put in place of
+    /// `anchor` for replace, or the unified diff for applypatch (one file, with correct `@@` line
+    /// counts)
+    content: Option<String>,
+    /// Line number for insertline, updateline, and deleteline operations
+    line: Option<usize>,
+    /// First line to read, counting from 1 (read; default: the first line)
+    start_line: Option<usize>,
+    /// Last line to read, inclusive (read; default: the last line)
+    end_line: Option<usize>,
+    /// Prefix each line with its line number (read; default: false)
+    line_numbers: Option<bool>,
+    /// Regular expression to search for (grep)
+    pattern: Option<String>,
+    /// Only include files whose path relative to `file_path` matches this glob, e.g. `*.rs` (listdir, grep)
+    glob: Option<String>,
+    /// How many levels of subdirectories to list (listdir; default: 1, only the directory itself)
+    depth: Option<usize>,
+    /// Exact text to replace, including enough surrounding lines to appear only once in the file
+    /// (replace)
+    anchor: Option<String>,
+    /// Which appearance of `anchor` to replace, counting from 1, if it appears more than once
+    /// (replace)
+    occurrence: Option<usize>,
+    /// Replace every appearance of `anchor` (replace; default: false)
+    replace_all: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -24,219 +70,910 @@ enum FileOpType {
     InsertLine,
     DeleteLine,
     UpdateLine,
+    Read,
+    Stat,
+    ListDir,
+    Grep,
+    Replace,
+    ApplyPatch,
+}
+
+impl FileOpType {
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            FileOpType::Read | FileOpType::Stat | FileOpType::ListDir | FileOpType::Grep
+        )
+    }
 }
 
 #[derive(JsonSchema, Deserialize)]
 struct FileToolInput {
+    /// The list of file operations to execute
     operations: Vec<FileOperation>,
 }
 
+/// Performs file operations such as create, delete, and update on files. Also reads files or line
+/// ranges of them, stats paths, lists directories and searches files with a regular expression.
+/// Long output is truncated, and says so. Prefer replace and applypatch to the line operations for
+/// edits: they don't depend on line numbers, and return a diff of what changed. The operations in
+/// one call are applied together or not at all, and each sees the changes made by those before it.
+#[derive(Default, Tool)]
+#[tool(name = "file_tool", input = FileToolInput)]
 pub struct FileTool;
 
+#[auto_register]
 #[async_trait]
 impl Tool for FileTool {
-    fn name(&self) -> &'static str {
-        "file_tool"
-    }
-
-    fn description(&self) -> &'static str {
-        "Performs file operations such as create, delete, and update on files."
-    }
-
-    fn parameters(&self) -> JsonValue {
-        // Define and return the JSON parameters schema for file operations
-        json!({
-            "type": "object",
-            "properties": {
-                "operations": {
-                    "type": "array",
-                    "description": "The JSON-encoded list of file operations to execute",
-                    "items": {
-                        "type": "object",
-                        "properties": {
-                            "op": {
-                                "type": "string",
-                                "enum": ["create", "delete", "insertline", "deleteline", "updateline"],
-                                "description": "type of file operation"
-                            },
-                            "file_path": {
-                                "type": "string",
-                                "description": "path of file to operate upon"
-                            },
-                            "content": {
-                                "type": "string",
-                                "description": "new file contents"
-                            },
-                            "line": {
-                                "type": "integer",
-                                "description": "line number for insertline, updateline, and deleteline operations"
-                            }
-                        },
-                        "required": ["op", "file_path"]
-                    }
-                }
+    // All or nothing: every change is staged in memory, and the files are only written once all
+    // of the operations have succeeded
+    async fn execute(&self, args: JsonValue, ctx: &ToolContext) -> Result<String, AppError> {
+        let input = Self::parse_input(args)?;
+
+        let mut staged = StagedFiles::default();
+        // What the read operations found and the edits changed, in order
+        let mut output = Vec::new();
+        for (index, operation) in input.operations.into_iter().enumerate() {
+            let label = OperationLabel::new(index, &operation);
+            match run_operation(operation, &mut staged, &label).await {
+                Ok(Some(text)) => output.push(text),
+                Ok(None) => {}
+                Err(e) => return Err(label.failed(e)),
             }
+        }
+        staged.commit(ctx).await?;
+
+        if output.is_empty() {
+            Ok("File operations completed successfully.".to_string())
+        } else {
+            Ok(output.join("\n"))
+        }
+    }
+
+    // Calls that only read, stat, list or search can be allowed by `read_only` policy rules
+    fn is_read_only(&self, args: &JsonValue) -> bool {
+        Self::parse_input(args.clone()).is_ok_and(|input| {
+            input
+                .operations
+                .iter()
+                .all(|operation| operation.op.is_read_only())
         })
     }
 
-    async fn execute(&self, args: JsonValue) -> Result<String, AppError> {
-        let input: FileToolInput = serde_json::from_value(args)?;
+    // Diffs of what the batch would change, staged just as `execute` would stage it
+    async fn preview(&self, args: &JsonValue) -> Option<String> {
+        let input = Self::parse_input(args.clone()).ok()?;
+        let mut staged = StagedFiles::default();
+        for (index, operation) in input.operations.into_iter().enumerate() {
+            let label = OperationLabel::new(index, &operation);
+            if let Err(e) = run_operation(operation, &mut staged, &label).await {
+                return Some(label.would_fail(e));
+            }
+        }
+        staged.preview().await
+    }
+}
 
-        for operation in input.operations {
-            match operation.op {
-                FileOpType::Create => {
-                    if let Some(content) = operation.content {
-                        create_file(&operation.file_path, &content).await?;
-                    } else {
-                        return Err(AppError::CommandError(
-                            "Missing file content for create operation".to_string(),
-                        ));
-                    }
-                }
-                FileOpType::Delete => {
-                    delete_file(&operation.file_path).await?;
-                }
-                FileOpType::InsertLine => {
-                    if let (Some(content), Some(line)) = (operation.content, operation.line) {
-                        insert_line(&operation.file_path, line, &content).await?;
-                    } else {
-                        return Err(AppError::CommandError(
-                            "Missing line content or line number for insert line operation"
-                                .to_string(),
-                        ));
-                    }
-                }
-                FileOpType::DeleteLine => {
-                    if let Some(line) = operation.line {
-                        delete_line(&operation.file_path, line).await?;
-                    } else {
-                        return Err(AppError::CommandError(
-                            "Missing line number for delete line operation".to_string(),
-                        ));
-                    }
+// Which operation of the batch something happened in, e.g. `/operations/2 (replace src/main.rs)`
+#[derive(Debug, Clone)]
+struct OperationLabel(String);
+
+impl OperationLabel {
+    fn new(index: usize, operation: &FileOperation) -> Self {
+        let op = serde_json::to_value(&operation.op).unwrap_or_default();
+        Self(format!(
+            "/operations/{} ({} {})",
+            index,
+            op.as_str().unwrap_or_default(),
+            operation.file_path
+        ))
+    }
+
+    fn failed(&self, error: AppError) -> AppError {
+        AppError::CommandError(format!(
+            "{} failed: {}. No files were changed.",
+            self.0,
+            error_reason(error)
+        ))
+    }
+
+    fn would_fail(&self, error: AppError) -> String {
+        format!("{} would fail: {}\n", self.0, error_reason(error))
+    }
+}
+
+fn error_reason(error: AppError) -> String {
+    match error {
+        AppError::CommandError(message) => message,
+        error => error.to_string(),
+    }
+}
+
+// Carry out one operation against the staged files. Returns what it has to show, if anything.
+async fn run_operation(
+    operation: FileOperation,
+    staged: &mut StagedFiles,
+    label: &OperationLabel,
+) -> Result<Option<String>, AppError> {
+    let path = operation.file_path.as_str();
+    match operation.op {
+        FileOpType::Create => {
+            let Some(content) = operation.content else {
+                return Err(AppError::CommandError(
+                    "Missing file content for create operation".to_string(),
+                ));
+            };
+            staged.write(path, content, label);
+        }
+        FileOpType::Delete => {
+            // Binary files can be deleted too, so only check that it's there
+            if !staged.exists(path).await {
+                return Err(AppError::CommandError(format!("{} doesn't exist", path)));
+            }
+            staged.delete(path, label);
+        }
+        FileOpType::InsertLine => {
+            let (Some(content), Some(line)) = (operation.content, operation.line) else {
+                return Err(AppError::CommandError(
+                    "Missing line content or line number for insert line operation".to_string(),
+                ));
+            };
+            let old = staged.read_existing(path).await?;
+            staged.write(path, insert_line(&old, line, &content), label);
+        }
+        FileOpType::DeleteLine => {
+            let Some(line) = operation.line else {
+                return Err(AppError::CommandError(
+                    "Missing line number for delete line operation".to_string(),
+                ));
+            };
+            let old = staged.read_existing(path).await?;
+            staged.write(path, delete_line(&old, line)?, label);
+        }
+        FileOpType::UpdateLine => {
+            let (Some(content), Some(line)) = (operation.content, operation.line) else {
+                return Err(AppError::CommandError(
+                    "Missing line content or line number for update line operation".to_string(),
+                ));
+            };
+            let old = staged.read_existing(path).await?;
+            staged.write(path, update_line(&old, line, &content)?, label);
+        }
+        // Reads see the edits made earlier in the batch
+        FileOpType::Read => {
+            let content = staged.read_existing(path).await?;
+            return Ok(Some(read_lines(
+                path,
+                &content,
+                operation.start_line,
+                operation.end_line,
+                operation.line_numbers.unwrap_or(false),
+            )?));
+        }
+        // Stat, listdir and grep look at the disk, as it was before the batch
+        FileOpType::Stat => return Ok(Some(stat_path(path).await?)),
+        FileOpType::ListDir => {
+            let glob = glob_matcher(operation.glob.as_deref())?;
+            let depth = operation.depth.unwrap_or(1);
+            let dir = PathBuf::from(path);
+            return Ok(Some(
+                tokio::task::spawn_blocking(move || list_dir(&dir, depth, glob.as_ref())).await??,
+            ));
+        }
+        FileOpType::Grep => {
+            let Some(pattern) = operation.pattern else {
+                return Err(AppError::CommandError(
+                    "Missing pattern for grep operation".to_string(),
+                ));
+            };
+            let pattern = Regex::new(&pattern)
+                .map_err(|e| AppError::CommandError(format!("Invalid grep pattern: {}", e)))?;
+            let glob = glob_matcher(operation.glob.as_deref())?;
+            let path = PathBuf::from(path);
+            return Ok(Some(
+                tokio::task::spawn_blocking(move || grep(&path, &pattern, glob.as_ref())).await??,
+            ));
+        }
+        FileOpType::Replace => {
+            let (Some(anchor), Some(content)) = (operation.anchor, operation.content) else {
+                return Err(AppError::CommandError(
+                    "Missing anchor or content for replace operation".to_string(),
+                ));
+            };
+            let old = staged.read_existing(path).await?;
+            let new = replace_anchor(
+                &old,
+                &anchor,
+                &content,
+                operation.occurrence,
+                operation.replace_all.unwrap_or(false),
+            )
+            .map_err(|e| AppError::CommandError(format!("{} in {}", e, path)))?;
+            let diff = diff_output(path, &old, &new);
+            staged.write(path, new, label);
+            return Ok(Some(diff));
+        }
+        // A missing file is patched as empty, so a patch can create it
+        FileOpType::ApplyPatch => {
+            let Some(patch) = operation.content else {
+                return Err(AppError::CommandError(
+                    "Missing patch content for applypatch operation".to_string(),
+                ));
+            };
+            let old = staged.read(path).await?.unwrap_or_default();
+            let (new, hunks) = apply_patch(&old, &patch)?;
+            let text = format!(
+                "{}{}",
+                describe_hunks(path, &hunks),
+                diff_output(path, &old, &new)
+            );
+            staged.write(path, new, label);
+            return Ok(Some(text));
+        }
+    }
+    Ok(None)
+}
+
+// A staged change to one file: new contents, or None to delete it
+struct StagedChange {
+    path: PathBuf,
+    content: Option<String>,
+    // The last operation to change the file, blamed if writing it fails
+    label: OperationLabel,
+}
+
+// The files as the batch has left them so far, in the order they were first changed
+#[derive(Default)]
+struct StagedFiles {
+    changes: Vec<StagedChange>,
+}
+
+impl StagedFiles {
+    // `./src/main.rs` and `src/main.rs` are the same file
+    fn key(path: &str) -> PathBuf {
+        Path::new(path)
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect()
+    }
+
+    fn staged(&self, path: &str) -> Option<&StagedChange> {
+        let key = Self::key(path);
+        self.changes.iter().find(|change| change.path == key)
+    }
+
+    // None if the file doesn't exist, or the batch has deleted it
+    async fn read(&self, path: &str) -> Result<Option<String>, AppError> {
+        if let Some(change) = self.staged(path) {
+            return Ok(change.content.clone());
+        }
+        match fs::read_to_string(path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn read_existing(&self, path: &str) -> Result<String, AppError> {
+        self.read(path)
+            .await?
+            .ok_or_else(|| AppError::CommandError(format!("{} doesn't exist", path)))
+    }
+
+    async fn exists(&self, path: &str) -> bool {
+        match self.staged(path) {
+            Some(change) => change.content.is_some(),
+            None => fs::metadata(path).await.is_ok(),
+        }
+    }
+
+    fn write(&mut self, path: &str, content: String, label: &OperationLabel) {
+        self.stage(path, Some(content), label);
+    }
+
+    fn delete(&mut self, path: &str, label: &OperationLabel) {
+        self.stage(path, None, label);
+    }
+
+    fn stage(&mut self, path: &str, content: Option<String>, label: &OperationLabel) {
+        let key = Self::key(path);
+        match self.changes.iter_mut().find(|change| change.path == key) {
+            Some(change) => {
+                change.content = content;
+                change.label = label.clone();
+            }
+            None => self.changes.push(StagedChange {
+                path: key,
+                content,
+                label: label.clone(),
+            }),
+        }
+    }
+
+    // A diff per file against what's on disk now. Binary files are only named.
+    async fn preview(&self) -> Option<String> {
+        let mut preview = CappedOutput::default();
+        for change in &self.changes {
+            let path = change.path.to_string_lossy();
+            let original = fs::read(&change.path).await.ok();
+            let old = match original.as_deref().map(std::str::from_utf8) {
+                Some(Ok(old)) => Some(old),
+                Some(Err(_)) => {
+                    let effect = match change.content {
+                        Some(_) => "overwritten",
+                        None => "deleted",
+                    };
+                    preview.push(&format!("{} (binary) would be {}", path, effect));
+                    continue;
                 }
-                FileOpType::UpdateLine => {
-                    if let (Some(content), Some(line)) = (operation.content, operation.line) {
-                        update_line(&operation.file_path, line, &content).await?;
-                    } else {
-                        return Err(AppError::CommandError(
-                            "Missing line content or line number for update line operation"
-                                .to_string(),
-                        ));
-                    }
+                None => None,
+            };
+            if old.is_some() && old == change.content.as_deref() {
+                continue;
+            }
+            for line in file_diff(&path, old, change.content.as_deref()).lines() {
+                preview.push(line);
+            }
+        }
+        if preview.truncated {
+            preview.text.push_str(&format!(
+                "[preview truncated after {} bytes]\n",
+                MAX_OUTPUT_BYTES
+            ));
+        }
+        (!preview.text.is_empty()).then_some(preview.text)
+    }
+
+    // Write every new file next to the one it replaces, then rename each into place. Deletions
+    // come last. If anything fails, the files already replaced or deleted are put back. Once
+    // done, the old contents are backed up so the call can be undone.
+    async fn commit(self, ctx: &ToolContext) -> Result<(), AppError> {
+        let mut pending = Vec::new();
+        for change in &self.changes {
+            match prepare_change(change).await {
+                Ok(prepared) => pending.push(prepared),
+                Err(e) => {
+                    discard_temp_files(&pending).await;
+                    return Err(change.label.failed(e));
                 }
             }
         }
 
-        Ok("File operations completed successfully.".to_string())
+        // Replacements before deletions, so a failed rename has less to undo
+        let (replacements, deletions): (Vec<&PreparedChange>, Vec<&PreparedChange>) =
+            pending.iter().partition(|prepared| prepared.temp.is_some());
+        let mut done = Vec::new();
+        for prepared in replacements.into_iter().chain(deletions) {
+            let result = match &prepared.temp {
+                Some(temp) => fs::rename(temp, &prepared.change.path).await,
+                None => fs::remove_file(&prepared.change.path).await,
+            };
+            if let Err(e) = result {
+                roll_back(&done).await;
+                discard_temp_files(&pending).await;
+                return Err(prepared.change.label.failed(e.into()));
+            }
+            done.push(prepared);
+        }
+        back_up(&pending, ctx);
+        Ok(())
     }
+}
 
-    fn input_schema(&self) -> RootSchema {
-        schema_for!(FileToolInput)
+// The changes are already made, so a failed backup only loses the chance to undo them
+fn back_up(pending: &[PreparedChange<'_>], ctx: &ToolContext) {
+    let Some(conversation_id) = &ctx.conversation_id else {
+        return;
+    };
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let changes = pending
+        .iter()
+        .map(|prepared| FileChange {
+            path: cwd.join(&prepared.change.path),
+            before: prepared.original.as_ref().map(|(bytes, _)| bytes.clone()),
+            after: prepared.change.content.clone().map(String::into_bytes),
+        })
+        .collect();
+    if let Err(e) = Backups::for_conversation(conversation_id).record(&ctx.call_id, changes) {
+        log::warn!(
+            "Failed to back up the changes of tool call {}: {}",
+            ctx.call_id,
+            e
+        );
     }
 }
 
-async fn create_file(file_path: &str, content: &str) -> Result<(), AppError> {
-    let mut file = File::create(file_path).await.map_err(AppError::from)?;
-    file.write_all(content.as_bytes())
-        .await
-        .map_err(AppError::from)?;
-    Ok(())
+// A change ready to commit: the original file, kept for rolling back, and for a write, the temp
+// file holding the new contents
+struct PreparedChange<'a> {
+    change: &'a StagedChange,
+    original: Option<(Vec<u8>, std::fs::Permissions)>,
+    temp: Option<PathBuf>,
 }
 
-async fn delete_file(file_path: &str) -> Result<(), AppError> {
-    fs::remove_file(file_path).await.map_err(AppError::from)?;
-    Ok(())
+async fn prepare_change(change: &StagedChange) -> Result<PreparedChange<'_>, AppError> {
+    let original = match fs::read(&change.path).await {
+        Ok(bytes) => Some((bytes, fs::metadata(&change.path).await?.permissions())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let temp = match &change.content {
+        Some(content) => {
+            let temp = temp_path(&change.path);
+            fs::write(&temp, content).await?;
+            // Keep the original's permissions, e.g. for executable scripts
+            if let Some((_, permissions)) = &original {
+                fs::set_permissions(&temp, permissions.clone()).await?;
+            }
+            Some(temp)
+        }
+        None => None,
+    };
+    Ok(PreparedChange {
+        change,
+        original,
+        temp,
+    })
 }
 
-async fn insert_line(
-    file_path: &str,
-    line_number: usize,
-    line_content: &str,
-) -> Result<(), AppError> {
-    let file = File::open(file_path).await.map_err(AppError::from)?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-    let mut contents = Vec::new();
+// In the same directory as `path`, so the rename doesn't cross filesystems
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, uuid::Uuid::new_v4()))
+}
+
+async fn discard_temp_files(pending: &[PreparedChange<'_>]) {
+    for temp in pending.iter().filter_map(|prepared| prepared.temp.as_ref()) {
+        let _ = fs::remove_file(temp).await;
+    }
+}
 
-    // Read lines and insert new line at the specified line number
-    let mut current_line = 1;
-    while let Some(line) = lines.next_line().await.map_err(AppError::from)? {
-        if current_line == line_number {
-            contents.push(format!("{}\n", line_content));
+// Put back the originals of files already replaced or deleted
+async fn roll_back(done: &[&PreparedChange<'_>]) {
+    for prepared in done {
+        let path = &prepared.change.path;
+        let result = match &prepared.original {
+            Some((bytes, permissions)) => match fs::write(path, bytes).await {
+                Ok(()) => fs::set_permissions(path, permissions.clone()).await,
+                Err(e) => Err(e),
+            },
+            None => fs::remove_file(path).await,
+        };
+        if let Err(e) = result {
+            log::error!("Failed to roll back {}: {}", path.display(), e);
         }
-        contents.push(format!("{}\n", line));
-        current_line += 1;
     }
+}
+
+// Out-of-range lines go at the end
+fn insert_line(content: &str, line_number: usize, line_content: &str) -> String {
+    let mut lines: Vec<&str> = content.lines().collect();
+    let index = line_number.saturating_sub(1).min(lines.len());
+    lines.insert(index, line_content);
+    join_lines(&lines, line_ending(content))
+}
+
+fn delete_line(content: &str, line_number: usize) -> Result<String, AppError> {
+    let mut lines: Vec<&str> = content.lines().collect();
+    let index = line_index(line_number, lines.len())?;
+    lines.remove(index);
+    Ok(join_lines(&lines, line_ending(content)))
+}
+
+fn update_line(content: &str, line_number: usize, new_content: &str) -> Result<String, AppError> {
+    let mut lines: Vec<&str> = content.lines().collect();
+    let index = line_index(line_number, lines.len())?;
+    lines[index] = new_content;
+    Ok(join_lines(&lines, line_ending(content)))
+}
+
+fn line_index(line_number: usize, line_count: usize) -> Result<usize, AppError> {
+    if line_number == 0 || line_number > line_count {
+        return Err(AppError::CommandError(format!(
+            "No line {}; the file has {} lines",
+            line_number, line_count
+        )));
+    }
+    Ok(line_number - 1)
+}
+
+// Every line ends with a newline, `\r\n` if that's what the file used
+fn join_lines(lines: &[&str], eol: &str) -> String {
+    lines
+        .iter()
+        .map(|line| format!("{}{}", line, eol))
+        .collect()
+}
 
-    // Handle case when line_number is greater than the total number of lines in the file
-    if line_number >= current_line {
-        contents.push(format!("{}\n", line_content));
+// The anchor has to appear exactly once, unless `occurrence` picks one or `replace_all` is set
+fn replace_anchor(
+    content: &str,
+    anchor: &str,
+    replacement: &str,
+    occurrence: Option<usize>,
+    replace_all: bool,
+) -> Result<String, String> {
+    if anchor.is_empty() {
+        return Err("The anchor is empty".to_string());
     }
+    let matches: Vec<usize> = content.match_indices(anchor).map(|(i, _)| i).collect();
+    let start = match (occurrence, replace_all) {
+        (Some(_), true) => return Err("Set occurrence or replace_all, not both".to_string()),
+        _ if matches.is_empty() => return Err("The anchor wasn't found".to_string()),
+        (None, true) => return Ok(content.replace(anchor, replacement)),
+        (Some(n), false) => *matches.get(n.wrapping_sub(1)).ok_or_else(|| {
+            format!(
+                "Occurrence {} asked for, but the anchor appears {} times",
+                n,
+                matches.len()
+            )
+        })?,
+        (None, false) if matches.len() > 1 => {
+            return Err(format!(
+                "The anchor appears {} times; add surrounding lines to make it unique, or set occurrence or replace_all",
+                matches.len()
+            ))
+        }
+        (None, false) => matches[0],
+    };
+    Ok(format!(
+        "{}{}{}",
+        &content[..start],
+        replacement,
+        &content[start + anchor.len()..]
+    ))
+}
 
-    // Write the modified contents back to the file
-    let mut file = File::create(file_path).await.map_err(AppError::from)?;
-    file.write_all(contents.concat().as_bytes())
-        .await
-        .map_err(AppError::from)?;
-    Ok(())
+// How the hunks of a patch fit, noting any that had to be moved or fuzzed
+fn describe_hunks(path: &str, hunks: &[AppliedHunk]) -> String {
+    let mut text = format!(
+        "Applied {} {} to {}",
+        hunks.len(),
+        if hunks.len() == 1 { "hunk" } else { "hunks" },
+        path
+    );
+    for (i, hunk) in hunks.iter().enumerate() {
+        if hunk.offset != 0 || hunk.fuzz != 0 {
+            text.push_str(&format!(
+                "; hunk {} at line {} (offset {}, fuzz {})",
+                i + 1,
+                hunk.line,
+                hunk.offset,
+                hunk.fuzz
+            ));
+        }
+    }
+    text.push('\n');
+    text
 }
 
-async fn delete_line(file_path: &str, line_number: usize) -> Result<(), AppError> {
-    let file = File::open(file_path).await.map_err(AppError::from)?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-    let mut contents = Vec::new();
+// What an edit changed, as a capped unified diff
+fn diff_output(file_path: &str, old: &str, new: &str) -> String {
+    let diff = unified_diff(file_path, old, new);
+    if diff.is_empty() {
+        return format!("No changes to {}\n", file_path);
+    }
+    let mut output = CappedOutput::default();
+    for line in diff.lines() {
+        if !output.push(line) {
+            break;
+        }
+    }
+    let mut text = output.text;
+    if output.truncated {
+        text.push_str(&format!(
+            "[diff truncated after {} bytes; read the file to see the rest]\n",
+            MAX_OUTPUT_BYTES
+        ));
+    }
+    text
+}
 
-    // Read lines and exclude the line to be deleted
-    let mut current_line = 1;
-    while let Some(line) = lines.next_line().await.map_err(AppError::from)? {
-        if current_line != line_number {
-            contents.push(format!("{}\n", line));
+// Output lines up to MAX_OUTPUT_BYTES; anything after that is counted but dropped
+#[derive(Default)]
+struct CappedOutput {
+    text: String,
+    lines: usize,
+    truncated: bool,
+}
+
+impl CappedOutput {
+    // False once the output is full
+    fn push(&mut self, line: &str) -> bool {
+        let line = cut_line(line);
+        if self.truncated || self.text.len() + line.len() + 1 > MAX_OUTPUT_BYTES {
+            self.truncated = true;
+            return false;
         }
-        current_line += 1;
+        self.text.push_str(&line);
+        self.text.push('\n');
+        self.lines += 1;
+        true
     }
+}
 
-    // Write the modified contents back to the file
-    let mut file = File::create(file_path).await.map_err(AppError::from)?;
-    file.write_all(contents.concat().as_bytes())
-        .await
-        .map_err(AppError::from)?;
-    Ok(())
+fn cut_line(line: &str) -> String {
+    match line.char_indices().nth(MAX_LINE_CHARS) {
+        Some((end, _)) => format!("{} [line cut short]", &line[..end]),
+        None => line.to_string(),
+    }
 }
 
-async fn update_line(
+fn read_lines(
     file_path: &str,
-    line_number: usize,
-    new_content: &str,
+    content: &str,
+    start_line: Option<usize>,
+    end_line: Option<usize>,
+    line_numbers: bool,
+) -> Result<String, AppError> {
+    let lines: Vec<&str> = content.lines().collect();
+    if lines.is_empty() {
+        return Ok(format!("{} is empty\n", file_path));
+    }
+
+    let start = start_line.unwrap_or(1).max(1);
+    let end = end_line.unwrap_or(lines.len()).min(lines.len());
+    if start > end {
+        return Err(AppError::CommandError(format!(
+            "No lines {}-{} in {}, which has {} lines",
+            start,
+            end_line.unwrap_or(lines.len()),
+            file_path,
+            lines.len()
+        )));
+    }
+
+    let mut output = CappedOutput::default();
+    for (line, number) in lines[start - 1..end].iter().zip(start..) {
+        let pushed = if line_numbers {
+            output.push(&format!("{}: {}", number, line))
+        } else {
+            output.push(line)
+        };
+        if !pushed {
+            break;
+        }
+    }
+
+    let last = start + output.lines - 1;
+    let mut text = format!(
+        "{} (lines {}-{} of {}):\n{}",
+        file_path,
+        start,
+        last,
+        lines.len(),
+        output.text
+    );
+    if output.truncated {
+        text.push_str(&format!(
+            "[truncated after {} bytes; read on from line {}]\n",
+            MAX_OUTPUT_BYTES,
+            last + 1
+        ));
+    }
+    Ok(text)
+}
+
+// A missing path isn't an error: whether it exists is often the question
+async fn stat_path(path: &str) -> Result<String, AppError> {
+    let metadata = match fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(format!("{}: does not exist\n", path))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let kind = if metadata.is_dir() {
+        "directory"
+    } else if metadata.is_file() {
+        "file"
+    } else {
+        "other"
+    };
+    let mut text = format!("{}: {}, {} bytes", path, kind, metadata.len());
+    if let Ok(modified) = metadata.modified() {
+        let modified: DateTime<Utc> = modified.into();
+        text.push_str(&format!(
+            ", modified {}",
+            modified.format("%Y-%m-%d %H:%M:%S UTC")
+        ));
+    }
+    if metadata.permissions().readonly() {
+        text.push_str(", read-only");
+    }
+    text.push('\n');
+    Ok(text)
+}
+
+fn glob_matcher(glob: Option<&str>) -> Result<Option<GlobMatcher>, AppError> {
+    glob.map(|glob| {
+        Glob::new(glob)
+            .map(|glob| glob.compile_matcher())
+            .map_err(|e| AppError::CommandError(format!("Invalid glob: {}", e)))
+    })
+    .transpose()
+}
+
+// Version control internals are never listed or searched
+fn is_skipped_dir(name: &std::ffi::OsStr) -> bool {
+    name == ".git"
+}
+
+// Entries sorted by name, as (path, is_dir)
+fn sorted_entries(dir: &Path) -> Result<Vec<(PathBuf, bool)>, AppError> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        entries.push((entry.path(), entry.file_type()?.is_dir()));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+// Entries relative to `dir`, with a trailing `/` on directories. With a glob only matching files
+// are listed, though every directory is still walked.
+fn list_dir(dir: &Path, depth: usize, glob: Option<&GlobMatcher>) -> Result<String, AppError> {
+    let mut output = CappedOutput::default();
+    list_entries(dir, dir, depth.max(1), glob, &mut output)?;
+
+    let mut text = format!("{}:\n{}", dir.display(), output.text);
+    if output.lines == 0 && !output.truncated {
+        text.push_str("(no entries)\n");
+    }
+    if output.truncated {
+        text.push_str(&format!(
+            "[truncated after {} entries; list a subdirectory or narrow the glob]\n",
+            output.lines
+        ));
+    }
+    Ok(text)
+}
+
+fn list_entries(
+    root: &Path,
+    dir: &Path,
+    depth: usize,
+    glob: Option<&GlobMatcher>,
+    output: &mut CappedOutput,
+) -> Result<(), AppError> {
+    for (path, is_dir) in sorted_entries(dir)? {
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if is_dir {
+            if glob.is_none() {
+                output.push(&format!("{}/", relative.display()));
+            }
+        } else if glob.is_none_or(|glob| glob.is_match(relative)) {
+            output.push(&relative.display().to_string());
+        }
+        if output.truncated {
+            return Ok(());
+        }
+        if is_dir && depth > 1 && !path.file_name().is_some_and(is_skipped_dir) {
+            list_entries(root, &path, depth - 1, glob, output)?;
+        }
+    }
+    Ok(())
+}
+
+// Matching lines as `path:line: text`, searching `path` itself or every file under it. Under a
+// directory, files and subdirectories that can't be read are skipped and named after the matches.
+fn grep(path: &Path, pattern: &Regex, glob: Option<&GlobMatcher>) -> Result<String, AppError> {
+    let mut output = CappedOutput::default();
+    let mut skipped = Vec::new();
+    if path.is_dir() {
+        grep_dir(path, path, pattern, glob, &mut output, &mut skipped)?;
+    } else {
+        grep_file(path, pattern, &mut output)?;
+    }
+
+    let mut text = output.text;
+    if output.lines == 0 {
+        text.push_str(&format!("No matches for `{}`\n", pattern));
+    }
+    if output.truncated {
+        text.push_str(&format!(
+            "[truncated after {} matches; narrow the pattern, path or glob]\n",
+            output.lines
+        ));
+    }
+    if !skipped.is_empty() {
+        let mut notes = skipped[..skipped.len().min(MAX_SKIP_NOTES)].join("; ");
+        if skipped.len() > MAX_SKIP_NOTES {
+            notes.push_str(&format!("; and {} more", skipped.len() - MAX_SKIP_NOTES));
+        }
+        text.push_str(&format!(
+            "[skipped {} unreadable: {}]\n",
+            skipped.len(),
+            notes
+        ));
+    }
+    Ok(text)
+}
+
+fn grep_dir(
+    root: &Path,
+    dir: &Path,
+    pattern: &Regex,
+    glob: Option<&GlobMatcher>,
+    output: &mut CappedOutput,
+    skipped: &mut Vec<String>,
 ) -> Result<(), AppError> {
-    let file = File::open(file_path).await.map_err(AppError::from)?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-    let mut contents = Vec::new();
-
-    // Read lines and update the specified line number
-    let mut current_line = 1;
-    while let Some(line) = lines.next_line().await.map_err(AppError::from)? {
-        if current_line == line_number {
-            contents.push(format!("{}\n", new_content));
+    for (path, is_dir) in sorted_entries(dir)? {
+        if output.truncated {
+            break;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let result = if is_dir {
+            if path.file_name().is_some_and(is_skipped_dir) {
+                continue;
+            }
+            grep_dir(root, &path, pattern, glob, output, skipped)
+        } else if glob.is_none_or(|glob| glob.is_match(relative)) {
+            grep_file(&path, pattern, output)
         } else {
-            contents.push(format!("{}\n", line));
+            continue;
+        };
+        if let Err(e) = result {
+            skipped.push(format!("{} ({})", relative.display(), skip_reason(&e)));
         }
-        current_line += 1;
     }
+    Ok(())
+}
 
-    // Write the modified contents back to the file
-    let mut file = File::create(file_path).await.map_err(AppError::from)?;
-    file.write_all(contents.concat().as_bytes())
-        .await
-        .map_err(AppError::from)?;
+// Binary files are skipped. Only the leading bytes are read to tell, and files over
+// MAX_GREP_FILE_BYTES aren't read at all.
+fn grep_file(path: &Path, pattern: &Regex, output: &mut CappedOutput) -> Result<(), AppError> {
+    // Follows symlinks, so a link to a directory or to nothing is caught here
+    let metadata = std::fs::metadata(path)?;
+    if !metadata.is_file() {
+        return Err(AppError::CommandError("not a regular file".to_string()));
+    }
+    if metadata.len() > MAX_GREP_FILE_BYTES {
+        return Err(AppError::CommandError(format!(
+            "{} bytes, over the {} byte limit for grep",
+            metadata.len(),
+            MAX_GREP_FILE_BYTES
+        )));
+    }
+
+    let mut file = std::fs::File::open(path)?;
+    let mut bytes = Vec::new();
+    (&mut file)
+        .take(BINARY_CHECK_BYTES as u64)
+        .read_to_end(&mut bytes)?;
+    if bytes.contains(&0) {
+        return Ok(());
+    }
+    // The file may have grown since the size check
+    file.take(MAX_GREP_FILE_BYTES).read_to_end(&mut bytes)?;
+
+    let content = String::from_utf8_lossy(&bytes);
+    for (number, line) in content.lines().enumerate() {
+        if pattern.is_match(line)
+            && !output.push(&format!("{}:{}: {}", path.display(), number + 1, line))
+        {
+            break;
+        }
+    }
     Ok(())
 }
 
+fn skip_reason(error: &AppError) -> String {
+    match error {
+        AppError::IOError(e) => e.to_string(),
+        AppError::CommandError(message) => message.clone(),
+        e => e.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::traits::ToolSpec;
     use std::path::Path;
     use tokio::fs;
 
@@ -246,12 +983,25 @@ mod tests {
         let _ = fs::remove_file(test_file).await;
     }
 
+    async fn run(operations: JsonValue) -> Result<String, AppError> {
+        FileTool
+            .execute(
+                serde_json::json!({ "operations": operations }),
+                &ToolContext::default(),
+            )
+            .await
+    }
+
     #[tokio::test]
     async fn test_create_file() {
         let test_file = "test_create.txt";
         setup_test_environment(test_file).await;
 
-        create_file(test_file, "New file content").await.unwrap();
+        run(serde_json::json!([
+            { "op": "create", "file_path": test_file, "content": "New file content" }
+        ]))
+        .await
+        .unwrap();
         let content = fs::read_to_string(test_file).await.unwrap();
         assert_eq!(content, "New file content");
 
@@ -262,56 +1012,268 @@ mod tests {
     #[tokio::test]
     async fn test_delete_file() {
         let test_file = "test_delete.txt";
-        create_file(test_file, "To be deleted").await.unwrap();
+        fs::write(test_file, "To be deleted").await.unwrap();
 
-        delete_file(test_file).await.unwrap();
+        run(serde_json::json!([{ "op": "delete", "file_path": test_file }]))
+            .await
+            .unwrap();
         let exists = Path::new(test_file).exists();
         assert!(!exists);
     }
 
+    #[test]
+    fn test_insert_line() {
+        let content = insert_line("First line\nSecond line", 2, "Inserted line");
+        assert_eq!(content, "First line\nInserted line\nSecond line\n");
+        assert_eq!(
+            insert_line("First line", 5, "Last line"),
+            "First line\nLast line\n"
+        );
+        assert_eq!(insert_line("a\r\nb\r\n", 2, "x"), "a\r\nx\r\nb\r\n");
+    }
+
+    #[test]
+    fn test_delete_line() {
+        let content = delete_line("First line\nSecond line\nThird line", 2).unwrap();
+        assert_eq!(content, "First line\nThird line\n");
+        assert!(delete_line("First line", 2).is_err());
+    }
+
+    #[test]
+    fn test_update_line() {
+        let content = update_line("First line\nSecond line", 2, "Updated line").unwrap();
+        assert_eq!(content, "First line\nUpdated line\n");
+        assert!(update_line("First line", 0, "Updated line").is_err());
+    }
+
+    #[test]
+    fn test_read_lines() {
+        let path = "test_read_lines.txt";
+        let content = "one\ntwo\nthree\nfour";
+
+        let text = read_lines(path, content, Some(2), Some(3), true).unwrap();
+        assert_eq!(
+            text,
+            "test_read_lines.txt (lines 2-3 of 4):\n2: two\n3: three\n"
+        );
+        let text = read_lines(path, content, Some(3), Some(10), false).unwrap();
+        assert_eq!(text, "test_read_lines.txt (lines 3-4 of 4):\nthree\nfour\n");
+        assert!(read_lines(path, content, Some(5), None, false).is_err());
+
+        // Large files are cut off on a line boundary, with a note saying where to carry on
+        let long = "x".repeat(100);
+        let content = vec![long.as_str(); 1000].join("\n");
+        let text = read_lines(path, &content, None, None, false).unwrap();
+        assert!(text.len() < MAX_OUTPUT_BYTES + 200);
+        assert!(text.starts_with("test_read_lines.txt (lines 1-324 of 1000):\n"));
+        assert!(text.ends_with("[truncated after 32768 bytes; read on from line 325]\n"));
+    }
+
     #[tokio::test]
-    async fn test_insert_line() {
-        let test_file = "test_insert_line.txt";
-        create_file(test_file, "First line\nSecond line")
-            .await
-            .unwrap();
+    async fn test_batch_is_all_or_nothing() {
+        let (kept, created) = ("test_batch_kept.txt", "test_batch_created.txt");
+        fs::write(kept, "keep\n").await.unwrap();
+        setup_test_environment(created).await;
 
-        insert_line(test_file, 2, "Inserted line").await.unwrap();
-        let content = fs::read_to_string(test_file).await.unwrap();
-        assert_eq!(content, "First line\nInserted line\nSecond line\n");
+        // Fails while staging: nothing is written
+        let error = run(serde_json::json!([
+            { "op": "create", "file_path": created, "content": "new\n" },
+            { "op": "updateline", "file_path": kept, "line": 1, "content": "changed" },
+            { "op": "replace", "file_path": kept, "anchor": "missing", "content": "x" }
+        ]))
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Error with command: /operations/2 (replace test_batch_kept.txt) failed: The anchor wasn't found in test_batch_kept.txt. No files were changed."
+        );
+        assert!(!Path::new(created).exists());
+        assert_eq!(fs::read_to_string(kept).await.unwrap(), "keep\n");
+
+        // Fails while writing: the files written so far are cleaned up
+        let error = run(serde_json::json!([
+            { "op": "create", "file_path": created, "content": "new\n" },
+            { "op": "create", "file_path": "test_batch_missing/x.txt", "content": "x" }
+        ]))
+        .await
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("/operations/1 (create test_batch_missing/x.txt) failed: IO error"));
+        assert!(!Path::new(created).exists());
+        let mut entries = fs::read_dir(".").await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(".test_batch"));
+        }
+
+        // Later operations see earlier ones
+        let output = run(serde_json::json!([
+            { "op": "create", "file_path": created, "content": "one\n" },
+            { "op": "insertline", "file_path": format!("./{}", created), "line": 2, "content": "two" },
+            { "op": "read", "file_path": created },
+            { "op": "delete", "file_path": kept }
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(
+            output,
+            "test_batch_created.txt (lines 1-2 of 2):\none\ntwo\n"
+        );
+        assert!(!Path::new(kept).exists());
 
         // Cleanup
-        let _ = fs::remove_file(test_file).await;
+        let _ = fs::remove_file(created).await;
     }
 
     #[tokio::test]
-    async fn test_delete_line() {
-        let test_file = "test_delete_line.txt";
-        create_file(test_file, "First line\nSecond line\nThird line")
+    async fn test_list_and_grep() {
+        let dir = std::env::temp_dir().join(format!("rtool_file_tool_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(dir.join("src/nested")).await.unwrap();
+        fs::write(dir.join("README.md"), "# Title\nfn not_code\n")
             .await
             .unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {\n    run();\n}\n")
+            .await
+            .unwrap();
+        fs::write(dir.join("src/nested/lib.rs"), "pub fn run() {}\n")
+            .await
+            .unwrap();
+        fs::write(dir.join("src/data.bin"), b"fn \0binary")
+            .await
+            .unwrap();
+
+        let text = list_dir(&dir, 1, None).unwrap();
+        assert!(text.ends_with(":\nREADME.md\nsrc/\n"));
+        let rs = glob_matcher(Some("*.rs")).unwrap();
+        let text = list_dir(&dir, 3, rs.as_ref()).unwrap();
+        assert!(text.ends_with(":\nsrc/main.rs\nsrc/nested/lib.rs\n"));
 
-        delete_line(test_file, 2).await.unwrap();
+        let pattern = Regex::new(r"fn \w+\(").unwrap();
+        let text = grep(&dir, &pattern, rs.as_ref()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("src/main.rs:1: fn main() {"));
+        assert!(lines[1].ends_with("src/nested/lib.rs:1: pub fn run() {}"));
+        let text = grep(&dir, &Regex::new("missing").unwrap(), None).unwrap();
+        assert_eq!(text, "No matches for `missing`\n");
+
+        // A dangling symlink doesn't stop the search, and is named after the matches
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("gone.rs"), dir.join("src/dangling.rs")).unwrap();
+            let text = grep(&dir, &pattern, rs.as_ref()).unwrap();
+            assert!(text.contains("src/main.rs:1: fn main() {"));
+            assert!(text.ends_with("[skipped 1 unreadable: src/dangling.rs (No such file or directory (os error 2))]\n"));
+        }
+
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn test_replace_anchor() {
+        let content = "a = 1;\nb = 1;\n";
+        let replace = |occurrence, replace_all| {
+            replace_anchor(content, "= 1", "= 2", occurrence, replace_all)
+        };
+        assert_eq!(
+            replace(None, false).unwrap_err(),
+            "The anchor appears 2 times; add surrounding lines to make it unique, or set occurrence or replace_all"
+        );
+        assert_eq!(replace(Some(2), false).unwrap(), "a = 1;\nb = 2;\n");
+        assert_eq!(replace(None, true).unwrap(), "a = 2;\nb = 2;\n");
+        assert!(replace(Some(3), false).is_err());
+        assert!(replace(Some(0), false).is_err());
+        assert!(replace(Some(1), true).is_err());
+        assert_eq!(
+            replace_anchor(content, "b = 1", "b = 3", None, false).unwrap(),
+            "a = 1;\nb = 3;\n"
+        );
+        assert!(replace_anchor(content, "c", "d", None, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_edits_return_a_diff() {
+        let test_file = "test_edit_diff.txt";
+        fs::write(test_file, "one\ntwo\nthree\n").await.unwrap();
+
+        let args = serde_json::json!({ "operations": [
+            { "op": "replace", "file_path": test_file, "anchor": "two", "content": "2" },
+            { "op": "applypatch", "file_path": test_file,
+              "content": "@@ -3 +3 @@\n-three\n+3\n" }
+        ] });
+        let output = FileTool
+            .execute(args, &ToolContext::default())
+            .await
+            .unwrap();
+        assert!(output.contains("--- a/test_edit_diff.txt\n+++ b/test_edit_diff.txt\n"));
+        assert!(output.contains("\n-two\n+2\n"));
+        assert!(output.contains("Applied 1 hunk to test_edit_diff.txt\n"));
+        assert!(output.contains("\n-three\n+3\n"));
         let content = fs::read_to_string(test_file).await.unwrap();
-        assert_eq!(content, "First line\nThird line\n");
+        assert_eq!(content, "one\n2\n3\n");
 
         // Cleanup
         let _ = fs::remove_file(test_file).await;
     }
 
+    #[test]
+    fn test_read_only_calls() {
+        let call = |ops: &[&str]| {
+            let operations: Vec<JsonValue> = ops
+                .iter()
+                .map(|op| serde_json::json!({ "op": op, "file_path": "src" }))
+                .collect();
+            FileTool.is_read_only(&serde_json::json!({ "operations": operations }))
+        };
+        assert!(call(&["read", "stat", "listdir", "grep"]));
+        assert!(!call(&["read", "delete"]));
+        assert!(!call(&["replace"]));
+        assert!(!call(&["rename"]));
+    }
+
     #[tokio::test]
-    async fn test_update_line() {
-        let test_file = "test_update_line.txt";
-        create_file(test_file, "First line\nSecond line")
+    async fn test_preview_changes_nothing() {
+        let (edited, created) = ("test_preview_edited.txt", "test_preview_created.txt");
+        fs::write(edited, "one\ntwo\n").await.unwrap();
+        setup_test_environment(created).await;
+
+        let preview = FileTool
+            .preview(&serde_json::json!({ "operations": [
+                { "op": "updateline", "file_path": edited, "line": 2, "content": "three" },
+                { "op": "create", "file_path": created, "content": "new\n" },
+                { "op": "read", "file_path": edited }
+            ]}))
             .await
             .unwrap();
+        assert!(preview.contains("\n-two\n+three\n"));
+        assert!(preview.contains(
+            "diff --git a/test_preview_created.txt b/test_preview_created.txt\nnew file\n--- /dev/null\n"
+        ));
+        assert_eq!(fs::read_to_string(edited).await.unwrap(), "one\ntwo\n");
+        assert!(!Path::new(created).exists());
 
-        update_line(test_file, 2, "Updated line").await.unwrap();
-        let content = fs::read_to_string(test_file).await.unwrap();
-        assert_eq!(content, "First line\nUpdated line\n");
+        let delete = |path: &str| serde_json::json!({ "op": "delete", "file_path": path });
+        let preview = FileTool
+            .preview(&serde_json::json!({ "operations": [delete(edited)] }))
+            .await
+            .unwrap();
+        assert!(preview.contains("deleted file\n--- a/test_preview_edited.txt\n+++ /dev/null\n"));
 
-        // Cleanup
-        let _ = fs::remove_file(test_file).await;
+        // A call that would fail changes nothing, so there's only the failure to show
+        let preview = FileTool
+            .preview(&serde_json::json!({ "operations": [delete(edited), delete(created)] }))
+            .await
+            .unwrap();
+        assert_eq!(
+            preview,
+            "/operations/1 (delete test_preview_created.txt) would fail: test_preview_created.txt doesn't exist\n"
+        );
+
+        let _ = fs::remove_file(edited).await;
     }
 
     #[test]
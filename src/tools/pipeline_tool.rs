@@ -1,23 +1,37 @@
 use crate::assistant::GLOBAL_TOOL_REGISTRY;
-use crate::models::{traits::Tool, types::AppError};
+use crate::models::{
+    traits::{Tool, ToolContext},
+    types::AppError,
+};
 
 use async_trait::async_trait;
-use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use proc_macro_crate::{auto_register, Tool};
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
-use serde_json::{self, json, Value as JsonValue};
-
-#[derive(Serialize, Deserialize, Debug)]
+use serde_json::{self, Value as JsonValue};
+
+/// Executes a series of tool calls, passing the output of one as the input to another using
+/// substitutions `${priorStepId}`. DO NOT STRINGIFY THE PARAMETERS IN PIPELINE STEPS -- PASS JSON
+/// DIRECTLY. Input value for the `steps` key MUST BE JSON, NOT A STRING. The `parameters` value for
+/// each step also MUST BE JSON, NOT A STRING.
+#[derive(Serialize, Deserialize, Debug, Default, Tool)]
+#[tool(name = "pipeline_tool", input = PipelineToolInput)]
 pub struct PipelineTool;
 
 #[derive(Serialize, Deserialize, Debug, JsonSchema)]
 struct PipelineStep {
+    /// The id of the pipeline step. Can be subsequently referenced in pipeline with `${thisId}` for
+    /// result substitution
     id: String,
+    /// Name of tool (in snake case) to call
     tool: String,
+    /// JSON arguments to pass to tool
     parameters: JsonValue,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
 struct PipelineToolInput {
+    /// The list of pipeline steps to execute, with possible substitutions
     steps: Vec<PipelineStep>,
 }
 
@@ -58,47 +72,11 @@ impl PipelineTool {
     }
 }
 
+#[auto_register]
 #[async_trait]
 impl Tool for PipelineTool {
-    fn name(&self) -> &'static str {
-        "pipeline_tool"
-    }
-
-    fn description(&self) -> &'static str {
-        "Executes a series of tool calls, passing the output of one as the input to another using substitutions `${priorStepId}`. DO NOT STRINGIFY THE PARAMETERS IN PIPELINE STEPS -- PASS JSON DIRECTLY. Input value for the `steps` key MUST BE JSON, NOT A STRING. The `parameters` value for each step also MUST BE JSON, NOT A STRING."
-    }
-
-    fn parameters(&self) -> JsonValue {
-        json!({
-            "type": "object",
-            "properties": {
-                "steps": {
-                    "type": "array",
-                    "description": "The list of pipeline steps to execute, with possible substitutions",
-                    "items": {
-                        "type": "object", 
-                        "properties": {
-                            "id": {
-                                "type": "string",
-                                "description": "the id of the pipeline step. can be subsequently referenced in pipeline with ${thisId} for result substitution",
-                            },
-                            "tool": {
-                                "type": "string",
-                                "description": "name of tool (in snake case) to call"
-                            },
-                            "parameters": {
-                                "type": "string",
-                                "description": "JSON arguments to pass to tool"
-                            }
-                        }
-                    }
-                }
-            }
-        })
-    }
-
-    async fn execute(&self, args: JsonValue) -> Result<String, AppError> {
-        let input: PipelineToolInput = serde_json::from_value(args)?;
+    async fn execute(&self, args: JsonValue, ctx: &ToolContext) -> Result<String, AppError> {
+        let input = Self::parse_input(args)?;
 
         let mut context = serde_json::Map::new();
 
@@ -114,7 +92,7 @@ impl Tool for PipelineTool {
                 Self::resolve_parameters(&params_json, &JsonValue::Object(context.clone()));
 
             let output = GLOBAL_TOOL_REGISTRY
-                .execute_tool(&step.tool, resolved_params)
+                .execute_tool(&step.tool, resolved_params, ctx)
                 .await?;
 
             context.insert(step.id.clone(), JsonValue::String(output));
@@ -123,14 +101,44 @@ impl Tool for PipelineTool {
         Ok(serde_json::to_string(&JsonValue::Object(context))?)
     }
 
-    fn input_schema(&self) -> RootSchema {
-        schema_for!(PipelineToolInput)
+    // Read-only if every step is. Steps are validated first, as they would be when run, since the
+    // model may send their parameters as a string.
+    fn is_read_only(&self, args: &JsonValue) -> bool {
+        Self::parse_input(args.clone()).is_ok_and(|input| {
+            input.steps.into_iter().all(|step| {
+                GLOBAL_TOOL_REGISTRY
+                    .validate_arguments(&step.tool, step.parameters)
+                    .is_ok_and(|parameters| {
+                        GLOBAL_TOOL_REGISTRY.is_read_only(&step.tool, &parameters)
+                    })
+            })
+        })
+    }
+
+    // The previews of the steps that have one. Each is made against things as they are now, before
+    // earlier steps run, and with `${priorStepId}` placeholders left as they are.
+    async fn preview(&self, args: &JsonValue) -> Option<String> {
+        let input = Self::parse_input(args.clone()).ok()?;
+        let mut previews = Vec::new();
+        for step in input.steps {
+            let Ok(parameters) =
+                GLOBAL_TOOL_REGISTRY.validate_arguments(&step.tool, step.parameters)
+            else {
+                continue;
+            };
+            if let Some(preview) = GLOBAL_TOOL_REGISTRY.preview(&step.tool, &parameters).await {
+                previews.push(format!("Step {} ({}):\n{}", step.id, step.tool, preview));
+            }
+        }
+        (!previews.is_empty()).then(|| previews.join("\n"))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::traits::ToolSpec;
+    use serde_json::json;
 
     #[tokio::test]
     async fn test_simple_pipeline() {
@@ -151,7 +159,10 @@ mod tests {
         let json_input = serde_json::to_value(input).unwrap();
 
         // Execute pipeline with given input
-        let result = pipeline_tool.execute(json_input).await.unwrap();
+        let result = pipeline_tool
+            .execute(json_input, &ToolContext::default())
+            .await
+            .unwrap();
 
         // Deserialize the JSON result to check the output
         let result_value: serde_json::Value = serde_json::from_str(&result).unwrap();
@@ -197,7 +208,10 @@ mod tests {
         let json_input = serde_json::to_value(input).unwrap();
 
         // Execute pipeline with given input
-        let result = pipeline_tool.execute(json_input).await.unwrap();
+        let result = pipeline_tool
+            .execute(json_input, &ToolContext::default())
+            .await
+            .unwrap();
 
         // Deserialize the JSON result to check the output
         let result_value: JsonValue = serde_json::from_str(&result).unwrap();
@@ -216,4 +230,17 @@ mod tests {
         let _schema = pipeline_tool.input_schema();
         dbg!(_schema);
     }
+
+    #[test]
+    fn test_pipeline_parameters_match_input() {
+        let parameters = PipelineTool.parameters();
+        let step = &parameters["properties"]["steps"]["items"];
+        assert_eq!(step["type"], "object");
+        assert_eq!(step["required"], json!(["id", "parameters", "tool"]));
+        // Step parameters are passed as JSON, not a string
+        assert!(step["properties"]["parameters"].get("type").is_none());
+        assert!(PipelineTool
+            .description()
+            .starts_with("Executes a series of tool calls"));
+    }
 }
@@ -1,76 +1,43 @@
-use crate::models::{traits::Tool, types::AppError};
+use crate::models::{
+    traits::{Tool, ToolContext},
+    types::AppError,
+};
 
 use async_trait::async_trait;
-use schemars::schema::RootSchema;
-use schemars::{schema_for, JsonSchema};
+use proc_macro_crate::{auto_register, Tool};
+use schemars::JsonSchema;
 use serde::de::Error as DeError;
 use serde_derive::{Deserialize, Serialize};
-use serde_json::{json, Result as JsonResult, Value as JsonValue};
+use serde_json::{Result as JsonResult, Value as JsonValue};
 use std::io;
 use std::process::{Command, Output};
 
-// Import this in main file where needed
+/// Executes a list of Linux shell commands and returns their concatenated output.
+#[derive(Default, Tool)]
+#[tool(name = "shell_tool", input = ShellToolInput)]
 pub struct ShellTool;
 
 #[derive(Debug, Deserialize, Serialize, JsonSchema)]
 struct ShellCommand {
+    /// Command to execute
     command: String,
+    /// The (optional) arguments to provide to the command
     args: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, JsonSchema)]
 struct ShellToolInput {
+    /// The list of Linux commands to execute
     commands: Vec<ShellCommand>,
 }
 
+#[auto_register]
 #[async_trait]
 impl Tool for ShellTool {
-    fn name(&self) -> &'static str {
-        "shell_tool"
-    }
-
-    fn description(&self) -> &'static str {
-        "Executes a list of Linux shell commands and returns their concatenated output."
-    }
-
-    fn parameters(&self) -> JsonValue {
-        json!({
-            "type": "object",
-            "properties": {
-                "commands": {
-                    "type": "array",
-                    "description": "The JSON-encoded list of Linux commands to execute",
-                    "items": {
-                        "type": "object",
-                        "properties": {
-                            "command": {
-                                "type": "string",
-                                "description": "command to execute",
-                            },
-                            "args": {
-                                "type": "array",
-                                "description": "The (optional) arguments to provide to the command",
-                                "items": {
-                                    "type": "string",
-                                    "description": "argument for command"
-                                }
-                            },
-                        },
-                        "required": ["command"]
-                    }
-                }
-            }
-        })
-    }
-
-    async fn execute(&self, args: JsonValue) -> Result<String, AppError> {
-        let input: ShellToolInput = serde_json::from_value(args)?;
+    async fn execute(&self, args: JsonValue, _ctx: &ToolContext) -> Result<String, AppError> {
+        let input = Self::parse_input(args)?;
         execute_linux_commands(input.commands).await
     }
-
-    fn input_schema(&self) -> RootSchema {
-        schema_for!(ShellToolInput)
-    }
 }
 
 async fn execute_linux_commands(commands: Vec<ShellCommand>) -> Result<String, AppError> {
@@ -120,6 +87,7 @@ fn handle_command_output(output: io::Result<Output>, command: &str) -> JsonResul
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::traits::ToolSpec;
 
     #[tokio::test]
     async fn text_execute_shell_commands() {
@@ -1,42 +1,33 @@
-use crate::models::{traits::Tool, types::AppError};
+use crate::config::settings;
+use crate::models::{
+    traits::{Tool, ToolContext},
+    types::AppError,
+};
 use async_recursion::async_recursion;
 use async_trait::async_trait;
-use schemars::schema::RootSchema;
-use schemars::{schema_for, JsonSchema};
+use proc_macro_crate::{auto_register, Tool};
+use schemars::JsonSchema;
 use serde::de::Error as DeError;
-use serde_json::{json, Result as JsonResult, Value as JsonValue};
+use serde_derive::Deserialize;
+use serde_json::{Result as JsonResult, Value as JsonValue};
 use std::fs;
 use std::path::Path;
 
+/// Return the formatted source code of the current project, including Cargo.toml and all .rs files
+#[derive(Default, Tool)]
+#[tool(name = "snap_tool", input = SnapToolInput)]
 pub struct SnapTool;
 
-#[derive(JsonSchema)]
+#[derive(Deserialize, JsonSchema)]
 struct SnapToolInput {}
 
+#[auto_register]
 #[async_trait]
 impl Tool for SnapTool {
-    fn name(&self) -> &'static str {
-        "snap_tool"
-    }
-
-    fn description(&self) -> &'static str {
-        "Return the formatted source code of the current project, including Cargo.toml and all .rs files"
-    }
-
-    fn parameters(&self) -> JsonValue {
-        json!({
-            "type": "object",
-            "properties": {}
-        })
-    }
-
-    async fn execute(&self, _args: JsonValue) -> Result<String, AppError> {
+    async fn execute(&self, args: JsonValue, _ctx: &ToolContext) -> Result<String, AppError> {
+        Self::parse_input(args)?;
         create_project_snapshot().await
     }
-
-    fn input_schema(&self) -> RootSchema {
-        schema_for!(SnapToolInput)
-    }
 }
 
 // Function to recursively create the snapshot
@@ -56,12 +47,12 @@ async fn create_project_snapshot() -> Result<String, AppError> {
     snapshot.push_str("\n\n");
 
     // Start the recursion from the root_path, which is "src"
-    read_directory_contents(&root_path, &mut snapshot).await?;
+    read_directory_contents(root_path, &mut snapshot).await?;
 
-    read_directory_contents(&proc_macro_path, &mut snapshot).await?;
+    read_directory_contents(proc_macro_path, &mut snapshot).await?;
 
-    // Write snapshot to state.txt
-    fs::write("state.txt", &snapshot)
+    // Write snapshot to the state file
+    fs::write(&settings().state_file, &snapshot)
         .map_err(|e| serde_json::Error::custom(format!("Error writing to file: {}", e)))?;
 
     // Return the created snapshot
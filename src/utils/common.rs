@@ -1,44 +1,297 @@
+use crate::approval::ApprovalAnswer;
 use crate::models::types::{AppError, ToolCall};
 
+use bytes::Bytes;
 use crossterm::{
     style::{Color, ResetColor, SetForegroundColor},
     ExecutableCommand,
 };
-use reqwest::Client;
+use reqwest::{header::HeaderMap, RequestBuilder, Response};
 use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
 use serde_json::Value;
 use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-// Utility function to send a post request and wait for a JSON response
+// How failed API requests are retried: exponential backoff, unless the server says when to come back
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay before retry number `attempt` (starting at 0), honouring a server-provided Retry-After
+    pub fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+
+        match retry_after {
+            Some(delay) => delay.min(self.max_backoff),
+            None => exponential,
+        }
+    }
+}
+
+// The `{"error": {...}}` body returned by OpenAI-style and Anthropic APIs
+#[derive(Debug, Deserialize)]
+struct ApiErrorBody {
+    error: ApiErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiErrorDetail {
+    message: String,
+    #[serde(rename = "type")]
+    r#type: Option<String>,
+    code: Option<Value>,
+}
+
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    parse_retry_after_at(headers, chrono::Utc::now())
+}
+
+// `retry-after-ms`, else `retry-after` as seconds or an HTTP date. Values that make no sense, such
+// as negative or infinite delays, are ignored.
+fn parse_retry_after_at(
+    headers: &HeaderMap,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let millis = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok());
+    if let Some(delay) = millis.and_then(|millis| seconds(millis / 1000.0)) {
+        return Some(delay);
+    }
+    let value = header("retry-after")?.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return seconds(secs);
+    }
+    // e.g. `Wed, 21 Oct 2015 07:28:00 GMT`; a date already past means now
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+fn seconds(secs: f64) -> Option<Duration> {
+    if !secs.is_finite() || secs < 0.0 {
+        return None;
+    }
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+// Turn a failed response (or an error body on a 200) into the matching `AppError` variant
+pub fn classify_api_error(status: u16, body: &str, retry_after: Option<Duration>) -> AppError {
+    let (message, error_type, code) = match serde_json::from_str::<ApiErrorBody>(body) {
+        Ok(parsed) => (
+            parsed.error.message,
+            parsed.error.r#type.unwrap_or_default(),
+            parsed
+                .error
+                .code
+                .and_then(|code| code.as_str().map(str::to_string))
+                .unwrap_or_default(),
+        ),
+        Err(_) => (body.trim().to_string(), String::new(), String::new()),
+    };
+
+    let lowered = message.to_lowercase();
+    let context_exceeded = code == "context_length_exceeded"
+        || lowered.contains("maximum context length")
+        || lowered.contains("context length")
+        || lowered.contains("prompt is too long");
+
+    match status {
+        401 | 403 => AppError::AuthenticationError(message),
+        _ if error_type == "authentication_error" || error_type == "permission_error" => {
+            AppError::AuthenticationError(message)
+        }
+        // OpenAI reports an exhausted quota as a 429, but waiting will not help
+        429 if code == "insufficient_quota" => AppError::ApiError { status, message },
+        429 => AppError::RateLimited {
+            message,
+            retry_after,
+        },
+        _ if error_type == "rate_limit_error" => AppError::RateLimited {
+            message,
+            retry_after,
+        },
+        _ if context_exceeded => AppError::ContextLengthExceeded(message),
+        500..=599 => AppError::ServerError { status, message },
+        _ if error_type == "overloaded_error" || error_type == "api_error" => {
+            AppError::ServerError { status, message }
+        }
+        _ => AppError::ApiError { status, message },
+    }
+}
+
+// Send the request, retrying transient failures, and return the first successful response
+async fn send_with_retry(
+    request: RequestBuilder,
+    payload: &Value,
+    retry: &RetryPolicy,
+) -> Result<Response, AppError> {
+    let mut attempt: u32 = 0;
+
+    loop {
+        let attempt_request = request
+            .try_clone()
+            .ok_or_else(|| AppError::CommandError("Request body cannot be retried".to_string()))?;
+
+        let result = match attempt_request.json(payload).send().await {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let status = response.status().as_u16();
+                let retry_after = parse_retry_after(response.headers());
+                let body = response.text().await.unwrap_or_default();
+                log::debug!("HTTP Response Status: {} body: {}", status, body);
+                classify_api_error(status, &body, retry_after)
+            }
+            Err(e) => AppError::from(e),
+        };
+
+        if !result.is_transient() || attempt >= retry.max_retries {
+            return Err(result);
+        }
+
+        let retry_after = match &result {
+            AppError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        };
+        let delay = retry.backoff(attempt, retry_after);
+        attempt += 1;
+
+        let notice = format!(
+            "{} - retrying in {:.1}s (attempt {}/{})\n",
+            result,
+            delay.as_secs_f32(),
+            attempt,
+            retry.max_retries
+        );
+        log::warn!("{}", notice.trim_end());
+        print_colorful(&notice, Color::DarkYellow)?;
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Utility function to send a post request and wait for a JSON response.
+// The request builder carries the URL and whatever authentication headers the provider needs.
 pub async fn post_json<T: DeserializeOwned>(
-    client: &Client,
-    url: &str,
-    api_key: &str,
+    request: RequestBuilder,
     payload: &Value,
+    retry: &RetryPolicy,
 ) -> Result<T, AppError> {
-    log::debug!("Sending HTTP POST payload {:?} to {}", payload, url);
-    let response = client
-        .post(url)
-        .bearer_auth(api_key)
-        .json(payload)
-        .send()
-        .await
-        .map_err(AppError::from)?;
+    log::debug!("Sending HTTP POST payload {:?}", payload);
+    let response = send_with_retry(request, payload, retry).await?;
 
-    log::debug!("HTTP Response Status: {:?}", response.status()); // Log response status
+    let status = response.status().as_u16();
+    log::debug!("HTTP Response Status: {:?}", status); // Log response status
 
-    response.json().await.map_err(AppError::from)
+    // Some OpenAI-compatible servers report errors in the body of a 200 response
+    let body = response.text().await.map_err(AppError::from)?;
+    serde_json::from_str(&body).map_err(|e| {
+        if serde_json::from_str::<ApiErrorBody>(&body).is_ok() {
+            classify_api_error(status, &body, None)
+        } else {
+            AppError::SerdeJsonError(e)
+        }
+    })
+}
+
+// Utility function to send a post request and hand back the response for incremental reading
+pub async fn post_stream(
+    request: RequestBuilder,
+    payload: &Value,
+    retry: &RetryPolicy,
+) -> Result<Response, AppError> {
+    log::debug!("Sending streaming HTTP POST payload {:?}", payload);
+    let response = send_with_retry(request, payload, retry).await?;
+
+    log::debug!("HTTP Response Status: {:?}", response.status());
+
+    Ok(response)
+}
+
+// Read the next chunk of a streamed response, giving up if the server goes quiet for too long
+pub async fn next_chunk(
+    response: &mut Response,
+    idle_timeout: Duration,
+) -> Result<Option<Bytes>, AppError> {
+    match tokio::time::timeout(idle_timeout, response.chunk()).await {
+        Ok(chunk) => chunk.map_err(AppError::from),
+        Err(_) => Err(AppError::Timeout(format!(
+            "no data received from the stream for {}s",
+            idle_timeout.as_secs()
+        ))),
+    }
 }
 
 // Utility function to print to console with the specified color
 pub fn print_colorful(message: &str, color: Color) -> Result<(), AppError> {
-    let mut stdout = io::stdout();
+    if PRINT_TO_STDERR.load(Ordering::Relaxed) {
+        write_colorful(&mut io::stderr(), message, color)
+    } else {
+        write_colorful(&mut io::stdout(), message, color)
+    }
+}
+
+fn write_colorful(out: &mut impl Write, message: &str, color: Color) -> Result<(), AppError> {
+    out.execute(SetForegroundColor(color)).unwrap();
+    write!(out, "{}", message)?;
+    out.execute(ResetColor).unwrap();
 
-    stdout.execute(SetForegroundColor(color)).unwrap();
-    print!("{}", message);
-    stdout.execute(ResetColor).unwrap();
+    out.flush().map_err(AppError::from)
+}
+
+// Set for --print and --batch, whose stdout carries only results; progress goes to stderr
+static PRINT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn print_to_stderr() {
+    PRINT_TO_STDERR.store(true, Ordering::Relaxed);
+}
 
-    io::stdout().flush().map_err(AppError::from)
+// A unified diff with additions in green, removals in red and hunk headers in cyan. New and
+// deleted files stand out in magenta.
+pub fn print_diff(diff: &str) -> Result<(), AppError> {
+    for line in diff.lines() {
+        let color = if line.starts_with("diff ")
+            || line.starts_with("new file")
+            || line.starts_with("deleted file")
+        {
+            Color::Magenta
+        } else if line.starts_with("+++") || line.starts_with("---") {
+            Color::DarkGrey
+        } else if line.starts_with('+') {
+            Color::Green
+        } else if line.starts_with('-') {
+            Color::Red
+        } else if line.starts_with("@@") {
+            Color::Cyan
+        } else {
+            Color::Reset
+        };
+        print_colorful(&format!("{}\n", line), color)?;
+    }
+    Ok(())
 }
 
 // Utility function for printing the assistant's replies
@@ -46,20 +299,34 @@ pub fn print_assistant_reply(reply: &str) -> Result<(), AppError> {
     print_colorful(&format!("Assistant: {}\n", reply), Color::Cyan)
 }
 
-// Utility function to pretty-print the tool's function call arguments and ask for user approval
-pub async fn request_tool_call_approval(tool_call: &ToolCall) -> Result<bool, AppError> {
+// Utility function to pretty-print the tool's function call arguments and ask for user approval.
+// The tool's preview of what the call would do, if any, is shown as a diff below the arguments.
+pub async fn request_tool_call_approval(
+    tool_call: &ToolCall,
+    args: &Value,
+    preview: Option<&str>,
+) -> Result<ApprovalAnswer, AppError> {
     log::info!("Requesting user approval for tool call: {:?}", tool_call);
 
-    // Deserialize JSON arguments string into serde_json::Value
-    let args: serde_json::Value = serde_json::from_str(&tool_call.function.arguments)?;
-
-    // Pretty-print the arguments
-    let pretty_args = serde_json::to_string_pretty(&args)?;
+    // Pretty-print the arguments as they will run, after validation. File contents, anchors and
+    // patches are in the preview, so they're only counted here.
+    let pretty_args = match preview {
+        Some(_) => serde_json::to_string_pretty(&without_bodies(args))?,
+        None => serde_json::to_string_pretty(args)?,
+    };
 
     // Printing information with formatted pretty-printed arguments
     // Let's use a gentle Blue color for the prompt
     print_colorful(
-        &format!("\n{}({}) ? (y/n) ", tool_call.function.name, pretty_args),
+        &format!("\n{}({})", tool_call.function.name, pretty_args),
+        Color::Blue,
+    )?;
+    if let Some(preview) = preview {
+        print_colorful("\n", Color::Blue)?;
+        print_diff(preview)?;
+    }
+    print_colorful(
+        " ? (y)es / (n)o[: reason] / (a)lways for this tool / (e)dit ",
         Color::Blue,
     )?;
 
@@ -72,12 +339,184 @@ pub async fn request_tool_call_approval(tool_call: &ToolCall) -> Result<bool, Ap
     io::stdin()
         .read_line(&mut approval)
         .map_err(AppError::from)?;
-    let approval = approval.trim();
 
-    // Return true if approved ('y' or 'Y'), false otherwise
-    Ok(approval.eq_ignore_ascii_case("y"))
+    Ok(ApprovalAnswer::parse(&approval))
 }
 
-pub fn read_file(file_path: &str) -> Result<String, AppError> {
+// `args` with every `content` and `anchor` string, at any depth, replaced by its size
+fn without_bodies(args: &Value) -> Value {
+    match args {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(body) if key == "content" || key == "anchor" => {
+                            Value::String(format!("<{} bytes>", body.len()))
+                        }
+                        value => without_bodies(value),
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(without_bodies).collect()),
+        value => value.clone(),
+    }
+}
+
+// Let the user edit `text` in $VISUAL or $EDITOR (default vi) and return what they saved
+pub fn edit_in_editor(text: &str, extension: &str) -> Result<String, AppError> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // The editor may come with arguments, e.g. `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| AppError::CommandError("$EDITOR is empty".to_string()))?;
+
+    let path = std::env::temp_dir().join(format!("rtool-{}.{}", uuid::Uuid::new_v4(), extension));
+    std::fs::write(&path, text)?;
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(&path)
+        .status();
+    let edited = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+
+    if !status?.success() {
+        return Err(AppError::CommandError(format!(
+            "Editor `{}` exited with an error",
+            editor
+        )));
+    }
+    Ok(edited?)
+}
+
+pub fn read_file<P: AsRef<Path>>(file_path: P) -> Result<String, AppError> {
     std::fs::read_to_string(file_path).map_err(AppError::from)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_openai_errors() {
+        let body = r#"{"error": {"message": "Rate limit reached", "type": "requests", "code": "rate_limit_exceeded"}}"#;
+        let error = classify_api_error(429, body, Some(Duration::from_secs(2)));
+        assert!(matches!(
+            error,
+            AppError::RateLimited { retry_after: Some(d), .. } if d == Duration::from_secs(2)
+        ));
+        assert!(error.is_transient());
+
+        let body = r#"{"error": {"message": "You exceeded your current quota", "type": "insufficient_quota", "code": "insufficient_quota"}}"#;
+        assert!(!classify_api_error(429, body, None).is_transient());
+
+        let body = r#"{"error": {"message": "This model's maximum context length is 8192 tokens", "type": "invalid_request_error", "code": "context_length_exceeded"}}"#;
+        assert!(matches!(
+            classify_api_error(400, body, None),
+            AppError::ContextLengthExceeded(_)
+        ));
+
+        let body = r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "code": "invalid_api_key"}}"#;
+        assert!(matches!(
+            classify_api_error(401, body, None),
+            AppError::AuthenticationError(_)
+        ));
+    }
+
+    #[test]
+    fn test_classify_anthropic_and_plain_errors() {
+        let body =
+            r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#;
+        assert!(matches!(
+            classify_api_error(529, body, None),
+            AppError::ServerError { status: 529, .. }
+        ));
+
+        let body = r#"{"type": "error", "error": {"type": "invalid_request_error", "message": "prompt is too long: 210000 tokens > 200000 maximum"}}"#;
+        assert!(matches!(
+            classify_api_error(400, body, None),
+            AppError::ContextLengthExceeded(_)
+        ));
+
+        let error = classify_api_error(502, "<html>Bad Gateway</html>", None);
+        assert!(matches!(error, AppError::ServerError { status: 502, .. }));
+        assert!(error.is_transient());
+
+        assert!(matches!(
+            classify_api_error(404, "not found", None),
+            AppError::ApiError { status: 404, .. }
+        ));
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let retry = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        assert_eq!(retry.backoff(0, None), Duration::from_secs(1));
+        assert_eq!(retry.backoff(2, None), Duration::from_secs(4));
+        assert_eq!(retry.backoff(6, None), Duration::from_secs(10));
+        assert_eq!(
+            retry.backoff(0, Some(Duration::from_secs(7))),
+            Duration::from_secs(7)
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "3".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(3)));
+
+        headers.insert("retry-after-ms", "1500".parse().unwrap());
+        assert_eq!(
+            parse_retry_after(&headers),
+            Some(Duration::from_millis(1500))
+        );
+
+        // Nonsense is ignored rather than trusted
+        for value in ["-1", "inf", "NaN", "1e300"] {
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after-ms", value.parse().unwrap());
+            headers.insert("retry-after", value.parse().unwrap());
+            assert_eq!(parse_retry_after(&headers), None, "{}", value);
+        }
+
+        let now = chrono::DateTime::parse_from_rfc3339("2015-10-21T07:27:30Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "retry-after",
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(
+            parse_retry_after_at(&headers, now),
+            Some(Duration::from_secs(30))
+        );
+        headers.insert(
+            "retry-after",
+            "Wed, 21 Oct 2015 07:00:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after_at(&headers, now), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_without_bodies() {
+        let args = serde_json::json!({"steps": [{"parameters": {"operations": [
+            {"op": "replace", "file_path": "a.rs", "anchor": "fn a()", "content": "fn b()\n"}
+        ]}}]});
+        assert_eq!(
+            without_bodies(&args),
+            serde_json::json!({"steps": [{"parameters": {"operations": [
+                {"op": "replace", "file_path": "a.rs", "anchor": "<6 bytes>", "content": "<7 bytes>"}
+            ]}}]})
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use crate::models::types::AppError;
+
+use similar::TextDiff;
+
+// How many context lines at either end of a hunk may be ignored to make it fit
+const MAX_FUZZ: usize = 2;
+
+// A unified diff from `old` to `new`, empty if they're the same
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    diff_with_headers(old, new, &format!("a/{}", path), &format!("b/{}", path))
+}
+
+// Like `unified_diff`, under a git-style header that marks a file that doesn't exist yet (`old` is
+// None) or won't any more (`new` is None)
+pub fn file_diff(path: &str, old: Option<&str>, new: Option<&str>) -> String {
+    let (marker, from, to) = match (old, new) {
+        (None, Some(_)) => ("new file\n", "/dev/null".to_string(), format!("b/{}", path)),
+        (Some(_), None) => (
+            "deleted file\n",
+            format!("a/{}", path),
+            "/dev/null".to_string(),
+        ),
+        _ => ("", format!("a/{}", path), format!("b/{}", path)),
+    };
+    let diff = diff_with_headers(old.unwrap_or_default(), new.unwrap_or_default(), &from, &to);
+    format!("diff --git a/{0} b/{0}\n{1}{2}", path, marker, diff)
+}
+
+fn diff_with_headers(old: &str, new: &str, from: &str, to: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(from, to)
+        .to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineKind {
+    Context,
+    Remove,
+    Add,
+}
+
+#[derive(Debug)]
+struct Hunk {
+    header: String,
+    // Where the hunk says it starts in the old file, counting from 1
+    old_start: usize,
+    lines: Vec<(LineKind, String)>,
+}
+
+// `@@ -12,5 +12,6 @@`: where the hunk starts in the old file and how many old and new lines it has
+struct HunkHeader {
+    old_start: usize,
+    old_lines: usize,
+    new_lines: usize,
+}
+
+// `\r\n` if the file uses it, so edits keep the file's line endings
+pub fn line_ending(content: &str) -> &'static str {
+    if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+impl Hunk {
+    fn old_lines(&self) -> impl Iterator<Item = &str> {
+        self.lines
+            .iter()
+            .filter(|(kind, _)| *kind != LineKind::Add)
+            .map(|(_, text)| text.as_str())
+    }
+
+    fn leading_context(&self) -> usize {
+        self.lines
+            .iter()
+            .take_while(|(kind, _)| *kind == LineKind::Context)
+            .count()
+    }
+
+    fn trailing_context(&self) -> usize {
+        self.lines
+            .iter()
+            .rev()
+            .take_while(|(kind, _)| *kind == LineKind::Context)
+            .count()
+    }
+}
+
+// How one hunk was applied
+#[derive(Debug, PartialEq)]
+pub struct AppliedHunk {
+    // Counting from 1, in the file as patched so far
+    pub line: usize,
+    // Lines away from where the hunk header put it
+    pub offset: isize,
+    // Context lines ignored to make it fit
+    pub fuzz: usize,
+}
+
+// Apply a unified diff to `content`. The file headers (`---`, `+++`) are skipped, and the hunk line
+// counts say where each hunk ends. Each hunk is looked for nearest to where its header puts it,
+// first exactly, then ignoring trailing whitespace, then ignoring up to MAX_FUZZ context lines at
+// either end. Fails, naming every hunk that didn't fit, unless all of them do.
+pub fn apply_patch(content: &str, patch: &str) -> Result<(String, Vec<AppliedHunk>), AppError> {
+    let hunks = parse_hunks(patch)?;
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+
+    let mut applied = Vec::new();
+    let mut failed = Vec::new();
+    // Hunks apply in order, each after the one before it
+    let mut next_line = 0;
+    let mut drift: isize = 0;
+    for (i, hunk) in hunks.iter().enumerate() {
+        // A hunk that only adds lines goes after the line its header names
+        let old_start = if hunk.old_lines().next().is_none() {
+            hunk.old_start
+        } else {
+            hunk.old_start.saturating_sub(1)
+        };
+        let expected = (old_start as isize + drift).max(0) as usize;
+        let Some((start, skip_start, skip_end)) = find_hunk(&lines, hunk, next_line, expected)
+        else {
+            failed.push(format!("hunk {} ({})", i + 1, hunk.header));
+            continue;
+        };
+
+        let body = &hunk.lines[skip_start..hunk.lines.len() - skip_end];
+        let mut replacement = Vec::new();
+        let mut position = start;
+        for (kind, text) in body {
+            match kind {
+                // Keep the file's version of context lines, which may differ in whitespace
+                LineKind::Context => {
+                    replacement.push(lines[position].clone());
+                    position += 1;
+                }
+                LineKind::Remove => position += 1,
+                LineKind::Add => replacement.push(text.clone()),
+            }
+        }
+        let added = replacement.len();
+        lines.splice(start..position, replacement);
+
+        // Report where the hunk's first line, dropped context included, ended up
+        let line = start - skip_start;
+        drift += added as isize - (position - start) as isize;
+        applied.push(AppliedHunk {
+            line: line + 1,
+            offset: line as isize - expected as isize,
+            fuzz: skip_start.max(skip_end),
+        });
+        next_line = start + added;
+    }
+
+    if !failed.is_empty() {
+        return Err(AppError::CommandError(format!(
+            "{} of {} hunks didn't match the file: {}",
+            failed.len(),
+            hunks.len(),
+            failed.join(", ")
+        )));
+    }
+
+    let eol = line_ending(content);
+    let mut patched = lines.join(eol);
+    if !patched.is_empty() && (content.is_empty() || content.ends_with('\n')) {
+        patched.push_str(eol);
+    }
+    Ok((patched, applied))
+}
+
+fn parse_hunks(patch: &str) -> Result<Vec<Hunk>, AppError> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    // Old and new lines still to come in the current hunk
+    let mut remaining = (0, 0);
+    for (number, line) in patch.lines().enumerate() {
+        if let Some(header) = line.strip_prefix("@@") {
+            let parsed = parse_hunk_header(header)
+                .ok_or_else(|| AppError::CommandError(format!("Invalid hunk header: {}", line)))?;
+            remaining = (parsed.old_lines, parsed.new_lines);
+            hunks.push(Hunk {
+                header: line.to_string(),
+                old_start: parsed.old_start,
+                lines: Vec::new(),
+            });
+            continue;
+        }
+
+        if remaining == (0, 0) {
+            // Before the first hunk: `diff`, `index`, `---` and `+++` lines. Only blank lines and
+            // `\ No newline at end of file` may follow a hunk.
+            let is_file_header = line.starts_with("--- ") || line.starts_with("+++ ");
+            if is_file_header && !hunks.is_empty() {
+                return Err(AppError::CommandError(
+                    "The patch changes more than one file; send one patch per file".to_string(),
+                ));
+            }
+            if !is_file_header
+                && (line.starts_with('+') || line.starts_with('-') || line.starts_with(' '))
+            {
+                return Err(AppError::CommandError(format!(
+                    "Line {} of the patch is outside every hunk; check the line counts in the `@@` header before it",
+                    number + 1
+                )));
+            }
+            continue;
+        }
+
+        let hunk = hunks.last_mut().expect("remaining lines belong to a hunk");
+        let (kind, text) = match line.chars().next() {
+            Some('+') => (LineKind::Add, &line[1..]),
+            Some('-') => (LineKind::Remove, &line[1..]),
+            Some(' ') => (LineKind::Context, &line[1..]),
+            Some('\\') => continue,
+            // An empty context line that lost its leading space
+            _ => (LineKind::Context, line),
+        };
+        match kind {
+            LineKind::Add => remaining.1 = remaining.1.saturating_sub(1),
+            LineKind::Remove => remaining.0 = remaining.0.saturating_sub(1),
+            LineKind::Context => {
+                remaining = (remaining.0.saturating_sub(1), remaining.1.saturating_sub(1))
+            }
+        }
+        hunk.lines.push((kind, text.to_string()));
+    }
+
+    if hunks.is_empty() {
+        return Err(AppError::CommandError(
+            "The patch has no hunks (lines starting with `@@`)".to_string(),
+        ));
+    }
+    Ok(hunks)
+}
+
+// `-12,5 +12,6 @@`, without the leading `@@`. A count left out is 1, and a pure addition to an
+// empty file starts at 0.
+fn parse_hunk_header(header: &str) -> Option<HunkHeader> {
+    let mut ranges = header.split_whitespace();
+    let parse_range = |range: &str| -> Option<(usize, usize)> {
+        let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+        Some((start.parse().ok()?, count.parse().ok()?))
+    };
+    let (old_start, old_lines) = parse_range(ranges.next()?.strip_prefix('-')?)?;
+    let (_, new_lines) = parse_range(ranges.next()?.strip_prefix('+')?)?;
+    Some(HunkHeader {
+        old_start,
+        old_lines,
+        new_lines,
+    })
+}
+
+// Where the hunk fits, as (start line index, leading and trailing context lines dropped)
+fn find_hunk(
+    lines: &[String],
+    hunk: &Hunk,
+    from: usize,
+    expected: usize,
+) -> Option<(usize, usize, usize)> {
+    let leading = hunk.leading_context().min(MAX_FUZZ);
+    let trailing = hunk.trailing_context().min(MAX_FUZZ);
+    let old: Vec<&str> = hunk.old_lines().collect();
+
+    // As written, then ignoring trailing whitespace, then dropping more and more context
+    let attempts = std::iter::once((true, 0)).chain((0..=MAX_FUZZ).map(|fuzz| (false, fuzz)));
+    for (exact, fuzz) in attempts {
+        let (skip_start, skip_end) = (fuzz.min(leading), fuzz.min(trailing));
+        if skip_start.max(skip_end) < fuzz {
+            // No context left to drop
+            break;
+        }
+        // A context-only hunk could otherwise lose every line and fit anywhere
+        if !old.is_empty() && skip_start + skip_end >= old.len() {
+            break;
+        }
+        let old = &old[skip_start..old.len() - skip_end];
+        if let Some(start) = find_lines(lines, old, from, expected + skip_start, exact) {
+            return Some((start, skip_start, skip_end));
+        }
+    }
+    None
+}
+
+// The match for `old` at or after `from` that's nearest `expected`
+fn find_lines(
+    lines: &[String],
+    old: &[&str],
+    from: usize,
+    expected: usize,
+    exact: bool,
+) -> Option<usize> {
+    if old.len() > lines.len() {
+        return None;
+    }
+    let matches_at = |start: usize| {
+        lines[start..start + old.len()]
+            .iter()
+            .zip(old)
+            .all(|(line, old)| {
+                if exact {
+                    line == old
+                } else {
+                    line.trim_end() == old.trim_end()
+                }
+            })
+    };
+    (from..=lines.len() - old.len())
+        .filter(|&start| matches_at(start))
+        .min_by_key(|&start| start.abs_diff(expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "fn main() {\n    let a = 1;\n    let b = 2;\n    println!(\"{}\", a + b);\n}\n\nfn other() {}\n";
+
+    #[test]
+    fn test_diff_round_trip() {
+        let new = FILE.replace("let b = 2;", "let b = 3;\n    let c = 4;");
+        let diff = unified_diff("src/main.rs", FILE, &new);
+        assert!(diff.starts_with("--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,6 +1,7 @@\n"));
+        assert!(diff.contains("\n-    let b = 2;\n+    let b = 3;\n+    let c = 4;\n"));
+        assert_eq!(apply_patch(FILE, &diff).unwrap().0, new);
+        assert_eq!(unified_diff("src/main.rs", FILE, FILE), "");
+    }
+
+    #[test]
+    fn test_hunks_end_where_their_counts_say() {
+        // Removing a line that itself starts with `--`
+        let sql = "select 1;\n-- old comment\nselect 2;\n";
+        let patch = "--- a/q.sql\n+++ b/q.sql\n@@ -1,3 +1,3 @@\n select 1;\n--- old comment\n+-- new comment\n select 2;\n";
+        assert_eq!(
+            apply_patch(sql, patch).unwrap().0,
+            "select 1;\n-- new comment\nselect 2;\n"
+        );
+
+        let second_file = format!(
+            "{}--- a/other.sql\n+++ b/other.sql\n@@ -1 +1 @@\n-x\n+y\n",
+            patch
+        );
+        assert!(apply_patch(sql, &second_file)
+            .unwrap_err()
+            .to_string()
+            .contains("more than one file"));
+        let undercounted = "@@ -1,1 +1,1 @@\n select 1;\n-select 2;\n";
+        assert!(apply_patch(sql, undercounted)
+            .unwrap_err()
+            .to_string()
+            .contains("Line 3 of the patch is outside every hunk"));
+    }
+
+    #[test]
+    fn test_line_endings_are_kept() {
+        let (patched, _) = apply_patch("one\r\ntwo\r\n", "@@ -2 +2 @@\n-two\n+three\n").unwrap();
+        assert_eq!(patched, "one\r\nthree\r\n");
+    }
+
+    #[test]
+    fn test_new_and_deleted_files_are_marked() {
+        assert_eq!(
+            file_diff("new.txt", None, Some("one\n")),
+            "diff --git a/new.txt b/new.txt\nnew file\n--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+one\n"
+        );
+        assert!(file_diff("old.txt", Some("one\n"), None).starts_with(
+            "diff --git a/old.txt b/old.txt\ndeleted file\n--- a/old.txt\n+++ /dev/null\n"
+        ));
+    }
+
+    #[test]
+    fn test_patch_with_offset_and_fuzz() {
+        // Shifted down by two lines, and the last context line doesn't match
+        let patch = "@@ -1,4 +1,4 @@\n fn main() {\n     let a = 1;\n-    let b = 2;\n+    let b = 5;\n     println!(\"changed\");\n";
+        let shifted = format!("// one\n// two\n{}", FILE);
+        let (patched, applied) = apply_patch(&shifted, patch).unwrap();
+        assert_eq!(patched, shifted.replace("let b = 2;", "let b = 5;"));
+        assert_eq!(
+            applied,
+            vec![AppliedHunk {
+                line: 3,
+                offset: 2,
+                fuzz: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_failed_hunks_change_nothing() {
+        let patch = "@@ -2,1 +2,1 @@\n-    let a = 1;\n+    let a = 9;\n@@ -7,1 +7,1 @@\n-fn missing() {}\n+fn found() {}\n";
+        let error = apply_patch(FILE, patch).unwrap_err().to_string();
+        assert!(error.contains("1 of 2 hunks didn't match the file: hunk 2 (@@ -7,1 +7,1 @@)"));
+        assert!(apply_patch(FILE, "just text").is_err());
+
+        // A patch can fill an empty file
+        let (patched, _) = apply_patch(
+            "",
+            "--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,2 @@\n+one\n+two\n",
+        )
+        .unwrap();
+        assert_eq!(patched, "one\ntwo\n");
+    }
+
+    #[test]
+    fn test_context_only_hunks_must_match() {
+        assert!(apply_patch("a\nb\n", "@@ -1,1 +1,1 @@\n zzz\n").is_err());
+        for context in 2..=4 {
+            let patch = format!(
+                "@@ -1,{0} +1,{0} @@\n{1}",
+                context,
+                " zzz\n".repeat(context)
+            );
+            assert!(apply_patch(FILE, &patch).is_err());
+        }
+        assert!(apply_patch(FILE, "@@ -2,1 +2,1 @@\n     let a = 1;\n").is_ok());
+    }
+}
@@ -1 +1,2 @@
 pub mod common;
+pub mod diff;